@@ -0,0 +1,429 @@
+//! Parsing of amount cells written in different locales, e.g. "1.234,56", "1,234.56",
+//! "-25.00 USD" or "(125.00)", and their rounding.
+use clap::ArgEnum;
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::error::PipelineError;
+
+/// How amounts are written.
+#[derive(Default)]
+pub struct AmountFormat {
+    /// Detected from each value if not set.
+    pub decimal_separator: Option<char>,
+    /// Trailing marker of negative amounts (e.g. "DR"), matched case-insensitively.
+    pub negative_marker: Option<String>,
+}
+
+/// Parses an amount, ignoring currency codes and symbols, spaces and thousands separators.
+/// A minus sign (leading or trailing), parentheses ("(1,250.00)") and the negative marker
+/// denote a negative amount.
+/// Values with anything else in them aren't amounts, e.g. "12abc34" or "125.00 DR" without
+/// the "DR" negative marker. A currency code is a word of three letters before or after the
+/// number ("USD 25.00", "25,00 lei").
+/// If the decimal separator isn't given, it's detected from the value: the last separator
+/// when both "." and "," are present, a separator used more than once is a thousands
+/// separator, and so is a single one followed by exactly three digits (e.g. "1,234"),
+/// unless the integer part is zero ("0.125").
+pub fn parse_amount(value: &str, format: &AmountFormat) -> Option<Decimal> {
+    let mut value = value.trim();
+    let mut marked = false;
+    if let Some(rest) = format
+        .negative_marker
+        .as_ref()
+        .and_then(|m| strip_marker(value, m))
+    {
+        value = rest;
+        marked = true;
+    }
+    if !is_amount_like(value) {
+        return None;
+    }
+    let parenthesized = matches!(
+        (value.find('('), value.rfind(')')),
+        (Some(open), Some(close)) if open < close
+    );
+    let negative = parenthesized || marked || value.contains(['-', '−']);
+    let chars: String = value
+        .chars()
+        .filter(|c| c.is_ascii_digit() || matches!(c, '.' | ','))
+        .collect();
+    let digits = chars.trim_end_matches(['.', ',']);
+    if !digits.chars().any(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    // A leading separator is a decimal one (".5").
+    let digits = &match digits.starts_with(['.', ',']) {
+        true => format!("0{}", digits),
+        false => digits.to_string(),
+    };
+    let separator = format
+        .decimal_separator
+        .or_else(|| detect_decimal_separator(digits));
+    let mut normalized = String::with_capacity(digits.len() + 1);
+    if negative {
+        normalized.push('-');
+    }
+    for c in digits.chars() {
+        match c {
+            '.' | ',' if Some(c) == separator => normalized.push('.'),
+            '.' | ',' => {}
+            c => normalized.push(c),
+        }
+    }
+    normalized.parse().ok()
+}
+
+/// Strips the marker if the value ends with it as a separate word ("125.00 DR", "125.00DR").
+fn strip_marker<'v>(value: &'v str, marker: &str) -> Option<&'v str> {
+    let marker = marker.trim().to_lowercase();
+    let length = marker.chars().count();
+    if length == 0 {
+        return None;
+    }
+    let (start, _) = value.char_indices().rev().nth(length - 1)?;
+    let rest = &value[..start];
+    match value[start..].to_lowercase() == marker && !rest.ends_with(char::is_alphabetic) {
+        true => Some(rest),
+        false => None,
+    }
+}
+
+/// Currency symbols accepted around the amounts.
+const CURRENCY_SYMBOLS: [char; 12] = ['$', '€', '£', '¥', '₽', '₴', '₺', '₹', '¢', '₩', '₪', '₸'];
+
+/// Whether the value is made of digits, separators and spaces, surrounded by signs,
+/// parentheses, currency symbols and a currency code.
+fn is_amount_like(value: &str) -> bool {
+    let (first, last) = match (
+        value.find(|c: char| c.is_ascii_digit()),
+        value.rfind(|c: char| c.is_ascii_digit()),
+    ) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return false,
+    };
+    let is_separator = |c: char| matches!(c, '.' | ',' | '\'') || c.is_whitespace();
+    // A currency code, symbols, signs and parentheses, e.g. "-$" or " USD)".
+    let is_affix = |part: &str| {
+        let code = part.trim_matches(|c: char| !c.is_alphabetic());
+        let is_code =
+            code.is_empty() || code.chars().count() == 3 && code.chars().all(char::is_alphabetic);
+        is_code
+            && part.chars().all(|c| {
+                c.is_alphabetic()
+                    || is_separator(c)
+                    || CURRENCY_SYMBOLS.contains(&c)
+                    || matches!(c, '-' | '−' | '+' | '(' | ')')
+            })
+    };
+    value[first..=last]
+        .chars()
+        .all(|c| c.is_ascii_digit() || is_separator(c))
+        && is_affix(&value[..first])
+        && is_affix(&value[last + 1..])
+}
+
+/// Returns the decimal separator of a value made of digits and separators,
+/// `None` if all separators are thousands separators.
+fn detect_decimal_separator(digits: &str) -> Option<char> {
+    let last = digits.rfind(['.', ','])?;
+    let separator = digits[last..].chars().next()?;
+    let has_other = digits.contains(if separator == '.' { ',' } else { '.' });
+    if has_other {
+        return Some(separator);
+    }
+    if digits.matches(separator).count() > 1 {
+        return None;
+    }
+    let fraction = &digits[last + 1..];
+    let integer = &digits[..last];
+    if fraction.len() == 3 && !integer.trim_start_matches('0').is_empty() {
+        return None;
+    }
+    Some(separator)
+}
+
+/// Converts the amount between currencies, given their rates in the same currency
+/// (e.g. MDL per unit, 1 for MDL itself).
+pub fn convert(
+    amount: Decimal,
+    from_rate: Decimal,
+    to_rate: Decimal,
+) -> Result<Decimal, PipelineError> {
+    if to_rate.is_zero() {
+        return Err(PipelineError::ZeroRate);
+    }
+    let converted = amount
+        .checked_mul(from_rate)
+        .ok_or(PipelineError::Overflow)?;
+    converted
+        .checked_div(to_rate)
+        .ok_or(PipelineError::Overflow)
+}
+
+/// How converted amounts and totals are rounded to their precision.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    /// Ties are rounded away from zero (2.675 -> 2.68).
+    HalfUp,
+    /// Ties are rounded to the even digit (2.665 -> 2.66), aka banker's rounding.
+    HalfEven,
+    /// Extra digits are dropped.
+    Truncate,
+}
+
+/// Formats the number rounded to the given number of decimals.
+pub fn round(value: Decimal, precision: usize, rounding: Rounding) -> String {
+    let strategy = match rounding {
+        Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+        Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+        Rounding::Truncate => RoundingStrategy::ToZero,
+    };
+    let mut rounded = value.round_dp_with_strategy(precision as u32, strategy);
+    if rounded.is_zero() {
+        // "-0.00" isn't an amount.
+        rounded.set_sign_positive(true);
+    }
+    format!("{:.*}", precision, rounded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(separator: Option<char>, marker: Option<&str>) -> AmountFormat {
+        AmountFormat {
+            decimal_separator: separator,
+            negative_marker: marker.map(str::to_string),
+        }
+    }
+
+    fn parse(value: &str) -> Option<String> {
+        parse_amount(value, &AmountFormat::default()).map(|a| a.to_string())
+    }
+
+    #[test]
+    fn detects_the_decimal_separator() {
+        let cases = [
+            ("1.234,56", "1234.56"),
+            ("1,234.56", "1234.56"),
+            ("1.234.567,89", "1234567.89"),
+            ("1,234,567.89", "1234567.89"),
+            ("1 234 567,89", "1234567.89"),
+            ("1\u{a0}234,56", "1234.56"),
+            ("1,234", "1234"),
+            ("1.234", "1234"),
+            ("1.234.567", "1234567"),
+            ("1,23", "1.23"),
+            ("1,2345", "1.2345"),
+            ("12,5", "12.5"),
+            ("0,125", "0.125"),
+            ("0.125", "0.125"),
+            ("000,125", "0.125"),
+            ("1234", "1234"),
+            ("1234.5", "1234.5"),
+            (".5", "0.5"),
+            (",5", "0.5"),
+            ("5.", "5"),
+            ("5,", "5"),
+            ("0", "0"),
+            ("007", "7"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse(value).as_deref(), Some(expected), "{}", value);
+        }
+    }
+
+    #[test]
+    fn explicit_decimal_separator() {
+        let cases = [
+            ("1,234", Some(','), "1.234"),
+            ("1,234", Some('.'), "1234"),
+            ("1.234", Some('.'), "1.234"),
+            ("1.234", Some(','), "1234"),
+            ("1.234.567,8", Some(','), "1234567.8"),
+            ("1,234.5", Some('.'), "1234.5"),
+        ];
+        for (value, separator, expected) in cases {
+            let amount = parse_amount(value, &format(separator, None)).map(|a| a.to_string());
+            assert_eq!(
+                amount.as_deref(),
+                Some(expected),
+                "{} {:?}",
+                value,
+                separator
+            );
+        }
+    }
+
+    #[test]
+    fn signs_and_parentheses() {
+        let cases = [
+            ("-25.00", "-25.00"),
+            ("- 25.00", "-25.00"),
+            ("25.00-", "-25.00"),
+            ("−12,50", "-12.50"),
+            ("(125.00)", "-125.00"),
+            ("( 1.234,56 )", "-1234.56"),
+            ("(1,250.00) USD", "-1250.00"),
+            ("+25.00", "25.00"),
+            ("-0", "0"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse(value).as_deref(), Some(expected), "{}", value);
+        }
+    }
+
+    #[test]
+    fn currency_codes_and_symbols() {
+        let cases = [
+            ("-25.00 USD", "-25.00"),
+            ("USD 25.00", "25.00"),
+            ("25.00USD", "25.00"),
+            ("$1,234.56", "1234.56"),
+            ("-$1,234.56", "-1234.56"),
+            ("€ 1.234,56", "1234.56"),
+            ("1.234,56 €", "1234.56"),
+            ("£0.99", "0.99"),
+            ("MDL 1 234,56", "1234.56"),
+            ("1 234,56 mdl", "1234.56"),
+            ("100 ₽", "100"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse(value).as_deref(), Some(expected), "{}", value);
+        }
+    }
+
+    #[test]
+    fn negative_markers() {
+        let dr = format(None, Some("DR"));
+        let cases = [
+            ("1,250.00 DR", Some("-1250.00")),
+            ("1,250.00DR", Some("-1250.00")),
+            ("1,250.00 dr", Some("-1250.00")),
+            ("1,250.00", Some("1250.00")),
+            ("(1,250.00) DR", Some("-1250.00")),
+        ];
+        for (value, expected) in cases {
+            let amount = parse_amount(value, &dr).map(|a| a.to_string());
+            assert_eq!(amount.as_deref(), expected, "{}", value);
+        }
+        assert_eq!(strip_marker("125 Dr", "DR"), Some("125 "));
+        assert_eq!(strip_marker("125 ADR", "DR"), None);
+        assert_eq!(strip_marker("125", ""), None);
+        assert_eq!(strip_marker("R", "DR"), None);
+        assert_eq!(parse_amount("125.00 DR", &AmountFormat::default()), None);
+    }
+
+    #[test]
+    fn not_amounts() {
+        for value in ["", "   ", "-", "()", ".", ",", "USD", "n/a", "$", "- USD"] {
+            assert_eq!(parse(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn converts_between_currencies() {
+        let rate = Decimal::new(177, 1);
+        let amount = Decimal::new(25000, 2);
+        assert_eq!(
+            convert(amount, rate, Decimal::ONE).unwrap(),
+            Decimal::new(4425, 0)
+        );
+        assert_eq!(
+            convert(Decimal::new(4425, 0), Decimal::ONE, rate).unwrap(),
+            amount
+        );
+        assert!(matches!(
+            convert(amount, rate, Decimal::ZERO),
+            Err(PipelineError::ZeroRate)
+        ));
+        assert!(matches!(
+            convert(Decimal::MAX, rate, Decimal::ONE),
+            Err(PipelineError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn rejects_other_characters() {
+        let cases = [
+            "12abc34",
+            "12a",
+            "a12",
+            "1.2.3x",
+            "12#34",
+            "#12",
+            "12%",
+            "1/2",
+            "1_000",
+            "2024-03-15",
+            "12 USDX",
+            "US 12",
+            "USD EUR 12",
+            "USD 12 EUR EUR",
+            "12 U S D",
+            "125.00 DR",
+            "1e5",
+            "0x1F",
+        ];
+        for value in cases {
+            assert_eq!(parse(value), None, "{:?}", value);
+        }
+        // Apostrophes are thousands separators ("1'234.56").
+        assert_eq!(parse("1'234.56").as_deref(), Some("1234.56"));
+        assert_eq!(parse("USD 12 EUR").as_deref(), Some("12"));
+        assert_eq!(parse("25,00 lei").as_deref(), Some("25.00"));
+        assert_eq!(parse("25,00 руб").as_deref(), Some("25.00"));
+    }
+
+    #[test]
+    fn rounds_ties_the_accountants_way() {
+        let cases = [
+            ("2.675", 2, Rounding::HalfUp, "2.68"),
+            ("2.665", 2, Rounding::HalfUp, "2.67"),
+            ("1.005", 2, Rounding::HalfUp, "1.01"),
+            ("1.015", 2, Rounding::HalfUp, "1.02"),
+            ("0.285", 2, Rounding::HalfUp, "0.29"),
+            ("8.345", 2, Rounding::HalfUp, "8.35"),
+            ("1.45", 1, Rounding::HalfUp, "1.5"),
+            ("-2.675", 2, Rounding::HalfUp, "-2.68"),
+            ("2.675", 2, Rounding::HalfEven, "2.68"),
+            ("2.665", 2, Rounding::HalfEven, "2.66"),
+            ("0.125", 2, Rounding::HalfEven, "0.12"),
+            ("0.135", 2, Rounding::HalfEven, "0.14"),
+            ("-2.665", 2, Rounding::HalfEven, "-2.66"),
+            ("2.679", 2, Rounding::Truncate, "2.67"),
+            ("-2.679", 2, Rounding::Truncate, "-2.67"),
+            ("2.5", 0, Rounding::HalfUp, "3"),
+            ("2.5", 0, Rounding::HalfEven, "2"),
+            ("2.5", 2, Rounding::HalfUp, "2.50"),
+            ("-0.001", 2, Rounding::HalfUp, "0.00"),
+            ("-0.004", 2, Rounding::Truncate, "0.00"),
+        ];
+        for (value, precision, rounding, expected) in cases {
+            let value: Decimal = value.parse().unwrap();
+            assert_eq!(
+                round(value, precision, rounding),
+                expected,
+                "{} {:?}",
+                value,
+                rounding
+            );
+        }
+    }
+
+    #[test]
+    fn rounds_converted_amounts_exactly() {
+        // As f64, 2.675 is 2.67499999999999982236431605997495353221893310546875, rounded to
+        // 2.67, 1.15 * 17.5 is rounded to 20.12 and 0.1 * 3 is 0.30000000000000004.
+        let converted = convert(
+            "1.15".parse().unwrap(),
+            "17.5".parse().unwrap(),
+            Decimal::ONE,
+        );
+        assert_eq!(round(converted.unwrap(), 2, Rounding::HalfUp), "20.13");
+        let converted = convert("0.1".parse().unwrap(), Decimal::from(3), Decimal::ONE);
+        assert_eq!(converted.unwrap().to_string(), "0.3");
+        let amount = parse_amount("2,675", &format(Some(','), None)).unwrap();
+        assert_eq!(round(amount, 2, Rounding::HalfUp), "2.68");
+    }
+}
@@ -0,0 +1,3897 @@
+//! Processing of CSV rows: parsing their dates, resolving the dates of their rates and
+//! assembling the output rows with the added columns, along with the counters of a run.
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Write as _;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use chrono::{DateTime, Datelike, Duration, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use clap::ArgEnum;
+use csv::{Reader, StringRecord};
+use futures::future::Either;
+use futures::{FutureExt, Stream, StreamExt};
+use regex::Regex;
+use rust_decimal::Decimal;
+
+use crate::amount::{self, AmountFormat, Rounding};
+use crate::error::{FetchError, ParseError, PipelineError, RunError};
+use crate::filter::FilterSet;
+use crate::holidays::HolidayCalendar;
+use crate::locale::DateLocale;
+use crate::rates::{BnmClient, FetchStats};
+use crate::LOG_TARGET;
+
+/// Rate of the currency added to CSV files, unless another one is configured.
+pub const CURRENCY: &str = "USD";
+
+/// Input date format of the command and the [`DateParser::new`] default.
+pub const DEFAULT_DATE_FORMAT: &str = "%m/%d/%Y";
+
+/// Name of the exchange rate column of the command and the [`PipelineConfig`] default.
+pub const DEFAULT_EXCHANGE_COLUMN: &str = "Exchange Rate";
+
+/// Earliest rate date of the command and the [`RateDateResolver`] default.
+pub const DEFAULT_MIN_DATE: &str = "1994-01-01";
+
+/// Common delimiters, the inspect command suggests the one splitting the first line into
+/// most fields.
+pub const CANDIDATE_DELIMITERS: [char; 4] = [',', ';', '\t', '|'];
+
+/// Which date's rate is used for a transaction date.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateDateMode {
+    Exact,
+    PreviousBusinessDay,
+}
+
+/// Whether the rate of the day or the average rate of its month is used.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateMode {
+    Daily,
+    MonthlyAverage,
+}
+
+/// Number of decimals of monthly average rates, same as BNM's daily rates.
+pub const AVERAGE_RATE_PRECISION: u32 = 4;
+
+/// How rows dated after the latest published rates are handled.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnFutureDate {
+    Error,
+    WarnSkip,
+    UseLatest,
+}
+
+/// How rows with an empty date are handled.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnEmptyDate {
+    Skip,
+    Blank,
+    Error,
+}
+
+/// How rows whose rate can't be found are handled.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnRateError {
+    Drop,
+    Blank,
+    Fail,
+}
+
+/// Whether amounts are converted to or from MDL.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConvertDirection {
+    ToMdl,
+    FromMdl,
+}
+
+/// Period of the subtotal rows written between the rows.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupTotals {
+    Month,
+    Year,
+}
+
+/// How cells of the amount columns which aren't amounts are handled.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnInvalidAmount {
+    Blank,
+    Error,
+}
+
+/// Which of the duplicate rows is kept.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DedupeKeep {
+    First,
+    Last,
+}
+
+/// Format of the run statistics.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatsFormat {
+    Text,
+    Json,
+}
+
+/// Whether rows not passing the filters are dropped or written with their status.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterMode {
+    Exclude,
+    Annotate,
+}
+
+/// Outcome of a row, written to the status column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RowStatus {
+    Ok,
+    NotMatched,
+    /// The row isn't valid CSV, it's never written.
+    ReadError,
+    EmptyDate,
+    DateParseError,
+    /// The row has fewer fields than the date column index.
+    RaggedRow,
+    FutureDate,
+    /// BNM didn't publish the rate.
+    RateMissing,
+    /// The request to BNM failed, or its response is invalid.
+    NetworkError,
+    AmountParseError,
+    Error,
+}
+
+/// Statuses of failed rows, counted by category in the summary.
+pub const FAILURE_STATUSES: [RowStatus; 9] = [
+    RowStatus::ReadError,
+    RowStatus::EmptyDate,
+    RowStatus::DateParseError,
+    RowStatus::RaggedRow,
+    RowStatus::FutureDate,
+    RowStatus::RateMissing,
+    RowStatus::NetworkError,
+    RowStatus::AmountParseError,
+    RowStatus::Error,
+];
+
+impl RowStatus {
+    /// Value of the status column, these must be kept stable as scripts rely on them.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RowStatus::Ok => "ok",
+            RowStatus::NotMatched => "not-matched",
+            RowStatus::ReadError => "read-error",
+            RowStatus::EmptyDate => "empty-date",
+            RowStatus::DateParseError => "date-parse-error",
+            RowStatus::RaggedRow => "ragged-row",
+            RowStatus::FutureDate => "future-date",
+            RowStatus::RateMissing => "rate-missing",
+            RowStatus::NetworkError => "network-error",
+            RowStatus::AmountParseError => "amount-parse-error",
+            RowStatus::Error => "error",
+        }
+    }
+}
+
+/// Counters and timings of a run, shared by the rows processed concurrently.
+#[derive(Default)]
+pub struct RunStats {
+    pub rows_read: AtomicUsize,
+    /// Rows passing the filters.
+    pub rows_matched: AtomicUsize,
+    pub rows_filtered: AtomicUsize,
+    pub rows_inverted: AtomicUsize,
+    pub rows_post_filtered: AtomicUsize,
+    pub rows_duplicate: AtomicUsize,
+    pub rows_failed: AtomicUsize,
+    pub rows_failed_blank: AtomicUsize,
+    pub rows_written: AtomicUsize,
+    pub future_dates_skipped: AtomicUsize,
+    pub future_dates_replaced: AtomicUsize,
+    pub rows_out_of_range: AtomicUsize,
+    pub empty_dates: AtomicUsize,
+    /// Rows with fewer fields than the date column index.
+    pub ragged_rows: AtomicUsize,
+    pub invalid_amounts: AtomicUsize,
+    pub amounts_converted: AtomicUsize,
+    pub rate_changes_missing: AtomicUsize,
+    pub totals_skipped: AtomicUsize,
+    /// Failed rows (dropped or kept blank) by status.
+    pub failures: std::sync::Mutex<BTreeMap<RowStatus, usize>>,
+    /// Wall-clock time of each phase of the run.
+    pub phases: std::sync::Mutex<Vec<(&'static str, std::time::Duration)>>,
+    /// Time spent in each step of the rows, in nanoseconds, summed over the rows.
+    pub read_time: AtomicU64,
+    pub prefetch_time: AtomicU64,
+    pub assemble_time: AtomicU64,
+    pub write_time: AtomicU64,
+}
+
+impl RunStats {
+    /// Adds a row to the counter.
+    pub fn increment(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds the time elapsed since the start of a step.
+    pub fn add_time(counter: &AtomicU64, start: Instant) {
+        counter.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a failed row by its status.
+    pub fn count_failure(&self, status: RowStatus) {
+        if let Ok(mut failures) = self.failures.lock() {
+            *failures.entry(status).or_insert(0) += 1;
+        }
+    }
+
+    /// Failed rows by status, from the most frequent one.
+    pub fn failures(&self) -> Vec<(RowStatus, usize)> {
+        let mut failures: Vec<_> = match self.failures.lock() {
+            Ok(failures) => failures.iter().map(|(s, c)| (*s, *c)).collect(),
+            Err(_) => Vec::new(),
+        };
+        failures.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        failures
+    }
+
+    /// Failed rows by status, e.g. "date-parse-error: 3, network-error: 1".
+    pub fn describe_failures(&self) -> String {
+        self.failures()
+            .iter()
+            .map(|(status, count)| format!("{}: {}", status.as_str(), count))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Records the time elapsed since the start of the phase, returning the current time
+    /// as the start of the next phase.
+    pub fn end_phase(&self, name: &'static str, start: Instant) -> Instant {
+        let now = Instant::now();
+        if let Ok(mut phases) = self.phases.lock() {
+            phases.push((name, now - start));
+        }
+        now
+    }
+
+    /// Counters of the "--stats" output along with those of the client's lookups, with
+    /// stable names.
+    pub fn counters(&self, fetch: &FetchStats) -> Vec<(String, usize)> {
+        let load = |c: &AtomicUsize| c.load(Ordering::Relaxed);
+        let mut counters: Vec<(String, usize)> = [
+            ("rows_read", &self.rows_read),
+            ("rows_matched", &self.rows_matched),
+            ("rows_filtered", &self.rows_filtered),
+            ("rows_inverted", &self.rows_inverted),
+            ("rows_post_filtered", &self.rows_post_filtered),
+            ("rows_duplicate", &self.rows_duplicate),
+            ("rows_out_of_range", &self.rows_out_of_range),
+            ("rows_written", &self.rows_written),
+            ("rows_failed", &self.rows_failed),
+            ("rows_failed_blank", &self.rows_failed_blank),
+            ("empty_dates", &self.empty_dates),
+            ("ragged_rows", &self.ragged_rows),
+            ("future_dates_skipped", &self.future_dates_skipped),
+            ("future_dates_replaced", &self.future_dates_replaced),
+            ("amounts_converted", &self.amounts_converted),
+            ("invalid_amounts", &self.invalid_amounts),
+            ("rate_changes_missing", &self.rate_changes_missing),
+            ("totals_skipped", &self.totals_skipped),
+            ("rate_lookups", &fetch.lookups),
+            ("rate_requests", &fetch.requests),
+            ("rates_missing", &fetch.missing),
+            ("request_errors_network", &fetch.network_errors),
+            ("request_errors_status", &fetch.status_errors),
+            ("request_errors_parse", &fetch.parse_errors),
+        ]
+        .iter()
+        .map(|(name, counter)| (name.to_string(), load(counter)))
+        .collect();
+        let failures = self.failures();
+        for status in FAILURE_STATUSES {
+            let count = failures
+                .iter()
+                .find(|(s, _)| *s == status)
+                .map(|(_, c)| *c)
+                .unwrap_or_default();
+            let name = format!("rows_failed_{}", status.as_str().replace('-', "_"));
+            counters.push((name, count));
+        }
+        let lookups = load(&fetch.lookups);
+        let requests = load(&fetch.requests);
+        counters.push(("cache_hits".to_string(), lookups.saturating_sub(requests)));
+        if let Ok(phases) = self.phases.lock() {
+            for (name, duration) in phases.iter() {
+                counters.push((format!("{}_ms", name), duration.as_millis() as usize));
+            }
+        }
+        counters
+    }
+
+    /// Timings of the "--timing" output along with the latencies of the client's requests,
+    /// in milliseconds.
+    pub fn timings(&self, fetch: &FetchStats) -> Vec<(String, usize)> {
+        let millis = |c: &AtomicU64| (c.load(Ordering::Relaxed) / 1_000_000) as usize;
+        let mut timings: Vec<(String, usize)> = [
+            ("read_time_ms", &self.read_time),
+            ("prefetch_time_ms", &self.prefetch_time),
+            ("assemble_time_ms", &self.assemble_time),
+            ("write_time_ms", &self.write_time),
+        ]
+        .iter()
+        .map(|(name, time)| (name.to_string(), millis(time)))
+        .collect();
+        for percentile in [50, 90, 99, 100] {
+            let name = match percentile {
+                100 => "request_latency_max_ms".to_string(),
+                p => format!("request_latency_p{}_ms", p),
+            };
+            let latency = fetch.latency_percentile(percentile);
+            timings.push((name, latency.as_millis() as usize));
+        }
+        timings
+    }
+
+    /// The "--stats" output, a line of counters.
+    pub fn format(
+        &self,
+        fetch: &FetchStats,
+        format: StatsFormat,
+        unique_dates: usize,
+        timing: bool,
+    ) -> String {
+        let mut counters = self.counters(fetch);
+        counters.insert(1, ("unique_dates".to_string(), unique_dates));
+        if timing {
+            counters.extend(self.timings(fetch));
+        }
+        match format {
+            StatsFormat::Text => counters
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join(" "),
+            StatsFormat::Json => format!(
+                "{{{}}}",
+                counters
+                    .iter()
+                    .map(|(name, value)| format!("\"{}\":{}", name, value))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        }
+    }
+
+    /// Logs the counters at the info level.
+    pub fn log_summary(&self) {
+        log::info!(
+            target: LOG_TARGET,
+            "Rows read: {}, not matching filters: {}, excluded by --invert-match: {}, removed by post filters: {}, duplicates: {}, written: {}, failed and dropped: {}, failed and kept blank: {}, out of date range: {}, empty dates: {}, ragged rows: {}, amounts converted: {}, invalid amounts: {}, missing rate changes: {}, cells skipped in totals: {}, future dates skipped: {}, future dates replaced with the latest rate: {}",
+            self.rows_read.load(Ordering::Relaxed),
+            self.rows_filtered.load(Ordering::Relaxed),
+            self.rows_inverted.load(Ordering::Relaxed),
+            self.rows_post_filtered.load(Ordering::Relaxed),
+            self.rows_duplicate.load(Ordering::Relaxed),
+            self.rows_written.load(Ordering::Relaxed),
+            self.rows_failed.load(Ordering::Relaxed),
+            self.rows_failed_blank.load(Ordering::Relaxed),
+            self.rows_out_of_range.load(Ordering::Relaxed),
+            self.empty_dates.load(Ordering::Relaxed),
+            self.ragged_rows.load(Ordering::Relaxed),
+            self.amounts_converted.load(Ordering::Relaxed),
+            self.invalid_amounts.load(Ordering::Relaxed),
+            self.rate_changes_missing.load(Ordering::Relaxed),
+            self.totals_skipped.load(Ordering::Relaxed),
+            self.future_dates_skipped.load(Ordering::Relaxed),
+            self.future_dates_replaced.load(Ordering::Relaxed),
+        );
+        let failures = self.describe_failures();
+        if !failures.is_empty() {
+            log::info!(target: LOG_TARGET, "Failed rows by category: {}", failures);
+        }
+    }
+}
+
+/// Special input date format for Excel (1900 date system) serial numbers.
+pub const EXCEL_SERIAL_FORMAT: &str = "excel-serial";
+
+/// Excel serial number of 9999-12-31, the last date Excel supports.
+pub const EXCEL_SERIAL_MAX: f64 = 2958465.0;
+
+/// Number of distinct values whose parsed or formatted dates are kept, so the dates
+/// repeated over the rows are parsed and formatted once. Values past it (e.g. timestamps)
+/// are parsed each time.
+pub const DATE_MEMO_SIZE: usize = 10_000;
+
+/// Where the transaction date of a row is read from.
+pub enum DateSource {
+    /// Candidate columns, the first one with a valid date is used.
+    Columns(Vec<usize>),
+    Parts {
+        year: usize,
+        month: usize,
+        day: usize,
+    },
+}
+
+impl DateSource {
+    pub fn new(
+        headers: Option<&StringRecord>,
+        columns: Option<&String>,
+        parts: Option<&String>,
+    ) -> Result<Self, ParseError> {
+        if let Some(parts) = parts {
+            let parts: Vec<&str> = parts.split(',').collect();
+            return match parts.as_slice() {
+                [year, month, day] => Ok(DateSource::Parts {
+                    year: get_column_index(headers, year)?,
+                    month: get_column_index(headers, month)?,
+                    day: get_column_index(headers, day)?,
+                }),
+                _ => Err(ParseError::DateParts {
+                    parts: parts.join(","),
+                }),
+            };
+        }
+        let columns = columns.ok_or(ParseError::NoDateColumn)?;
+        Ok(DateSource::Columns(get_column_indexes(headers, columns)?))
+    }
+
+    /// Column the output date related columns are placed after.
+    pub fn main_column(&self) -> usize {
+        match self {
+            DateSource::Columns(columns) => columns[0],
+            DateSource::Parts { day, .. } => *day,
+        }
+    }
+}
+
+/// Reads the transaction dates of the rows, in the input format and locale.
+pub struct DateParser {
+    pub source: DateSource,
+    pub on_empty: OnEmptyDate,
+    pub format: String,
+    pub locale: DateLocale,
+    pub year_pivot: Option<u32>,
+    pub regex: Option<Regex>,
+    pub in_timezone: Option<Tz>,
+    pub target_timezone: Tz,
+    /// Dates already parsed, by the cell value.
+    pub memo: std::sync::Mutex<HashMap<String, NaiveDate>>,
+}
+
+impl DateParser {
+    /// A parser of the dates of the source in the format, written in English, the rows with an
+    /// empty date being kept with empty added columns, and timestamps converted to BNM's
+    /// timezone (Europe/Chisinau).
+    pub fn new(source: DateSource, format: impl Into<String>) -> Self {
+        DateParser {
+            source,
+            on_empty: OnEmptyDate::Blank,
+            format: format.into(),
+            locale: DateLocale::En,
+            year_pivot: None,
+            regex: None,
+            in_timezone: None,
+            target_timezone: chrono_tz::Europe::Chisinau,
+            memo: Default::default(),
+        }
+    }
+
+    /// Whether all the date cells are empty.
+    pub fn is_empty(&self, record: &StringRecord) -> bool {
+        let is_blank = |i: usize| record.get(i).map(|v| v.trim().is_empty()).unwrap_or(false);
+        match &self.source {
+            DateSource::Columns(columns) => columns.iter().all(|c| is_blank(*c)),
+            DateSource::Parts { year, month, day } => {
+                is_blank(*year) && is_blank(*month) && is_blank(*day)
+            }
+        }
+    }
+
+    /// Raw content of the date cells, used in error messages.
+    pub fn describe(&self, record: &StringRecord) -> String {
+        let columns = match &self.source {
+            DateSource::Columns(columns) => columns.clone(),
+            DateSource::Parts { year, month, day } => vec![*year, *month, *day],
+        };
+        columns
+            .iter()
+            .map(|c| record.get(*c).unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Returns the parsed date and the index of the column it was taken from,
+    /// if it was a single column.
+    pub fn parse_record(
+        &self,
+        record: &StringRecord,
+    ) -> Result<(NaiveDate, Option<usize>), ParseError> {
+        match &self.source {
+            DateSource::Columns(columns) => {
+                let mut errors = Vec::new();
+                for (i, column) in columns.iter().enumerate() {
+                    let value = match record.get(*column) {
+                        Some(v) if v.trim().is_empty() => continue,
+                        Some(v) => v,
+                        None => {
+                            errors.push(ParseError::MissingColumn {
+                                column: *column,
+                                fields: record.len(),
+                            });
+                            continue;
+                        }
+                    };
+                    match self.parse(value) {
+                        Ok(date) => {
+                            if i > 0 {
+                                log::debug!(target: LOG_TARGET, "Using fallback date column {} - {}", column, value);
+                            }
+                            return Ok((date, Some(*column)));
+                        }
+                        Err(e) => errors.push(ParseError::DateCell {
+                            column: *column,
+                            value: value.to_string(),
+                            error: Box::new(e),
+                        }),
+                    }
+                }
+                Err(ParseError::DateColumns { errors })
+            }
+            DateSource::Parts { year, month, day } => {
+                let year: i32 = parse_date_part(record, *year, "year")?;
+                let month: u32 = parse_date_part(record, *month, "month")?;
+                let day_value: u32 = parse_date_part(record, *day, "day")?;
+                if !(1..=12).contains(&month) {
+                    return Err(ParseError::Month { month });
+                }
+                let date =
+                    NaiveDate::from_ymd_opt(year, month, day_value).ok_or(ParseError::Day {
+                        year,
+                        month,
+                        day: day_value,
+                    })?;
+                Ok((date, None))
+            }
+        }
+    }
+
+    /// Parses a date value, values repeated over the rows are parsed once.
+    pub fn parse(&self, value: &str) -> Result<NaiveDate, ParseError> {
+        if let Some(date) = self.memo.lock().ok().and_then(|m| m.get(value).copied()) {
+            return Ok(date);
+        }
+        let date = self.parse_value(value)?;
+        if let Ok(mut memo) = self.memo.lock() {
+            if memo.len() < DATE_MEMO_SIZE {
+                memo.insert(value.to_string(), date);
+            }
+        }
+        Ok(date)
+    }
+
+    /// Parses a date value, without looking it up among the parsed values.
+    pub fn parse_value(&self, value: &str) -> Result<NaiveDate, ParseError> {
+        let value = match &self.regex {
+            Some(re) => re
+                .captures(value)
+                .and_then(|c| c.get(1))
+                .map(|m| m.as_str())
+                .ok_or_else(|| ParseError::DateRegex {
+                    value: value.to_string(),
+                })?,
+            None => value,
+        };
+        if self.format == EXCEL_SERIAL_FORMAT {
+            return parse_excel_serial(value);
+        }
+        let value = self.locale.translate(value);
+        let date = self.parse_timestamp(&value)?;
+        self.apply_year_pivot(date)
+    }
+
+    /// Parses the value, converting timestamps with a timezone to the target timezone.
+    pub fn parse_timestamp(&self, value: &str) -> Result<NaiveDate, ParseError> {
+        let has_offset = ["%z", "%:z", "%#z", "%+"]
+            .iter()
+            .any(|f| self.format.contains(f));
+        if has_offset {
+            let timestamp = DateTime::parse_from_str(value, &self.format)?;
+            return Ok(timestamp
+                .with_timezone(&self.target_timezone)
+                .date()
+                .naive_local());
+        }
+        let tz = match self.in_timezone {
+            Some(tz) => tz,
+            None => return Ok(NaiveDate::parse_from_str(value, &self.format)?),
+        };
+        let timestamp = NaiveDateTime::parse_from_str(value, &self.format)?;
+        let timestamp = match tz.from_local_datetime(&timestamp) {
+            LocalResult::Single(t) => t,
+            // Repeated hour when the clock goes back, the first occurrence is used.
+            LocalResult::Ambiguous(t, _) => t,
+            LocalResult::None => {
+                return Err(ParseError::NonexistentTime {
+                    time: timestamp,
+                    timezone: tz,
+                });
+            }
+        };
+        Ok(timestamp
+            .with_timezone(&self.target_timezone)
+            .date()
+            .naive_local())
+    }
+
+    /// Moves dates with a two-digit year into the century given by the year pivot, if set.
+    pub fn apply_year_pivot(&self, date: NaiveDate) -> Result<NaiveDate, ParseError> {
+        let pivot = match self.year_pivot {
+            Some(p) => p as i32,
+            None => return Ok(date),
+        };
+        let pivot_year = |year: i32| {
+            let year = year.rem_euclid(100);
+            if year < pivot {
+                2000 + year
+            } else {
+                1900 + year
+            }
+        };
+        if self.format.contains("%y") {
+            let year = pivot_year(date.year());
+            return date
+                .with_year(year)
+                .ok_or(ParseError::PivotDate { date, year });
+        }
+        if self.format.contains("%g") {
+            // ISO week dates must be rebuilt from the week, as the calendar year may differ.
+            let week = date.iso_week();
+            let year = pivot_year(week.year());
+            return NaiveDate::from_isoywd_opt(year, week.week(), date.weekday()).ok_or(
+                ParseError::PivotWeek {
+                    week: week.week(),
+                    year,
+                },
+            );
+        }
+        Ok(date)
+    }
+}
+
+/// Columns added to the output, in addition to the input ones.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutValue {
+    Exchange,
+    Reference,
+    ReferenceDelta,
+    Inverse,
+    RateChange,
+    /// Converted amount of the n-th amount column.
+    Converted(usize),
+    RateDate,
+    Status,
+    Year,
+    Month,
+    Day,
+}
+
+impl OutValue {
+    /// The option adding the column, used in error messages.
+    pub fn option(&self) -> &'static str {
+        match self {
+            OutValue::Exchange => "--out-exchange-column",
+            OutValue::Reference => "--out-reference-column",
+            OutValue::ReferenceDelta => "--out-reference-delta-column",
+            OutValue::Inverse => "--out-inverse-column",
+            OutValue::RateChange => "--out-rate-change-column",
+            OutValue::Converted(_) => "--out-converted-column",
+            OutValue::RateDate => "--out-rate-date-column",
+            OutValue::Status => "--out-status-column",
+            OutValue::Year | OutValue::Month | OutValue::Day => "--out-date-split-names",
+        }
+    }
+}
+
+/// A column added to the output rows.
+pub struct OutColumn {
+    /// Index of the input column the column is inserted after, appended if not set.
+    pub after: Option<usize>,
+    pub name: String,
+    pub value: OutValue,
+}
+
+/// The columns added to the output rows, along with where they're inserted.
+pub struct OutLayout {
+    pub columns: Vec<OutColumn>,
+}
+
+/// Room reserved for each added field when sizing an output record, enough for a rate
+/// or a date.
+pub const ADDED_FIELD_SIZE: usize = 16;
+
+impl OutLayout {
+    /// Input record with empty added columns, except for the status.
+    pub fn blank(&self, record: &StringRecord, status: RowStatus) -> StringRecord {
+        self.assemble(record, |c| match c.value {
+            OutValue::Status => status.as_str().to_string(),
+            _ => String::new(),
+        })
+    }
+
+    /// Index of the added column with the value, in an output record of `width` input fields.
+    pub fn position(&self, value: OutValue, width: usize) -> Option<usize> {
+        let marker = |c: &OutColumn| match c.value == value {
+            true => "*".to_string(),
+            false => String::new(),
+        };
+        let record = self.assemble(std::iter::repeat_n("", width), marker);
+        record.iter().position(|v| v == "*")
+    }
+
+    /// Merges input fields with the added columns, `value` provides the content of added columns.
+    /// Columns to be inserted after a missing input field (short rows) are appended.
+    /// Fields are copied into the record's buffer, rather than allocated one by one.
+    /// The buffer is sized upfront, as growing it field by field costs more than the copies.
+    pub fn assemble<'f, I, F>(&self, fields: I, value: F) -> StringRecord
+    where
+        I: IntoIterator<Item = &'f str>,
+        I::IntoIter: Clone,
+        F: Fn(&OutColumn) -> String,
+    {
+        let fields = fields.into_iter();
+        let (count, size) = fields
+            .clone()
+            .fold((0, 0), |(count, size), f| (count + 1, size + f.len()));
+        let added = self.columns.len();
+        let mut record =
+            StringRecord::with_capacity(size + added * ADDED_FIELD_SIZE, count + added);
+        let mut len = 0;
+        for (i, field) in fields.enumerate() {
+            record.push_field(field);
+            for column in self.columns.iter().filter(|c| c.after == Some(i)) {
+                record.push_field(&value(column));
+            }
+            len = i + 1;
+        }
+        for column in self
+            .columns
+            .iter()
+            .filter(|c| c.after.map(|i| i >= len).unwrap_or(true))
+        {
+            record.push_field(&value(column));
+        }
+        record
+    }
+}
+
+/// Computes the date of the exchange rate for a transaction date.
+pub struct RateDateResolver {
+    pub offset: i32,
+    /// Set in case the previous business day must be used.
+    pub calendar: Option<HolidayCalendar>,
+    pub on_future_date: OnFutureDate,
+    /// Current date in BNM's timezone.
+    pub today: NaiveDate,
+    pub min_date: NaiveDate,
+}
+
+/// Inclusive range of transaction dates to be processed.
+pub struct DateRange {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+impl DateRange {
+    /// Whether the date is within the range, open ends match any date.
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        self.from.map(|f| date >= f).unwrap_or(true) && self.to.map(|t| date <= t).unwrap_or(true)
+    }
+}
+
+/// Exact rate dates up to today, skipping the rows dated later with a warning.
+impl Default for RateDateResolver {
+    fn default() -> Self {
+        RateDateResolver {
+            offset: 0,
+            calendar: None,
+            on_future_date: OnFutureDate::WarnSkip,
+            today: Utc::now()
+                .with_timezone(&chrono_tz::Europe::Chisinau)
+                .date()
+                .naive_local(),
+            min_date: DEFAULT_MIN_DATE.parse().expect("valid default min date"),
+        }
+    }
+}
+
+impl RateDateResolver {
+    /// Days of the month of the date, whose rates are averaged in monthly average mode.
+    pub fn month_days(&self, date: NaiveDate) -> Vec<NaiveDate> {
+        let month = date.with_day(1).unwrap_or(date);
+        month
+            .iter_days()
+            .take_while(|d| d.month() == month.month() && *d <= self.today)
+            .filter(|d| *d >= self.min_date)
+            .collect()
+    }
+
+    /// The business day before the date, even if business days aren't otherwise used.
+    pub fn previous_business_day(&self, date: NaiveDate) -> Result<NaiveDate, PipelineError> {
+        let day_before = date
+            .pred_opt()
+            .ok_or(PipelineError::NoBusinessDay { date, days: 0 })?;
+        match self.calendar.as_ref() {
+            Some(calendar) => calendar.previous_business_day(day_before),
+            None => HolidayCalendar::default().previous_business_day(day_before),
+        }
+    }
+
+    /// Returns the date whose rate is used for the transaction date.
+    pub fn resolve(&self, date: NaiveDate, stats: &RunStats) -> Result<NaiveDate, PipelineError> {
+        let mut rate_date = date
+            .checked_add_signed(Duration::days(self.offset.into()))
+            .ok_or(PipelineError::OffsetOutOfRange {
+                date,
+                offset: self.offset,
+            })?;
+        if let Some(calendar) = self.calendar.as_ref() {
+            rate_date = calendar.previous_business_day(rate_date)?;
+        }
+        if rate_date > self.today {
+            match self.on_future_date {
+                OnFutureDate::Error => {
+                    return Err(PipelineError::FutureDate {
+                        date,
+                        rate_date,
+                        fatal: true,
+                    })
+                }
+                OnFutureDate::WarnSkip => {
+                    RunStats::increment(&stats.future_dates_skipped);
+                    return Err(PipelineError::FutureDate {
+                        date,
+                        rate_date,
+                        fatal: false,
+                    });
+                }
+                OnFutureDate::UseLatest => {
+                    RunStats::increment(&stats.future_dates_replaced);
+                    rate_date = self.today;
+                }
+            }
+        }
+        if rate_date < self.min_date {
+            return Err(PipelineError::BeforeMinDate {
+                date,
+                rate_date,
+                min_date: self.min_date,
+            });
+        }
+        if rate_date == date {
+            return Ok(date);
+        }
+        log::debug!(
+            target: LOG_TARGET,
+            "Using rate date {} for transaction date {}",
+            rate_date,
+            date
+        );
+        Ok(rate_date)
+    }
+}
+
+/// Rate of one unit of the currency added to the rows.
+pub async fn fetch_exchange_rate(
+    client: &BnmClient,
+    date: &NaiveDate,
+    currency: &str,
+) -> Result<Decimal, FetchError> {
+    client
+        .published_rate(*date, currency)
+        .await?
+        .ok_or_else(|| FetchError::NotPublished {
+            date: *date,
+            currency: currency.to_string(),
+        })
+}
+
+/// Average rate of the month of the date, over the days up to today starting from
+/// the earliest rate date. Days without a published rate are skipped.
+/// Up to the concurrency limit of the client of days are requested at once.
+pub async fn fetch_monthly_average(
+    client: &BnmClient,
+    date: NaiveDate,
+    currency: &str,
+    rate_dates: &RateDateResolver,
+) -> Result<Decimal, FetchError> {
+    let month = date.with_day(1).unwrap_or(date);
+    client
+        .monthly_average(month, &rate_dates.month_days(month), currency)
+        .await
+}
+
+pub fn parse_date_part<T: std::str::FromStr>(
+    record: &StringRecord,
+    column: usize,
+    part: &'static str,
+) -> Result<T, ParseError> {
+    let value = record.get(column).ok_or(ParseError::MissingDatePart {
+        part,
+        column,
+        fields: record.len(),
+    })?;
+    value.trim().parse().map_err(|_| ParseError::DatePart {
+        part,
+        value: value.to_string(),
+    })
+}
+
+/// Converts an Excel serial number to a date, the fractional (time) part is ignored.
+/// Excel wrongly treats 1900 as a leap year, so serials after 60 are shifted by one day,
+/// and 60 itself (the non-existent 1900-02-29) is an error.
+pub fn parse_excel_serial(value: &str) -> Result<NaiveDate, ParseError> {
+    let serial: f64 = value.trim().parse().map_err(|_| ParseError::ExcelSerial {
+        value: value.to_string(),
+    })?;
+    if !(1.0..=EXCEL_SERIAL_MAX).contains(&serial) {
+        return Err(ParseError::ExcelSerialRange {
+            value: value.to_string(),
+        });
+    }
+    let days = serial.trunc() as i64;
+    if days == 60 {
+        return Err(ParseError::ExcelLeapDay {
+            value: value.to_string(),
+        });
+    }
+    let epoch = if days > 60 {
+        NaiveDate::from_ymd(1899, 12, 30)
+    } else {
+        NaiveDate::from_ymd(1899, 12, 31)
+    };
+    Ok(epoch + Duration::days(days))
+}
+
+/// Writes the transaction dates of the output rows.
+pub struct DateFormatter {
+    /// Output date format, dates are written as in the input file if not set.
+    pub format: Option<String>,
+    pub locale: DateLocale,
+    /// Dates already formatted.
+    pub memo: std::sync::Mutex<HashMap<NaiveDate, String>>,
+}
+
+impl DateFormatter {
+    /// Creates a formatter writing dates in the format and locale, as read if no format is given.
+    pub fn new(format: Option<String>, locale: DateLocale) -> Self {
+        DateFormatter {
+            format,
+            locale,
+            memo: Default::default(),
+        }
+    }
+
+    /// Formats the date, `None` if the input value should be written as it is.
+    pub fn format(&self, date: &NaiveDate) -> Option<Result<String, ParseError>> {
+        let format = self.format.as_ref()?;
+        if let Some(out) = self.memo.lock().ok().and_then(|m| m.get(date).cloned()) {
+            return Some(Ok(out));
+        }
+        let out = format_date(date, format, self.locale);
+        if let (Ok(out), Ok(mut memo)) = (out.as_ref(), self.memo.lock()) {
+            if memo.len() < DATE_MEMO_SIZE {
+                memo.insert(*date, out.clone());
+            }
+        }
+        Some(out)
+    }
+
+    /// Formats the date using the output format, or ISO format if it's not set.
+    pub fn format_or_iso(&self, date: &NaiveDate) -> Result<String, ParseError> {
+        self.format(date)
+            .unwrap_or_else(|| Ok(date.format("%Y-%m-%d").to_string()))
+    }
+}
+
+/// Formats the date, with month and weekday names in the locale.
+pub fn format_date(
+    date: &NaiveDate,
+    format: &str,
+    locale: DateLocale,
+) -> Result<String, ParseError> {
+    let format = locale.localize_format(format, date);
+    let mut out = String::new();
+    write!(out, "{}", date.format(&format)).map_err(|_| ParseError::DateFormat { format })?;
+    Ok(out)
+}
+
+/// Returns the index of a column, given by name if there are headers, by index otherwise.
+/// Unknown names fail with the closest header name as a suggestion.
+pub fn get_column_index(headers: Option<&StringRecord>, column: &str) -> Result<usize, ParseError> {
+    match headers {
+        Some(h) => h
+            .iter()
+            .position(|h| h == column)
+            .ok_or_else(|| ParseError::UnknownColumn {
+                column: column.to_string(),
+                suggestion: suggest_column(h, column).map(str::to_string),
+                headers: h.iter().map(str::to_string).collect(),
+            }),
+        None => column
+            .parse::<usize>()
+            .map_err(|_| ParseError::ColumnIndex {
+                column: column.to_string(),
+            }),
+    }
+}
+
+/// Number of headers listed when a column isn't found.
+pub const LISTED_COLUMNS: usize = 20;
+
+/// The header closest to the column name (ignoring case and surrounding spaces),
+/// if it differs by a few characters only.
+pub fn suggest_column<'h>(headers: &'h StringRecord, column: &str) -> Option<&'h str> {
+    let column = column.trim().to_lowercase();
+    let max_distance = (column.chars().count() / 3).max(1);
+    headers
+        .iter()
+        .map(|h| (h, edit_distance(&h.trim().to_lowercase(), &column)))
+        .filter(|(_, distance)| *distance <= max_distance)
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(h, _)| h)
+}
+
+/// Levenshtein distance, in characters.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Indexes of comma-separated columns.
+pub fn get_column_indexes(
+    headers: Option<&StringRecord>,
+    columns: &str,
+) -> Result<Vec<usize>, ParseError> {
+    columns
+        .split(',')
+        .map(|c| get_column_index(headers, c))
+        .collect()
+}
+
+/// Amount columns converted with the exchange rate.
+pub struct AmountColumns {
+    pub columns: Vec<usize>,
+    pub format: AmountFormat,
+    pub direction: ConvertDirection,
+    pub precision: Option<usize>,
+    pub on_invalid: OnInvalidAmount,
+}
+
+impl AmountColumns {
+    /// Non-empty amount cells of the row which aren't numbers.
+    pub fn invalid<'r>(&self, record: &'r StringRecord) -> Vec<&'r str> {
+        self.columns
+            .iter()
+            .filter_map(|c| record.get(*c))
+            .filter(|v| !v.trim().is_empty() && amount::parse_amount(v, &self.format).is_none())
+            .collect()
+    }
+
+    /// Converts the amount of the n-th column, empty cells are left empty.
+    /// Returns `None` if the amount is invalid but must be left empty.
+    pub fn convert(
+        &self,
+        record: &StringRecord,
+        index: usize,
+        rate: Decimal,
+        rounding: Rounding,
+        stats: &RunStats,
+    ) -> Result<Option<String>, PipelineError> {
+        let value = record.get(self.columns[index]).unwrap_or_default();
+        if value.trim().is_empty() {
+            return Ok(Some(String::new()));
+        }
+        match amount::parse_amount(value, &self.format) {
+            Some(amount) => {
+                let (from_rate, to_rate) = match self.direction {
+                    ConvertDirection::ToMdl => (rate, Decimal::ONE),
+                    ConvertDirection::FromMdl => (Decimal::ONE, rate),
+                };
+                let converted = amount::convert(amount, from_rate, to_rate).map_err(|error| {
+                    PipelineError::Conversion {
+                        column: index,
+                        value: value.to_string(),
+                        error: Box::new(error),
+                    }
+                })?;
+                RunStats::increment(&stats.amounts_converted);
+                Ok(Some(format_number(converted, self.precision, rounding)))
+            }
+            None => {
+                RunStats::increment(&stats.invalid_amounts);
+                match self.on_invalid {
+                    OnInvalidAmount::Blank => Ok(None),
+                    OnInvalidAmount::Error => Err(PipelineError::InvalidAmount {
+                        column: index,
+                        value: value.to_string(),
+                    }),
+                }
+            }
+        }
+    }
+}
+
+/// Sums of the amount and the converted amount columns of the written rows.
+pub struct Totals {
+    pub amounts: Vec<Decimal>,
+    pub converted: Vec<Decimal>,
+    /// Number of input fields of the widest row.
+    pub width: usize,
+}
+
+impl Totals {
+    /// Creates zero totals of the amount columns.
+    pub fn new(width: usize, amounts: &AmountColumns) -> Self {
+        Totals {
+            amounts: vec![Decimal::ZERO; amounts.columns.len()],
+            converted: vec![Decimal::ZERO; amounts.columns.len()],
+            width,
+        }
+    }
+
+    /// Adds the amounts of a row, non-empty cells which aren't numbers are skipped and counted.
+    pub fn add(
+        &mut self,
+        in_record: &StringRecord,
+        out_record: &StringRecord,
+        layout: &OutLayout,
+        amounts: &AmountColumns,
+        stats: &RunStats,
+    ) {
+        self.width = self.width.max(in_record.len());
+        for (i, column) in amounts.columns.iter().enumerate() {
+            let value = in_record.get(*column).unwrap_or_default();
+            let parsed = amount::parse_amount(value, &amounts.format);
+            add_to_sum(&mut self.amounts[i], value, parsed, stats);
+            let value = layout
+                .position(OutValue::Converted(i), in_record.len())
+                .and_then(|i| out_record.get(i))
+                .unwrap_or_default();
+            add_to_sum(&mut self.converted[i], value, value.parse().ok(), stats);
+        }
+    }
+
+    /// Totals row, labeled in the first column which isn't an amount one.
+    pub fn record(
+        &self,
+        label: &str,
+        layout: &OutLayout,
+        amounts: &AmountColumns,
+        rounding: Rounding,
+    ) -> StringRecord {
+        let last = amounts.columns.iter().max().copied().unwrap_or_default();
+        let mut fields = vec![String::new(); self.width.max(last + 1)];
+        for (column, sum) in amounts.columns.iter().zip(&self.amounts) {
+            fields[*column] = sum.normalize().to_string();
+        }
+        if let Some(i) = (0..fields.len()).find(|i| !amounts.columns.contains(i)) {
+            fields[i] = label.to_string();
+        }
+        layout.assemble(fields.iter().map(String::as_str), |c| match c.value {
+            OutValue::Converted(i) => format_number(self.converted[i], amounts.precision, rounding),
+            _ => String::new(),
+        })
+    }
+}
+
+/// Returns the records with group subtotal rows (sorting them by date),
+/// and the grand total row if it must be written.
+fn add_totals(
+    mut rows: Vec<(StringRecord, OutRow)>,
+    in_width: usize,
+    config: &PipelineConfig,
+    amounts: &AmountColumns,
+    options: &RunOptions,
+    stats: &RunStats,
+) -> (Vec<StringRecord>, Option<StringRecord>) {
+    let PipelineConfig {
+        layout, rounding, ..
+    } = config;
+    let new_totals = || Totals::new(in_width, amounts);
+    let group_label = |date: Option<NaiveDate>| {
+        let format = match options.group_totals {
+            Some(GroupTotals::Month) => "%Y-%m",
+            Some(GroupTotals::Year) => "%Y",
+            None => return None,
+        };
+        date.map(|d| d.format(format).to_string())
+    };
+    if options.group_totals.is_some() {
+        // Rows without a date (e.g. empty or failed ones) are kept at the end, without a subtotal.
+        rows.sort_by_key(|(_, row)| (row.date.is_none(), row.date));
+    }
+    let mut records = Vec::with_capacity(rows.len());
+    let mut total = new_totals();
+    let mut group: Option<(String, Totals)> = None;
+    for (in_record, row) in rows {
+        let label = group_label(row.date);
+        if let Some((current, totals)) = group.take() {
+            match label.as_ref() == Some(&current) {
+                true => group = Some((current, totals)),
+                false => records.push(totals.record(&current, layout, amounts, *rounding)),
+            }
+        }
+        if let Some(label) = label {
+            let (_, totals) = group.get_or_insert_with(|| (label, new_totals()));
+            totals.add(&in_record, &row.record, layout, amounts, stats);
+        }
+        total.add(&in_record, &row.record, layout, amounts, stats);
+        records.push(row.record);
+    }
+    if let Some((current, totals)) = group {
+        records.push(totals.record(&current, layout, amounts, *rounding));
+    }
+    let footer = match options.totals {
+        true => Some(total.record("TOTAL", layout, amounts, *rounding)),
+        false => None,
+    };
+    (records, footer)
+}
+
+/// Adds the parsed value of a cell, empty cells are ignored.
+pub fn add_to_sum(sum: &mut Decimal, value: &str, parsed: Option<Decimal>, stats: &RunStats) {
+    if value.trim().is_empty() {
+        return;
+    }
+    match parsed {
+        Some(v) => *sum += v,
+        None => RunStats::increment(&stats.totals_skipped),
+    }
+}
+
+/// Formats the number rounded to the precision, or as is if it's not set.
+pub fn format_number(value: Decimal, precision: Option<usize>, rounding: Rounding) -> String {
+    match precision {
+        Some(p) => amount::round(value, p, rounding),
+        None => value.normalize().to_string(),
+    }
+}
+
+/// Everything needed to add the exchange rate to a row, built once for the run with
+/// [`PipelineConfig::builder`].
+pub struct PipelineConfig {
+    pub date_parser: Arc<DateParser>,
+    pub date_range: DateRange,
+    pub rate_dates: RateDateResolver,
+    pub out_dates: DateFormatter,
+    pub layout: OutLayout,
+    pub amounts: Option<AmountColumns>,
+    /// Code of the currency whose rate is added.
+    pub currency: String,
+    pub rate_precision: Option<usize>,
+    /// Set in case the inverse rate must be added.
+    pub inverse_precision: Option<usize>,
+    /// Set in case the rate change must be added.
+    pub rate_change_precision: Option<usize>,
+    pub reference_rate: Option<Decimal>,
+    pub rate_mode: RateMode,
+    pub rounding: Rounding,
+    /// Maximum number of rows processed at once.
+    pub concurrency: usize,
+    /// Number of rows whose rates are fetched at once before processing them, if set.
+    pub prefetch: Option<usize>,
+}
+
+/// Builds a [`PipelineConfig`], the defaults being those of the command.
+pub struct PipelineConfigBuilder(PipelineConfig);
+
+impl PipelineConfig {
+    /// A configuration reading the dates with the parser, with the defaults of the command:
+    /// - all the dates are processed, each with the rate of the same day
+    ///   ([`RateDateResolver::default`]), written as they're read,
+    /// - the daily USD rate is appended as a [`DEFAULT_EXCHANGE_COLUMN`] column, as published,
+    /// - no amounts are converted, and roundings are [`Rounding::HalfUp`],
+    /// - up to 32 rows are processed at once, without prefetching their rates.
+    pub fn builder(date_parser: impl Into<Arc<DateParser>>) -> PipelineConfigBuilder {
+        PipelineConfigBuilder(PipelineConfig {
+            date_parser: date_parser.into(),
+            date_range: DateRange {
+                from: None,
+                to: None,
+            },
+            rate_dates: RateDateResolver::default(),
+            out_dates: DateFormatter::new(None, DateLocale::En),
+            layout: OutLayout {
+                columns: vec![OutColumn {
+                    after: None,
+                    name: DEFAULT_EXCHANGE_COLUMN.to_string(),
+                    value: OutValue::Exchange,
+                }],
+            },
+            amounts: None,
+            currency: CURRENCY.to_string(),
+            rate_precision: None,
+            inverse_precision: None,
+            rate_change_precision: None,
+            reference_rate: None,
+            rate_mode: RateMode::Daily,
+            rounding: Rounding::HalfUp,
+            concurrency: 32,
+            prefetch: None,
+        })
+    }
+}
+
+impl PipelineConfigBuilder {
+    /// Range of the transaction dates processed, the other rows are skipped.
+    pub fn date_range(mut self, from: Option<NaiveDate>, to: Option<NaiveDate>) -> Self {
+        self.0.date_range = DateRange { from, to };
+        self
+    }
+
+    /// How the rate date of a transaction date is resolved.
+    pub fn rate_dates(mut self, rate_dates: RateDateResolver) -> Self {
+        self.0.rate_dates = rate_dates;
+        self
+    }
+
+    /// How the transaction and rate dates are written.
+    pub fn out_dates(mut self, out_dates: DateFormatter) -> Self {
+        self.0.out_dates = out_dates;
+        self
+    }
+
+    /// The columns added to the rows, e.g. the rate date or the inverse rate besides the rate.
+    /// The inverse rate and the rate change columns need their precision set as well.
+    pub fn layout(mut self, layout: OutLayout) -> Self {
+        self.0.layout = layout;
+        self
+    }
+
+    /// The amount columns converted with the rate.
+    pub fn amounts(mut self, amounts: Option<AmountColumns>) -> Self {
+        self.0.amounts = amounts;
+        self
+    }
+
+    /// Code of the currency whose rate is added, e.g. "EUR".
+    pub fn currency(mut self, currency: impl Into<String>) -> Self {
+        self.0.currency = currency.into();
+        self
+    }
+
+    /// Number of decimals of the rates, as published if not set.
+    pub fn rate_precision(mut self, precision: Option<usize>) -> Self {
+        self.0.rate_precision = precision;
+        self
+    }
+
+    /// Number of decimals of the inverse rate, which is added only if set.
+    pub fn inverse_precision(mut self, precision: Option<usize>) -> Self {
+        self.0.inverse_precision = precision;
+        self
+    }
+
+    /// Number of decimals of the rate change, which is added only if set.
+    pub fn rate_change_precision(mut self, precision: Option<usize>) -> Self {
+        self.0.rate_change_precision = precision;
+        self
+    }
+
+    /// Rate the rates are compared with in the reference columns.
+    pub fn reference_rate(mut self, rate: Option<Decimal>) -> Self {
+        self.0.reference_rate = rate;
+        self
+    }
+
+    pub fn rate_mode(mut self, mode: RateMode) -> Self {
+        self.0.rate_mode = mode;
+        self
+    }
+
+    pub fn rounding(mut self, rounding: Rounding) -> Self {
+        self.0.rounding = rounding;
+        self
+    }
+
+    /// Maximum number of rows processed at once, at least 1.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.0.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Fetches the rates of each chunk of that many rows at once before processing them.
+    pub fn prefetch(mut self, chunk_size: Option<usize>) -> Self {
+        self.0.prefetch = chunk_size.map(|size| size.max(1));
+        self
+    }
+
+    pub fn build(self) -> PipelineConfig {
+        self.0
+    }
+}
+
+/// The configuration of a run along with its counters and the client of its rates.
+#[derive(Clone, Copy)]
+pub struct RowContext<'a> {
+    pub config: &'a PipelineConfig,
+    pub stats: &'a RunStats,
+    pub client: &'a BnmClient,
+}
+
+/// Output record along with its transaction date, if it was parsed.
+pub struct OutRow {
+    pub record: StringRecord,
+    pub date: Option<NaiveDate>,
+}
+
+/// What must be done with a row, before fetching its rate.
+pub enum RowPlan {
+    /// The row must not be written (e.g. out of the date range).
+    Skip,
+    /// The row is written with empty added columns.
+    Blank(RowStatus),
+    Fetch {
+        date: NaiveDate,
+        /// Set if the date was taken from a single column.
+        date_column: Option<usize>,
+        rate_date: NaiveDate,
+    },
+}
+
+/// Parses the date of the row and resolves its rate date, without any request.
+pub fn plan_row(ctx: &RowContext<'_>, record: &StringRecord) -> Result<RowPlan, PipelineError> {
+    let RowContext { config, stats, .. } = ctx;
+    let PipelineConfig {
+        date_parser,
+        date_range,
+        rate_dates,
+        ..
+    } = config;
+    if date_parser.is_empty(record) {
+        RunStats::increment(&stats.empty_dates);
+        return match date_parser.on_empty {
+            OnEmptyDate::Skip => Ok(RowPlan::Skip),
+            OnEmptyDate::Blank => Ok(RowPlan::Blank(RowStatus::EmptyDate)),
+            OnEmptyDate::Error => Err(PipelineError::EmptyDate),
+        };
+    }
+    let (date, date_column) = date_parser.parse_record(record).inspect_err(|e| {
+        if e.is_short_row() {
+            RunStats::increment(&stats.ragged_rows);
+        }
+    })?;
+    if !date_range.contains(date) {
+        RunStats::increment(&stats.rows_out_of_range);
+        return Ok(RowPlan::Skip);
+    }
+    let rate_date = rate_dates.resolve(date, stats)?;
+    Ok(RowPlan::Fetch {
+        date,
+        date_column,
+        rate_date,
+    })
+}
+
+/// Returns `None` in case the row must not be written (e.g. out of the date range).
+pub async fn add_exchange(
+    ctx: &RowContext<'_>,
+    record: &StringRecord,
+) -> Result<Option<OutRow>, PipelineError> {
+    let RowContext {
+        config,
+        stats,
+        client,
+    } = ctx;
+    let PipelineConfig {
+        rate_dates,
+        out_dates,
+        layout,
+        currency,
+        ..
+    } = config;
+    let start = Instant::now();
+    let plan = plan_row(ctx, record);
+    RunStats::add_time(&stats.assemble_time, start);
+    let (date, date_column, rate_date) = match plan? {
+        RowPlan::Skip => return Ok(None),
+        RowPlan::Blank(status) => {
+            return Ok(Some(OutRow {
+                record: layout.blank(record, status),
+                date: None,
+            }))
+        }
+        RowPlan::Fetch {
+            date,
+            date_column,
+            rate_date,
+        } => (date, date_column, rate_date),
+    };
+    let (exchange_rate, out_rate_date) = match config.rate_mode {
+        RateMode::Daily => (
+            fetch_exchange_rate(client, &rate_date, currency).await,
+            out_dates.format_or_iso(&rate_date)?,
+        ),
+        RateMode::MonthlyAverage => (
+            fetch_monthly_average(client, rate_date, currency, rate_dates).await,
+            rate_date.format("%Y-%m").to_string(),
+        ),
+    };
+    let exchange_rate = exchange_rate?;
+    let inverse = match config.inverse_precision {
+        Some(_) if exchange_rate.is_zero() => return Err(PipelineError::ZeroInverse { rate_date }),
+        Some(p) => amount::round(Decimal::ONE / exchange_rate, p, config.rounding),
+        None => String::new(),
+    };
+    let rate_change = match config.rate_change_precision {
+        Some(p) => rate_change(ctx, rate_date, exchange_rate)
+            .await
+            .map(|change| amount::round(change, p, config.rounding))
+            .unwrap_or_default(),
+        None => String::new(),
+    };
+    let start = Instant::now();
+    // All the amount columns of the row share the same rate, so it's fetched once.
+    let mut converted = Vec::new();
+    let mut status = RowStatus::Ok;
+    if let Some(amounts) = config.amounts.as_ref() {
+        for i in 0..amounts.columns.len() {
+            let value = amounts.convert(record, i, exchange_rate, config.rounding, stats)?;
+            converted.push(value.unwrap_or_else(|| {
+                status = RowStatus::AmountParseError;
+                String::new()
+            }));
+        }
+    }
+    let out_date = match date_column {
+        Some(_) => out_dates.format(&date).transpose()?,
+        None => None,
+    };
+    let fields = record.iter().enumerate().map(|(i, v)| match &out_date {
+        Some(out_date) if date_column == Some(i) => out_date.as_str(),
+        _ => v,
+    });
+    let record = layout.assemble(fields, |c| match c.value {
+        OutValue::Exchange => format_number(exchange_rate, config.rate_precision, config.rounding),
+        OutValue::Inverse => inverse.clone(),
+        OutValue::Reference => config
+            .reference_rate
+            .map(|r| format_number(r, config.rate_precision, config.rounding))
+            .unwrap_or_default(),
+        OutValue::ReferenceDelta => config
+            .reference_rate
+            .map(|r| format_number(exchange_rate - r, config.rate_precision, config.rounding))
+            .unwrap_or_default(),
+        OutValue::RateChange => rate_change.clone(),
+        OutValue::Converted(i) => converted[i].clone(),
+        OutValue::RateDate => out_rate_date.clone(),
+        OutValue::Status => status.as_str().to_string(),
+        OutValue::Year => date.year().to_string(),
+        OutValue::Month => date.month().to_string(),
+        OutValue::Day => date.day().to_string(),
+    });
+    RunStats::add_time(&stats.assemble_time, start);
+    Ok(Some(OutRow {
+        record,
+        date: Some(date),
+    }))
+}
+
+/// Dates whose rates must be fetched to process the rows, along with the number of rows
+/// which would fail before any request.
+pub fn request_dates(
+    ctx: &RowContext<'_>,
+    records: &[(StringRecord, bool)],
+) -> (BTreeSet<NaiveDate>, usize) {
+    let mut dates = BTreeSet::new();
+    let mut failed = 0;
+    for (record, _) in records.iter().filter(|(_, matches)| *matches) {
+        let rate_date = match plan_row(ctx, record) {
+            Ok(RowPlan::Fetch { rate_date, .. }) => rate_date,
+            Ok(_) => continue,
+            Err(e) => {
+                log::debug!(target: LOG_TARGET, "The row would fail - {}", e);
+                failed += 1;
+                continue;
+            }
+        };
+        let mut rate_dates = vec![rate_date];
+        if ctx.config.rate_change_precision.is_some() {
+            let previous = match ctx.config.rate_mode {
+                RateMode::Daily => ctx.config.rate_dates.previous_business_day(rate_date).ok(),
+                RateMode::MonthlyAverage => rate_date.with_day(1).and_then(|d| d.pred_opt()),
+            };
+            rate_dates.extend(previous);
+        }
+        for date in rate_dates {
+            match ctx.config.rate_mode {
+                RateMode::Daily => {
+                    dates.insert(date);
+                }
+                RateMode::MonthlyAverage => dates.extend(ctx.config.rate_dates.month_days(date)),
+            }
+        }
+    }
+    (dates, failed)
+}
+
+/// Fetches the rates of the dates the rows need, so adding them to the rows only reads
+/// the cache, and returns those dates. Failed requests are retried by the rows, which
+/// report the error.
+pub async fn prefetch_rates(
+    ctx: &RowContext<'_>,
+    records: &[(StringRecord, bool)],
+) -> BTreeSet<NaiveDate> {
+    // The rows are planned again when processed, so they're counted then.
+    let stats = RunStats::default();
+    let (dates, _) = request_dates(
+        &RowContext {
+            stats: &stats,
+            ..*ctx
+        },
+        records,
+    );
+    log::info!(target: LOG_TARGET, "{} dates to fetch for {} rows", dates.len(), records.len());
+    let requested: Vec<NaiveDate> = dates.iter().copied().collect();
+    // Rates of other currencies than the one of the rows only fail here, the tables are
+    // cached regardless.
+    let results = ctx.client.rate_results(&requested, &ctx.config.currency);
+    for (date, result) in results.await {
+        if let Err(e) = result {
+            log::debug!(target: LOG_TARGET, "Failed to prefetch the rate of {} - {}", date, e);
+        }
+    }
+    dates
+}
+
+/// An input row along with its outcome.
+pub struct ProcessedRecord {
+    pub input: StringRecord,
+    /// The output row, `None` if the row isn't written (e.g. out of the date range),
+    /// or the error of the row.
+    pub output: Result<Option<OutRow>, PipelineError>,
+}
+
+/// Adds the exchange rates to the rows, yielded in the input order as they complete.
+/// Rows not matching the filters (kept by [`FilterMode::Annotate`]) are yielded with empty
+/// added columns.
+///
+/// Up to the configured concurrency of rows are processed at once, and the records aren't read further until
+/// the consumer takes the first of them, so the stream holds at most that many rows (plus a
+/// chunk when prefetching). With prefetching, the rates of each chunk of rows are fetched at
+/// once before its rows are processed, the chunk being read upfront. When all the rates of a
+/// chunk are cached, its rows are processed one by one as they're taken.
+/// A fatal error (see [`PipelineError::is_fatal`]) ends the stream with the error, dropping
+/// the stream cancels the requests of the rows in progress.
+pub fn process_records<'a, I>(
+    records: I,
+    ctx: &'a RowContext<'a>,
+) -> impl Stream<Item = Result<ProcessedRecord, PipelineError>> + 'a
+where
+    I: Iterator<Item = (StringRecord, bool)> + 'a,
+{
+    let rows: Pin<Box<dyn Stream<Item = Result<ProcessedRecord, PipelineError>> + 'a>> =
+        match ctx.config.prefetch {
+            None => Box::pin(
+                futures::stream::iter(records)
+                    .map(move |(input, matches)| process_record(ctx, input, matches))
+                    .buffered(ctx.config.concurrency),
+            ),
+            Some(size) => {
+                let mut records = records;
+                let chunks = std::iter::from_fn(move || {
+                    let chunk: Vec<_> = records.by_ref().take(size).collect();
+                    (!chunk.is_empty()).then_some(chunk)
+                });
+                let stream = futures::stream::iter(chunks).then(move |chunk| async move {
+                    let start = Instant::now();
+                    let dates = prefetch_rates(ctx, &chunk).await;
+                    RunStats::add_time(&ctx.stats.prefetch_time, start);
+                    let cached = rates_cached(ctx.client, &dates).await;
+                    (chunk, cached)
+                });
+                Box::pin(stream.flat_map(move |(chunk, cached)| {
+                    let rows: Pin<Box<dyn Stream<Item = _> + 'a>> = match cached {
+                        true => Box::pin(process_cached(ctx, chunk)),
+                        false => Box::pin(
+                            futures::stream::iter(chunk)
+                                .map(move |(input, matches)| process_record(ctx, input, matches))
+                                .buffered(ctx.config.concurrency),
+                        ),
+                    };
+                    rows
+                }))
+            }
+        };
+    rows
+}
+
+/// Adds the exchange rate to the row, see [`process_records`].
+async fn process_record(
+    ctx: &RowContext<'_>,
+    input: StringRecord,
+    matches: bool,
+) -> Result<ProcessedRecord, PipelineError> {
+    if !matches {
+        let blank = OutRow {
+            record: ctx.config.layout.blank(&input, RowStatus::NotMatched),
+            date: None,
+        };
+        return Ok(ProcessedRecord {
+            input,
+            output: Ok(Some(blank)),
+        });
+    }
+    match add_exchange(ctx, &input).await {
+        Err(e) if e.is_fatal() => Err(e),
+        output => Ok(ProcessedRecord { input, output }),
+    }
+}
+
+/// Whether the rate tables of all the dates were fetched already.
+async fn rates_cached(client: &BnmClient, dates: &BTreeSet<NaiveDate>) -> bool {
+    for date in dates {
+        if !client.is_cached(*date).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// Processes rows whose rates are all cached one after the other, without buffering them:
+/// each row completes when first polled, so the stream doesn't wait on the runtime.
+/// A row which doesn't (e.g. the cache is locked by another lookup) is awaited in place,
+/// which keeps the output the same as [`process_records`] without prefetching.
+fn process_cached<'a>(
+    ctx: &'a RowContext<'a>,
+    records: Vec<(StringRecord, bool)>,
+) -> impl Stream<Item = Result<ProcessedRecord, PipelineError>> + 'a {
+    futures::stream::iter(records).then(move |(input, matches)| {
+        let mut row = Box::pin(process_record(ctx, input, matches));
+        match (&mut row).now_or_never() {
+            Some(processed) => Either::Left(futures::future::ready(processed)),
+            None => Either::Right(row),
+        }
+    })
+}
+
+/// How [`run`] handles the processed rows, besides adding their rates.
+pub struct RunOptions {
+    pub on_rate_error: OnRateError,
+    /// Filters of the output rows, those not matching aren't written.
+    pub post_filter: FilterSet,
+    /// Whether the grand total row is written, if amounts are converted.
+    pub totals: bool,
+    pub group_totals: Option<GroupTotals>,
+    /// Maximum number of failed rows, the run ends once more of them failed.
+    pub max_errors: Option<usize>,
+    /// Whether the output is written when more rows than `max_errors` failed.
+    pub keep_partial: bool,
+    /// Whether the failed rows are returned, e.g. to write an error report.
+    pub report_failures: bool,
+    /// The input files the records were read from, see [`input_file`].
+    pub in_files: Vec<PathBuf>,
+    /// How long the pending rows are waited for once interrupted.
+    pub interrupt_grace: std::time::Duration,
+    /// Start of the "fetch" phase, which may include the requests made before the run
+    /// (e.g. the reference rate).
+    pub fetch_start: Instant,
+}
+
+/// A row the exchange rate couldn't be added to, as listed in the error report.
+pub struct FailedRow {
+    pub line: u64,
+    pub status: RowStatus,
+    pub message: String,
+    pub record: StringRecord,
+}
+
+/// How a [`run`] ended, once its output is written.
+pub struct RunSummary {
+    /// The failed rows, if [`RunOptions::report_failures`] is set.
+    pub failed_rows: Vec<FailedRow>,
+    /// Line of the last processed row.
+    pub last_line: u64,
+    /// Whether the run was interrupted, the rows after `last_line` not being processed.
+    pub interrupted: bool,
+    /// Set if more rows than [`RunOptions::max_errors`] failed, the output being written only
+    /// with [`RunOptions::keep_partial`].
+    pub exceeded: Option<RunError>,
+}
+
+/// Adds the exchange rates to the records, writing them after the headers, along with the
+/// totals. The rows are counted in the stats of the context, up to the "write" phase.
+///
+/// The writer is opened with `open` before the first row, which is written as soon as it's
+/// processed, unless the output depends on all the rows (group totals sort them) or mustn't be
+/// written if the run fails (with [`OnRateError::Fail`], or `max_errors` without
+/// `keep_partial`): it's opened once all of them are processed then. Once `interrupt`
+/// completes, no more records are read, and the rows in progress are waited for up to the
+/// grace period before writing the processed ones.
+pub async fn run<I, W>(
+    ctx: &RowContext<'_>,
+    records: I,
+    headers: Option<&StringRecord>,
+    options: &RunOptions,
+    mut open: impl FnMut() -> std::io::Result<csv::Writer<W>>,
+    interrupt: impl Future<Output = ()>,
+) -> Result<RunSummary, RunError>
+where
+    I: Iterator<Item = (StringRecord, bool)>,
+    W: std::io::Write,
+{
+    let RowContext { config, stats, .. } = *ctx;
+    let PipelineConfig {
+        date_parser,
+        layout,
+        amounts,
+        ..
+    } = config;
+    let out_headers = headers.map(|h| get_out_headers(h, layout));
+    let on_rate_error = options.on_rate_error;
+    // Set once interrupted, no more records are read then, only the rows in progress are
+    // waited for.
+    let interrupted = AtomicBool::new(false);
+    let records = records.take_while(|_| !interrupted.load(Ordering::Relaxed));
+    let mut results = process_records(records, ctx).map(|result| {
+        let record = result.map_err(|e| RunError::Fatal(Box::new(e)))?;
+        match record.output {
+            // Returning an error drops the stream, cancelling the requests of the other rows.
+            Err(error) if on_rate_error == OnRateError::Fail => Err(RunError::Row {
+                row: row_label(
+                    input_file(&options.in_files, &record.input),
+                    row_line(&record.input),
+                ),
+                date: date_parser.describe(&record.input),
+                error: Box::new(error),
+            }),
+            _ => Ok(record),
+        }
+    });
+    // Rows are written as soon as they're processed, unless the output depends on all of them
+    // (group totals sort them) or mustn't be written if the run fails.
+    let with_totals = amounts.is_some() && (options.totals || options.group_totals.is_some());
+    let buffered = (with_totals && options.group_totals.is_some())
+        || on_rate_error == OnRateError::Fail
+        || (options.max_errors.is_some() && !options.keep_partial);
+    let mut writer = match buffered {
+        true => None,
+        false => {
+            let mut writer = open().map_err(RunError::Create)?;
+            if let Some(h) = out_headers.as_ref() {
+                writer.write_record(h).map_err(RunError::Write)?;
+            }
+            Some(writer)
+        }
+    };
+    let in_width = headers.map(|h| h.len()).unwrap_or_default();
+    let mut total = match amounts.as_ref() {
+        Some(amounts) if options.totals => Some(Totals::new(in_width, amounts)),
+        _ => None,
+    };
+    let mut out_rows = Vec::new();
+    let mut rows_written = 0;
+    let mut summary = RunSummary {
+        failed_rows: Vec::new(),
+        last_line: 0,
+        interrupted: false,
+        exceeded: None,
+    };
+    let mut interrupt = Box::pin(interrupt);
+    let mut grace_deadline = None;
+    loop {
+        let result = match grace_deadline {
+            None => tokio::select! {
+                result = results.next() => result,
+                _ = &mut interrupt => {
+                    interrupted.store(true, Ordering::Relaxed);
+                    summary.interrupted = true;
+                    grace_deadline = Some(tokio::time::Instant::now() + options.interrupt_grace);
+                    continue;
+                }
+            },
+            Some(deadline) => tokio::time::timeout_at(deadline, results.next())
+                .await
+                .unwrap_or_default(),
+        };
+        let ProcessedRecord {
+            input: in_record,
+            output: result,
+        } = match result {
+            Some(result) => result?,
+            None => break,
+        };
+        summary.last_line = row_line(&in_record);
+        let row = match result {
+            Ok(row) => row,
+            Err(e) => {
+                let status = e.status();
+                stats.count_failure(status);
+                if options.report_failures {
+                    summary.failed_rows.push(FailedRow {
+                        line: row_line(&in_record),
+                        status,
+                        message: e.to_string(),
+                        record: in_record.clone(),
+                    });
+                }
+                let label = row_label(
+                    input_file(&options.in_files, &in_record),
+                    row_line(&in_record),
+                );
+                if on_rate_error == OnRateError::Drop {
+                    RunStats::increment(&stats.rows_failed);
+                    log::warn!(
+                        target: LOG_TARGET,
+                        row = row_line(&in_record),
+                        date = date_parser.describe(&in_record).as_str(),
+                        category = status.as_str();
+                        "{}: failed to add exchange rate - {}", label, e
+                    );
+                    None
+                } else {
+                    RunStats::increment(&stats.rows_failed_blank);
+                    log::warn!(
+                        target: LOG_TARGET,
+                        row = row_line(&in_record),
+                        date = date_parser.describe(&in_record).as_str(),
+                        category = status.as_str();
+                        "{}: failed to add exchange rate, keeping the row - {}", label, e
+                    );
+                    Some(OutRow {
+                        record: layout.blank(&in_record, status),
+                        date: None,
+                    })
+                }
+            }
+        };
+        match row {
+            Some(row) if options.post_filter.matches(&row.record) => match writer.as_mut() {
+                Some(writer) => {
+                    let start = Instant::now();
+                    if let (Some(total), Some(amounts)) = (total.as_mut(), amounts.as_ref()) {
+                        total.add(&in_record, &row.record, layout, amounts, stats);
+                    }
+                    writer.write_record(&row.record).map_err(RunError::Write)?;
+                    RunStats::add_time(&stats.write_time, start);
+                    rows_written += 1;
+                }
+                None => out_rows.push((in_record, row)),
+            },
+            Some(_) => RunStats::increment(&stats.rows_post_filtered),
+            None => {}
+        }
+        let failures = stats.failures();
+        let failed = failures.iter().map(|(_, count)| count).sum::<usize>();
+        if let Some(max) = options.max_errors.filter(|max| failed > *max) {
+            // The most frequent category decides the exit code.
+            summary.exceeded = Some(RunError::TooManyErrors {
+                failed,
+                max,
+                status: failures[0].0,
+                failures: stats.describe_failures(),
+            });
+            break;
+        }
+    }
+    // Cancels the requests of the rows left.
+    drop(results);
+    if summary.exceeded.is_some() && !options.keep_partial {
+        return Ok(summary);
+    }
+    let phase_start = stats.end_phase("fetch", options.fetch_start);
+    let mut writer = match writer {
+        Some(mut writer) => {
+            if let (Some(total), Some(amounts)) = (total, amounts.as_ref()) {
+                let footer = total.record("TOTAL", layout, amounts, config.rounding);
+                writer.write_record(&footer).map_err(RunError::Write)?;
+            }
+            writer
+        }
+        None => {
+            rows_written = out_rows.len();
+            let (out_records, footer) = match amounts.as_ref() {
+                Some(amounts) if with_totals => {
+                    add_totals(out_rows, in_width, config, amounts, options, stats)
+                }
+                _ => (
+                    out_rows.into_iter().map(|(_, row)| row.record).collect(),
+                    None,
+                ),
+            };
+            let mut writer = open().map_err(RunError::Create)?;
+            let headers = out_headers.as_ref().into_iter();
+            for record in headers.chain(&out_records).chain(footer.as_ref()) {
+                writer.write_record(record).map_err(RunError::Write)?;
+            }
+            writer
+        }
+    };
+    writer.flush().map_err(RunError::Flush)?;
+    RunStats::add_time(&stats.write_time, phase_start);
+    stats.end_phase("write", phase_start);
+    stats.rows_written.store(rows_written, Ordering::Relaxed);
+    Ok(summary)
+}
+
+/// Percent change of the rate versus the previous business day (or the previous month's
+/// average rate), `None` (with a warning) if the previous rate isn't available.
+pub async fn rate_change(
+    ctx: &RowContext<'_>,
+    rate_date: NaiveDate,
+    rate: Decimal,
+) -> Option<Decimal> {
+    let RowContext {
+        config,
+        stats,
+        client,
+    } = ctx;
+    let PipelineConfig {
+        rate_dates,
+        currency,
+        ..
+    } = config;
+    let previous = async {
+        let (date, rate) = match config.rate_mode {
+            RateMode::Daily => {
+                let date = rate_dates.previous_business_day(rate_date)?;
+                (date, fetch_exchange_rate(client, &date, currency).await?)
+            }
+            RateMode::MonthlyAverage => {
+                let date = rate_date
+                    .with_day(1)
+                    .and_then(|d| d.pred_opt())
+                    .ok_or(PipelineError::NoPreviousMonth { date: rate_date })?;
+                (
+                    date,
+                    fetch_monthly_average(client, date, currency, rate_dates).await?,
+                )
+            }
+        };
+        if rate.is_zero() {
+            return Err(PipelineError::ZeroPreviousRate { date });
+        }
+        Ok(rate)
+    };
+    match previous.await {
+        Ok(previous) => Some((rate - previous) / previous * Decimal::ONE_HUNDRED),
+        Err(e) => {
+            RunStats::increment(&stats.rate_changes_missing);
+            log::warn!(
+                target: LOG_TARGET,
+                date:% = rate_date,
+                currency = currency.as_str(),
+                category = RowStatus::RateMissing.as_str();
+                "No previous rate for {} - {}", rate_date, e
+            );
+            None
+        }
+    }
+}
+
+/// Returns the headers of the output rows, with the names of the added columns.
+pub fn get_out_headers(headers: &StringRecord, layout: &OutLayout) -> StringRecord {
+    layout.assemble(headers, |c| c.name.clone())
+}
+
+/// Fails if an added column has the same name as another output column,
+/// listing where each of them comes from.
+/// Duplicates among the input columns are written as they are.
+pub fn check_out_headers(
+    headers: &StringRecord,
+    out_headers: &StringRecord,
+    layout: &OutLayout,
+) -> Result<(), ParseError> {
+    const INPUT: &str = "the input";
+    let sources = layout.assemble(std::iter::repeat_n(INPUT, headers.len()), |c| {
+        c.value.option().to_string()
+    });
+    let mut columns: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (name, source) in out_headers.iter().zip(sources.iter()) {
+        columns.entry(name).or_default().push(source);
+    }
+    let duplicates: Vec<(String, Vec<String>)> = columns
+        .iter()
+        .filter(|(_, sources)| sources.len() > 1 && sources.iter().any(|s| *s != INPUT))
+        .map(|(name, sources)| {
+            let sources = sources.iter().map(|s| s.to_string()).collect();
+            (name.to_string(), sources)
+        })
+        .collect();
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+    Err(ParseError::DuplicateColumns {
+        columns: duplicates,
+    })
+}
+
+/// Reads the records as they're consumed, along with whether they match the filter,
+/// non-matching records are dropped in exclude mode.
+pub fn read_records<'r, T>(
+    reader: &'r mut Reader<T>,
+    file: Option<&'r std::path::PathBuf>,
+    pad_to: Option<usize>,
+    filter: &'r FilterSet,
+    mode: FilterMode,
+    stats: &'r RunStats,
+) -> impl Iterator<Item = (StringRecord, bool)> + 'r
+where
+    T: std::io::Read,
+{
+    reader
+        .records()
+        .filter_map(move |r| {
+            r.map_err(|e| {
+                let row = e.position().map(|p| p.line()).unwrap_or_default();
+                stats.count_failure(RowStatus::ReadError);
+                log::warn!(
+                    target: LOG_TARGET,
+                    row,
+                    category = RowStatus::ReadError.as_str();
+                    "{}: skipping row due to parse error - {}", row_label(file, row), e
+                );
+                e
+            })
+            .ok()
+        })
+        .filter_map(move |mut r| {
+            RunStats::increment(&stats.rows_read);
+            if let Some(width) = pad_to {
+                for _ in r.len()..width {
+                    r.push_field("");
+                }
+            }
+            let matches = filter.matches(&r);
+            match (matches, filter.is_inverted()) {
+                (false, true) => RunStats::increment(&stats.rows_inverted),
+                (false, false) => RunStats::increment(&stats.rows_filtered),
+                (true, _) => RunStats::increment(&stats.rows_matched),
+            }
+            match (matches, mode) {
+                (false, FilterMode::Exclude) => None,
+                _ => Some((r, matches)),
+            }
+        })
+}
+
+/// Line of the record in the input file, 0 if unknown.
+pub fn row_line(record: &StringRecord) -> u64 {
+    record.position().map(|p| p.line()).unwrap_or_default()
+}
+
+/// Where a row is in the input, prefixing the messages about it (e.g. "line 4 (march.csv)").
+pub fn row_label(file: Option<&std::path::PathBuf>, line: u64) -> String {
+    match file {
+        Some(file) => format!("line {} ({})", line, file.display()),
+        None => format!("line {}", line),
+    }
+}
+
+/// Input file of a row, the position of the rows read from several files being the index of
+/// their file.
+pub fn input_file<'a>(files: &'a [PathBuf], record: &StringRecord) -> Option<&'a PathBuf> {
+    match files.len() {
+        0 | 1 => files.first(),
+        _ => record
+            .position()
+            .and_then(|p| files.get(p.record() as usize)),
+    }
+}
+
+/// Drops records with the same values in the key columns, keeping the first or last one.
+pub fn dedupe_records(
+    records: Vec<(StringRecord, bool)>,
+    columns: &[usize],
+    keep: DedupeKeep,
+    stats: &RunStats,
+) -> Vec<(StringRecord, bool)> {
+    let mut seen = HashSet::with_capacity(records.len());
+    let mut is_new = |record: &StringRecord| {
+        let key: Vec<String> = columns
+            .iter()
+            .map(|c| record.get(*c).unwrap_or_default().to_string())
+            .collect();
+        let new = seen.insert(key);
+        if !new {
+            RunStats::increment(&stats.rows_duplicate);
+        }
+        new
+    };
+    match keep {
+        DedupeKeep::First => records.into_iter().filter(|(r, _)| is_new(r)).collect(),
+        DedupeKeep::Last => {
+            let mut records: Vec<_> = records
+                .into_iter()
+                .rev()
+                .filter(|(r, _)| is_new(r))
+                .collect();
+            records.reverse();
+            records
+        }
+    }
+}
+
+/// The candidate delimiter splitting the first line into most fields, if any.
+pub fn detect_delimiter(data: &[u8]) -> Option<char> {
+    let line = data.split(|b| *b == b'\n').next()?;
+    CANDIDATE_DELIMITERS
+        .iter()
+        .map(|d| (*d, line.iter().filter(|b| **b == *d as u8).count()))
+        .filter(|(_, count)| *count > 0)
+        .max_by_key(|(_, count)| *count)
+        .map(|(d, _)| d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(year, month, day)
+    }
+
+    #[test]
+    fn excel_serials() {
+        let cases = [
+            ("1", date(1900, 1, 1)),
+            ("31", date(1900, 1, 31)),
+            ("59", date(1900, 2, 28)),
+            ("61", date(1900, 3, 1)),
+            ("36526", date(2000, 1, 1)),
+            ("43831", date(2020, 1, 1)),
+            ("45366", date(2024, 3, 15)),
+            (" 45366 ", date(2024, 3, 15)),
+            ("2958465", date(9999, 12, 31)),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse_excel_serial(value).unwrap(), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn excel_serial_time_is_truncated() {
+        assert_eq!(parse_excel_serial("45366.0").unwrap(), date(2024, 3, 15));
+        assert_eq!(parse_excel_serial("45366.5").unwrap(), date(2024, 3, 15));
+        assert_eq!(
+            parse_excel_serial("45366.99999").unwrap(),
+            date(2024, 3, 15)
+        );
+    }
+
+    #[test]
+    fn invalid_excel_serials() {
+        for value in ["", "abc", "45366,5", "2024-03-15"] {
+            assert!(
+                matches!(
+                    parse_excel_serial(value),
+                    Err(ParseError::ExcelSerial { .. })
+                ),
+                "{}",
+                value
+            );
+        }
+        for value in ["60", "60.5"] {
+            assert!(
+                matches!(
+                    parse_excel_serial(value),
+                    Err(ParseError::ExcelLeapDay { .. })
+                ),
+                "{}",
+                value
+            );
+        }
+        for value in ["0", "0.5", "-1", "2958466"] {
+            assert!(
+                matches!(
+                    parse_excel_serial(value),
+                    Err(ParseError::ExcelSerialRange { .. })
+                ),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn excel_serial_format_renders_as_usual() {
+        let parser = DateParser::new(DateSource::Columns(vec![0]), EXCEL_SERIAL_FORMAT);
+        let date = parser.parse("45366").unwrap();
+        let formatter = DateFormatter::new(Some("%d.%m.%Y".to_string()), DateLocale::En);
+        assert_eq!(formatter.format(&date).unwrap().unwrap(), "15.03.2024");
+    }
+
+    fn parser(format: &str) -> DateParser {
+        DateParser::new(DateSource::Columns(vec![0]), format)
+    }
+
+    #[test]
+    fn year_pivot_boundary() {
+        let mut parser = parser("%m/%d/%y");
+        parser.year_pivot = Some(50);
+        let cases = [
+            ("03/15/00", date(2000, 3, 15)),
+            ("03/15/24", date(2024, 3, 15)),
+            ("03/15/49", date(2049, 3, 15)),
+            ("03/15/50", date(1950, 3, 15)),
+            ("03/15/69", date(1969, 3, 15)),
+            ("03/15/99", date(1999, 3, 15)),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parser.parse_value(value).unwrap(), expected, "{}", value);
+        }
+        parser.year_pivot = Some(70);
+        assert_eq!(parser.parse_value("03/15/69").unwrap(), date(2069, 3, 15));
+        assert_eq!(parser.parse_value("03/15/70").unwrap(), date(1970, 3, 15));
+        parser.year_pivot = Some(0);
+        assert_eq!(parser.parse_value("03/15/00").unwrap(), date(1900, 3, 15));
+        parser.year_pivot = Some(100);
+        assert_eq!(parser.parse_value("03/15/99").unwrap(), date(2099, 3, 15));
+    }
+
+    #[test]
+    fn year_pivot_defaults_to_chrono() {
+        let parser = parser("%m/%d/%y");
+        assert_eq!(parser.parse_value("03/15/69").unwrap(), date(2069, 3, 15));
+        assert_eq!(parser.parse_value("03/15/70").unwrap(), date(1970, 3, 15));
+    }
+
+    #[test]
+    fn year_pivot_ignores_four_digit_years() {
+        let mut parser = parser("%m/%d/%Y");
+        parser.year_pivot = Some(70);
+        assert_eq!(parser.parse_value("03/15/1969").unwrap(), date(1969, 3, 15));
+        assert_eq!(parser.parse_value("03/15/2069").unwrap(), date(2069, 3, 15));
+    }
+
+    #[test]
+    fn year_pivot_to_a_non_leap_year() {
+        let mut parser = parser("%m/%d/%y");
+        parser.year_pivot = Some(0);
+        assert!(matches!(
+            parser.parse_value("02/29/00"),
+            Err(ParseError::PivotDate { year: 1900, .. })
+        ));
+    }
+
+    fn resolver(today: NaiveDate) -> RateDateResolver {
+        RateDateResolver {
+            today,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn previous_business_day_rate_dates() {
+        let stats = RunStats::default();
+        let mut rate_dates = resolver(date(2024, 12, 31));
+        rate_dates.calendar = Some(HolidayCalendar::default());
+        let cases = [
+            // Weekend, Easter Monday and Memorial Day, New Year's Day.
+            (date(2024, 3, 16), date(2024, 3, 15)),
+            (date(2024, 3, 17), date(2024, 3, 15)),
+            (date(2024, 5, 6), date(2024, 5, 3)),
+            (date(2024, 5, 13), date(2024, 5, 10)),
+            (date(2024, 1, 1), date(2023, 12, 29)),
+            (date(2024, 3, 15), date(2024, 3, 15)),
+        ];
+        for (day, expected) in cases {
+            assert_eq!(
+                rate_dates.resolve(day, &stats).unwrap(),
+                expected,
+                "{}",
+                day
+            );
+        }
+        assert_eq!(
+            resolver(date(2024, 12, 31))
+                .resolve(date(2024, 3, 16), &stats)
+                .unwrap(),
+            date(2024, 3, 16)
+        );
+    }
+
+    #[test]
+    fn rate_date_bounds() {
+        let stats = RunStats::default();
+        let rate_dates = resolver(date(2024, 3, 15));
+        assert_eq!(
+            rate_dates.resolve(date(1994, 1, 1), &stats).unwrap(),
+            date(1994, 1, 1)
+        );
+        let error = rate_dates.resolve(date(1993, 12, 31), &stats).unwrap_err();
+        assert!(matches!(error, PipelineError::BeforeMinDate { .. }));
+        assert_eq!(error.status(), RowStatus::RateMissing);
+        assert!(error.to_string().contains("predates available data"));
+        assert!(rate_dates.resolve(date(1924, 3, 15), &stats).is_err());
+        assert_eq!(
+            rate_dates.resolve(date(2024, 3, 15), &stats).unwrap(),
+            date(2024, 3, 15)
+        );
+        let error = rate_dates.resolve(date(2024, 3, 16), &stats).unwrap_err();
+        assert!(matches!(
+            error,
+            PipelineError::FutureDate { fatal: false, .. }
+        ));
+        assert_eq!(stats.future_dates_skipped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn custom_min_date() {
+        let stats = RunStats::default();
+        let mut rate_dates = resolver(date(2024, 3, 15));
+        rate_dates.min_date = date(2020, 1, 1);
+        assert!(rate_dates.resolve(date(2020, 1, 1), &stats).is_ok());
+        assert!(matches!(
+            rate_dates.resolve(date(2019, 12, 31), &stats),
+            Err(PipelineError::BeforeMinDate { .. })
+        ));
+        // The bound applies to the rate date, after the offset.
+        rate_dates.offset = -1;
+        assert!(rate_dates.resolve(date(2020, 1, 1), &stats).is_err());
+        assert!(rate_dates.resolve(date(2020, 1, 2), &stats).is_ok());
+    }
+
+    #[test]
+    fn iso_week_dates() {
+        let parser = parser("%G-W%V-%u");
+        let cases = [
+            ("2024-W11-5", date(2024, 3, 15)),
+            ("2024-W01-1", date(2024, 1, 1)),
+            ("2025-W01-1", date(2024, 12, 30)),
+            ("2025-W01-3", date(2025, 1, 1)),
+            ("2021-W52-7", date(2022, 1, 2)),
+            ("2020-W53-5", date(2021, 1, 1)),
+            ("2020-W53-7", date(2021, 1, 3)),
+            ("2026-W53-1", date(2026, 12, 28)),
+            ("2027-W01-1", date(2027, 1, 4)),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parser.parse_value(value).unwrap(), expected, "{}", value);
+        }
+        // 2021 has 52 weeks.
+        assert!(parser.parse_value("2021-W53-1").is_err());
+        let formatter = DateFormatter::new(Some("%Y-%m-%d".to_string()), DateLocale::En);
+        let date = parser.parse_value("2020-W53-5").unwrap();
+        assert_eq!(formatter.format(&date).unwrap().unwrap(), "2021-01-01");
+        let weeks = DateFormatter::new(Some("%G-W%V-%u".to_string()), DateLocale::En);
+        assert_eq!(weeks.format(&date).unwrap().unwrap(), "2020-W53-5");
+    }
+
+    #[test]
+    fn iso_week_year_pivot() {
+        let mut parser = parser("%g-W%V-%u");
+        parser.year_pivot = Some(50);
+        assert_eq!(parser.parse_value("20-W53-5").unwrap(), date(2021, 1, 1));
+        assert_eq!(parser.parse_value("25-W01-1").unwrap(), date(2024, 12, 30));
+        assert_eq!(parser.parse_value("98-W53-4").unwrap(), date(1998, 12, 31));
+        assert_eq!(parser.parse_value("99-W52-5").unwrap(), date(1999, 12, 31));
+        // 1998 has 53 weeks, 2098 doesn't.
+        parser.year_pivot = Some(100);
+        assert!(matches!(
+            parser.parse_value("98-W53-4"),
+            Err(ParseError::PivotWeek {
+                week: 53,
+                year: 2098
+            })
+        ));
+    }
+
+    #[test]
+    fn parses_and_renders_in_different_locales() {
+        let mut parser = parser("%d %B %Y");
+        parser.locale = DateLocale::Ro;
+        let formatter = DateFormatter::new(Some("%d %B %Y".to_string()), DateLocale::Ru);
+        let english = DateFormatter::new(Some("%d %b %Y".to_string()), DateLocale::En);
+        let cases = [
+            ("15 ianuarie 2024", "15 января 2024", "15 Jan 2024"),
+            ("29 februarie 2024", "29 февраля 2024", "29 Feb 2024"),
+            ("15 martie 2024", "15 марта 2024", "15 Mar 2024"),
+            ("1 mai 2024", "01 мая 2024", "01 May 2024"),
+            ("31 decembrie 2024", "31 декабря 2024", "31 Dec 2024"),
+        ];
+        for (value, russian, en) in cases {
+            let date = parser.parse_value(value).unwrap();
+            assert_eq!(formatter.format(&date).unwrap().unwrap(), russian);
+            assert_eq!(english.format(&date).unwrap().unwrap(), en);
+        }
+    }
+
+    #[test]
+    fn round_trips_all_months_between_locales() {
+        let locales = [DateLocale::En, DateLocale::Ro, DateLocale::Ru];
+        for input in locales {
+            for output in locales {
+                let mut output_parser = parser("%A, %d %B %Y");
+                output_parser.locale = output;
+                let formatter = DateFormatter::new(Some("%A, %d %B %Y".to_string()), output);
+                let source = DateFormatter::new(Some("%d %b %Y".to_string()), input);
+                let mut input_parser = parser("%d %b %Y");
+                input_parser.locale = input;
+                for month in 1..=12 {
+                    let day = date(2024, month, 15);
+                    let value = source.format(&day).unwrap().unwrap();
+                    let parsed = input_parser.parse_value(&value).unwrap();
+                    let rendered = formatter.format(&parsed).unwrap().unwrap();
+                    assert_eq!(
+                        output_parser.parse_value(&rendered).unwrap(),
+                        day,
+                        "{}",
+                        rendered
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn timestamps_with_offsets_at_midnight() {
+        let parser = parser("%Y-%m-%dT%H:%M:%S%z");
+        let cases = [
+            // Chisinau is UTC+2 in winter and UTC+3 in summer.
+            ("2024-03-15T21:59:59+0000", date(2024, 3, 15)),
+            ("2024-03-15T22:00:00+0000", date(2024, 3, 16)),
+            ("2024-03-15T23:30:00+0000", date(2024, 3, 16)),
+            ("2024-07-15T20:59:59+0000", date(2024, 7, 15)),
+            ("2024-07-15T21:00:00+0000", date(2024, 7, 16)),
+            ("2024-03-16T00:30:00+0200", date(2024, 3, 16)),
+            ("2024-03-15T18:00:00-0500", date(2024, 3, 16)),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parser.parse_value(value).unwrap(), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn timestamps_in_timezone() {
+        let mut parser = parser("%Y-%m-%d %H:%M");
+        parser.in_timezone = Some(chrono_tz::UTC);
+        assert_eq!(
+            parser.parse_value("2024-03-15 21:59").unwrap(),
+            date(2024, 3, 15)
+        );
+        assert_eq!(
+            parser.parse_value("2024-03-15 22:00").unwrap(),
+            date(2024, 3, 16)
+        );
+        // Around the DST transitions of March 31st and October 27th 2024.
+        assert_eq!(
+            parser.parse_value("2024-03-30 22:00").unwrap(),
+            date(2024, 3, 31)
+        );
+        assert_eq!(
+            parser.parse_value("2024-03-31 20:59").unwrap(),
+            date(2024, 3, 31)
+        );
+        assert_eq!(
+            parser.parse_value("2024-03-31 21:00").unwrap(),
+            date(2024, 4, 1)
+        );
+        assert_eq!(
+            parser.parse_value("2024-10-26 20:59").unwrap(),
+            date(2024, 10, 26)
+        );
+        assert_eq!(
+            parser.parse_value("2024-10-26 21:00").unwrap(),
+            date(2024, 10, 27)
+        );
+        assert_eq!(
+            parser.parse_value("2024-10-27 21:59").unwrap(),
+            date(2024, 10, 27)
+        );
+        assert_eq!(
+            parser.parse_value("2024-10-27 22:00").unwrap(),
+            date(2024, 10, 28)
+        );
+        parser.target_timezone = chrono_tz::America::New_York;
+        assert_eq!(
+            parser.parse_value("2024-03-15 03:59").unwrap(),
+            date(2024, 3, 14)
+        );
+        assert_eq!(
+            parser.parse_value("2024-03-15 04:00").unwrap(),
+            date(2024, 3, 15)
+        );
+    }
+
+    #[test]
+    fn local_times_skipped_or_repeated_by_dst() {
+        let mut parser = parser("%Y-%m-%d %H:%M");
+        parser.in_timezone = Some(chrono_tz::Europe::Chisinau);
+        assert!(matches!(
+            parser.parse_value("2024-03-31 02:30"),
+            Err(ParseError::NonexistentTime { .. })
+        ));
+        assert_eq!(
+            parser.parse_value("2024-03-31 03:30").unwrap(),
+            date(2024, 3, 31)
+        );
+        // The first of the repeated hours is still in summer time, the day before in UTC.
+        parser.target_timezone = chrono_tz::UTC;
+        assert_eq!(
+            parser.parse_value("2024-10-27 02:30").unwrap(),
+            date(2024, 10, 26)
+        );
+        assert_eq!(
+            parser.parse_value("2024-10-27 03:30").unwrap(),
+            date(2024, 10, 27)
+        );
+    }
+
+    fn headers() -> StringRecord {
+        StringRecord::from(vec!["Date", "Type", "Amount"])
+    }
+
+    fn record(fields: &[&str]) -> StringRecord {
+        StringRecord::from(fields.to_vec())
+    }
+
+    #[test]
+    fn date_sources() {
+        let headers = headers();
+        assert!(matches!(
+            DateSource::new(Some(&headers), Some(&"Date".to_string()), None).unwrap(),
+            DateSource::Columns(columns) if columns == [0]
+        ));
+        assert!(matches!(
+            DateSource::new(None, Some(&"2,0".to_string()), None).unwrap(),
+            DateSource::Columns(columns) if columns == [2, 0]
+        ));
+        let parts = DateSource::new(None, None, Some(&"2,1,0".to_string())).unwrap();
+        assert!(matches!(
+            parts,
+            DateSource::Parts {
+                year: 2,
+                month: 1,
+                day: 0
+            }
+        ));
+        assert_eq!(parts.main_column(), 0);
+        assert!(matches!(
+            DateSource::new(None, None, Some(&"2,1".to_string())),
+            Err(ParseError::DateParts { .. })
+        ));
+        assert!(matches!(
+            DateSource::new(None, None, None),
+            Err(ParseError::NoDateColumn)
+        ));
+        assert!(matches!(
+            DateSource::new(Some(&headers), Some(&"Dates".to_string()), None),
+            Err(ParseError::UnknownColumn { suggestion: Some(s), .. }) if s == "Date"
+        ));
+    }
+
+    #[test]
+    fn parses_records() {
+        let columns = || DateSource::Columns(vec![0, 1]);
+        let parts = || DateSource::Parts {
+            year: 0,
+            month: 1,
+            day: 2,
+        };
+        let pivot = |mut parser: DateParser| {
+            parser.year_pivot = Some(50);
+            parser
+        };
+        let locale = |mut parser: DateParser| {
+            parser.locale = DateLocale::Ru;
+            parser
+        };
+        type Case<'a> = (
+            DateParser,
+            &'a [&'a str],
+            Option<(NaiveDate, Option<usize>)>,
+        );
+        let cases: Vec<Case> = vec![
+            (
+                DateParser::new(columns(), "%Y-%m-%d"),
+                &["2024-03-15", "2024-03-16"],
+                Some((date(2024, 3, 15), Some(0))),
+            ),
+            // Fallback columns, for empty and invalid cells.
+            (
+                DateParser::new(columns(), "%Y-%m-%d"),
+                &["", "2024-03-16"],
+                Some((date(2024, 3, 16), Some(1))),
+            ),
+            (
+                DateParser::new(columns(), "%Y-%m-%d"),
+                &["pending", "2024-03-16"],
+                Some((date(2024, 3, 16), Some(1))),
+            ),
+            (DateParser::new(columns(), "%Y-%m-%d"), &["", ""], None),
+            (
+                DateParser::new(columns(), "%Y-%m-%d"),
+                &["2024-03-15x"],
+                None,
+            ),
+            (
+                DateParser::new(parts(), "ignored"),
+                &["2024", "3", "15"],
+                Some((date(2024, 3, 15), None)),
+            ),
+            (
+                DateParser::new(parts(), "ignored"),
+                &[" 2024", "03 ", "15"],
+                Some((date(2024, 3, 15), None)),
+            ),
+            (
+                DateParser::new(parts(), "ignored"),
+                &["2024", "13", "15"],
+                None,
+            ),
+            (
+                DateParser::new(parts(), "ignored"),
+                &["2023", "2", "29"],
+                None,
+            ),
+            (
+                DateParser::new(parts(), "ignored"),
+                &["2024", "x", "15"],
+                None,
+            ),
+            (DateParser::new(parts(), "ignored"), &["2024", "3"], None),
+            (
+                pivot(DateParser::new(columns(), "%d/%m/%y")),
+                &["15/03/49"],
+                Some((date(2049, 3, 15), Some(0))),
+            ),
+            (
+                pivot(DateParser::new(columns(), "%d/%m/%y")),
+                &["15/03/50"],
+                Some((date(1950, 3, 15), Some(0))),
+            ),
+            (
+                DateParser::new(columns(), EXCEL_SERIAL_FORMAT),
+                &["45366"],
+                Some((date(2024, 3, 15), Some(0))),
+            ),
+            (
+                DateParser::new(columns(), EXCEL_SERIAL_FORMAT),
+                &["1"],
+                Some((date(1900, 1, 1), Some(0))),
+            ),
+            (
+                DateParser::new(columns(), EXCEL_SERIAL_FORMAT),
+                &["59.75"],
+                Some((date(1900, 2, 28), Some(0))),
+            ),
+            (
+                DateParser::new(columns(), EXCEL_SERIAL_FORMAT),
+                &["60"],
+                None,
+            ),
+            (
+                DateParser::new(columns(), EXCEL_SERIAL_FORMAT),
+                &["61"],
+                Some((date(1900, 3, 1), Some(0))),
+            ),
+            (
+                DateParser::new(columns(), "%G-W%V-%u"),
+                &["2020-W53-5"],
+                Some((date(2021, 1, 1), Some(0))),
+            ),
+            (
+                DateParser::new(columns(), "%G-W%V-%u"),
+                &["2025-W01-1"],
+                Some((date(2024, 12, 30), Some(0))),
+            ),
+            (
+                locale(DateParser::new(columns(), "%d %B %Y")),
+                &["15 марта 2024"],
+                Some((date(2024, 3, 15), Some(0))),
+            ),
+            (
+                locale(DateParser::new(columns(), "%d %b %Y")),
+                &["", "2 сент. 2024"],
+                Some((date(2024, 9, 2), Some(1))),
+            ),
+        ];
+        for (parser, fields, expected) in cases {
+            let parsed = parser.parse_record(&record(fields)).ok();
+            assert_eq!(parsed, expected, "{} {:?}", parser.format, fields);
+        }
+    }
+
+    #[test]
+    fn short_rows() {
+        let parser = DateParser::new(DateSource::Columns(vec![2]), "%Y-%m-%d");
+        let error = parser.parse_record(&record(&["x", "y"])).unwrap_err();
+        assert!(error.is_short_row());
+        assert_eq!(PipelineError::Parse(error).status(), RowStatus::RaggedRow);
+        let parser = DateParser::new(
+            DateSource::Parts {
+                year: 0,
+                month: 1,
+                day: 2,
+            },
+            "",
+        );
+        let error = parser.parse_record(&record(&["2024", "3"])).unwrap_err();
+        assert!(matches!(
+            error,
+            ParseError::MissingDatePart { part: "day", .. }
+        ));
+        let error = parser
+            .parse_record(&record(&["2024", "3", "x"]))
+            .unwrap_err();
+        assert_eq!(
+            PipelineError::Parse(error).status(),
+            RowStatus::DateParseError
+        );
+    }
+
+    #[test]
+    fn inverted_rows_are_counted() {
+        let data = "Date,Type,Amount\n2024-03-15,Card,1\n2024-03-15,Fee,2\n2024-03-16,Card,3\n";
+        let mut reader = Reader::from_reader(data.as_bytes());
+        let headers = headers();
+        let options = crate::filter::FilterOptions::default();
+        let filter = FilterSet::new(&["Type=Fee".to_string()], &[], Some(&headers), &options)
+            .unwrap()
+            .inverted(true);
+        let stats = RunStats::default();
+        let rows: Vec<_> = read_records(
+            &mut reader,
+            None,
+            None,
+            &filter,
+            FilterMode::Exclude,
+            &stats,
+        )
+        .map(|(r, _)| r.get(2).unwrap().to_string())
+        .collect();
+        assert_eq!(rows, ["1", "3"]);
+        assert_eq!(stats.rows_read.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.rows_inverted.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.rows_filtered.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.rows_matched.load(Ordering::Relaxed), 2);
+    }
+
+    fn dedupe(data: &str, columns: &[usize], keep: DedupeKeep) -> (Vec<String>, usize) {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(data.as_bytes());
+        let records: Vec<_> = reader.records().map(|r| (r.unwrap(), true)).collect();
+        let stats = RunStats::default();
+        let kept = dedupe_records(records, columns, keep, &stats)
+            .into_iter()
+            .map(|(r, _)| r.iter().collect::<Vec<_>>().join(","))
+            .collect();
+        (kept, stats.rows_duplicate.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn dedupes_by_index_keys() {
+        let data =
+            "2024-03-15,A,1\n2024-03-15,B,2\n2024-03-15,A,3\n2024-03-16,A,4\n2024-03-15,B,5\n";
+        let cases: [(&[usize], DedupeKeep, &[&str], usize); 6] = [
+            (
+                &[0, 1],
+                DedupeKeep::First,
+                &["2024-03-15,A,1", "2024-03-15,B,2", "2024-03-16,A,4"],
+                2,
+            ),
+            (
+                &[0, 1],
+                DedupeKeep::Last,
+                &["2024-03-15,A,3", "2024-03-16,A,4", "2024-03-15,B,5"],
+                2,
+            ),
+            (
+                &[0],
+                DedupeKeep::First,
+                &["2024-03-15,A,1", "2024-03-16,A,4"],
+                3,
+            ),
+            (
+                &[0],
+                DedupeKeep::Last,
+                &["2024-03-16,A,4", "2024-03-15,B,5"],
+                3,
+            ),
+            (
+                &[1],
+                DedupeKeep::First,
+                &["2024-03-15,A,1", "2024-03-15,B,2"],
+                3,
+            ),
+            (
+                &[2],
+                DedupeKeep::First,
+                &[
+                    "2024-03-15,A,1",
+                    "2024-03-15,B,2",
+                    "2024-03-15,A,3",
+                    "2024-03-16,A,4",
+                    "2024-03-15,B,5",
+                ],
+                0,
+            ),
+        ];
+        for (columns, keep, expected, removed) in cases {
+            assert_eq!(
+                dedupe(data, columns, keep),
+                (expected.iter().map(|r| r.to_string()).collect(), removed),
+                "{:?} {:?}",
+                columns,
+                keep
+            );
+        }
+    }
+
+    #[test]
+    fn dedupe_keys_of_short_rows() {
+        // A missing key cell is the same as an empty one.
+        let data = "2024-03-15,A\n2024-03-15\n2024-03-15,\n2024-03-15,A\n";
+        let reader = || {
+            csv::ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .from_reader(data.as_bytes())
+        };
+        let records: Vec<_> = reader()
+            .into_records()
+            .map(|r| (r.unwrap(), true))
+            .collect();
+        let stats = RunStats::default();
+        let kept = dedupe_records(records, &[0, 1], DedupeKeep::First, &stats);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[1].0.len(), 1);
+        assert_eq!(stats.rows_duplicate.load(Ordering::Relaxed), 2);
+    }
+
+    /// Source of the clients of the tests, with a USD rate of 17 plus the day of the month in
+    /// hundredths, and no rates on Sundays. The tests run concurrently and share its requests,
+    /// so each of them uses dates of its own, the requests in flight being those of March 2024.
+    struct FakeRates {
+        dates: std::sync::Mutex<Vec<NaiveDate>>,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    static FAKE_RATES: FakeRates = FakeRates {
+        dates: std::sync::Mutex::new(Vec::new()),
+        in_flight: AtomicUsize::new(0),
+        max_in_flight: AtomicUsize::new(0),
+    };
+
+    impl crate::rates::RateSource for &'static FakeRates {
+        fn table<'a>(
+            &'a self,
+            date: &'a NaiveDate,
+        ) -> futures::future::BoxFuture<'a, Result<crate::rates::RateTable, FetchError>> {
+            Box::pin(async move {
+                self.dates.lock().unwrap().push(*date);
+                let tracked = date.year() == 2024 && date.month() == 3;
+                if tracked {
+                    let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                if tracked {
+                    self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                }
+                let mut table = HashMap::new();
+                if date.weekday() != chrono::Weekday::Sun {
+                    let rate = crate::rates::OfficialRate {
+                        nominal: Decimal::ONE,
+                        rate: Decimal::from(17) + Decimal::new(date.day().into(), 2),
+                    };
+                    table.insert("USD".to_string(), rate);
+                }
+                Ok(table)
+            })
+        }
+    }
+
+    fn fake_client() -> BnmClient {
+        BnmClient::builder()
+            .source(&FAKE_RATES)
+            .concurrency(2)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn monthly_average_requests_a_few_days_at_once() {
+        let client = fake_client();
+        // 2024-03-03 and 2024-03-10 are Sundays.
+        let average = fetch_monthly_average(
+            &client,
+            date(2024, 3, 1),
+            "USD",
+            &resolver(date(2024, 3, 10)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(average, Decimal::new(170525, 4));
+        let max_in_flight = FAKE_RATES.max_in_flight.load(Ordering::SeqCst);
+        assert_eq!(max_in_flight, 2);
+        for day in 1..=10 {
+            assert_eq!(requests_of(date(2024, 3, day)), 1, "2024-03-{:02}", day);
+        }
+    }
+
+    /// Number of requests made for the date.
+    fn requests_of(date: NaiveDate) -> usize {
+        let dates = FAKE_RATES.dates.lock().unwrap();
+        dates.iter().filter(|d| **d == date).count()
+    }
+
+    #[tokio::test]
+    async fn amount_columns_share_the_rate_of_the_row() {
+        let client = fake_client();
+        let layout = OutLayout {
+            columns: [
+                OutValue::Exchange,
+                OutValue::Converted(0),
+                OutValue::Converted(1),
+                OutValue::Converted(2),
+            ]
+            .into_iter()
+            .map(|value| OutColumn {
+                after: None,
+                name: value.option().to_string(),
+                value,
+            })
+            .collect(),
+        };
+        let amounts = AmountColumns {
+            columns: vec![1, 2, 3],
+            format: AmountFormat::default(),
+            direction: ConvertDirection::ToMdl,
+            precision: None,
+            on_invalid: OnInvalidAmount::Error,
+        };
+        let config = PipelineConfig::builder(parser("%Y-%m-%d"))
+            .layout(layout)
+            .amounts(Some(amounts))
+            .build();
+        let stats = RunStats::default();
+        let ctx = RowContext {
+            config: &config,
+            stats: &stats,
+            client: &client,
+        };
+        let rows = [
+            ("2023-06-05", "100", "-3.20", "96.80"),
+            ("2023-06-05", "10", "-0.50", "9.50"),
+            ("2023-06-06", "20", "-0.60", "19.40"),
+            ("2023-06-05", "1", "0", "1"),
+            ("2023-06-07", "5", "-0.30", "4.70"),
+        ];
+        let records = rows
+            .iter()
+            .map(|(date, gross, fee, net)| (record(&[date, gross, fee, net]), true));
+        let processed: Vec<_> = process_records(records, &ctx).collect().await;
+        let converted: Vec<Vec<String>> = processed
+            .into_iter()
+            .map(|row| {
+                let out = row.unwrap().output.unwrap().unwrap();
+                out.record.iter().skip(4).map(str::to_string).collect()
+            })
+            .collect();
+        assert_eq!(converted[0], ["17.05", "1705", "-54.56", "1650.44"]);
+        assert_eq!(converted[2], ["17.06", "341.2", "-10.236", "330.964"]);
+        for day in [5, 6, 7] {
+            assert_eq!(requests_of(date(2023, 6, day)), 1, "2023-06-{:02}", day);
+        }
+        assert_eq!(stats.amounts_converted.load(Ordering::Relaxed), 15);
+    }
+
+    #[test]
+    fn edit_distances() {
+        let cases = [
+            ("", "", 0),
+            ("", "abc", 3),
+            ("amount", "amount", 0),
+            ("ammount", "amount", 1),
+            ("amuont", "amount", 2),
+            ("date", "data", 1),
+            ("suma", "sumă", 1),
+            ("kitten", "sitting", 3),
+        ];
+        for (a, b, distance) in cases {
+            assert_eq!(edit_distance(a, b), distance, "{} {}", a, b);
+            assert_eq!(edit_distance(b, a), distance, "{} {}", b, a);
+        }
+    }
+
+    #[test]
+    fn suggestion_ranking() {
+        let headers = record(&[
+            "Date",
+            "Description",
+            "Amount",
+            "Amount MDL",
+            "Balance",
+            "Data",
+        ]);
+        let cases = [
+            ("Ammount", Some("Amount")),
+            ("amount", Some("Amount")),
+            (" AMOUNT ", Some("Amount")),
+            ("Amount MLD", Some("Amount MDL")),
+            // The closest one wins, the first of them on a tie.
+            ("Dat", Some("Date")),
+            ("Datq", Some("Date")),
+            ("Dta", Some("Data")),
+            ("Balanse", Some("Balance")),
+            ("Descripton", Some("Description")),
+            // Names differing by more than a third of their characters.
+            ("Sum", None),
+            ("Total", None),
+            ("Amnt", None),
+        ];
+        for (column, expected) in cases {
+            assert_eq!(suggest_column(&headers, column), expected, "{}", column);
+        }
+    }
+
+    #[test]
+    fn unknown_columns_list_the_headers() {
+        let error = get_column_index(Some(&headers()), "Ammount").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Cannot find column \"Ammount\" in headers, did you mean \"Amount\"? \
+             Available columns: Date, Type, Amount"
+        );
+        let error = get_column_index(Some(&headers()), "Total").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Cannot find column \"Total\" in headers. Available columns: Date, Type, Amount"
+        );
+        let many = (1..=25)
+            .map(|i| format!("Column {}", i))
+            .collect::<Vec<_>>();
+        let error = get_column_index(Some(&StringRecord::from(many)), "Note").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .ends_with("Column 19, Column 20 and 5 more"),
+            "{}",
+            error
+        );
+        assert_eq!(get_column_index(None, "2").unwrap(), 2);
+        assert!(matches!(
+            get_column_index(None, "Amount"),
+            Err(ParseError::ColumnIndex { .. })
+        ));
+    }
+
+    #[test]
+    fn failures_by_category() {
+        let stats = RunStats::default();
+        assert_eq!(stats.describe_failures(), "");
+        for status in [
+            RowStatus::NetworkError,
+            RowStatus::DateParseError,
+            RowStatus::RateMissing,
+            RowStatus::DateParseError,
+            RowStatus::NetworkError,
+            RowStatus::RateMissing,
+            RowStatus::DateParseError,
+        ] {
+            stats.count_failure(status);
+        }
+        // The most frequent first, the ties in the order of the statuses.
+        assert_eq!(
+            stats.failures(),
+            vec![
+                (RowStatus::DateParseError, 3),
+                (RowStatus::RateMissing, 2),
+                (RowStatus::NetworkError, 2),
+            ]
+        );
+        assert_eq!(
+            stats.describe_failures(),
+            "date-parse-error: 3, rate-missing: 2, network-error: 2"
+        );
+    }
+
+    #[test]
+    fn stats_lines() {
+        let stats = RunStats::default();
+        stats.rows_read.store(12, Ordering::Relaxed);
+        stats.count_failure(RowStatus::RateMissing);
+        let fetch = FetchStats::default();
+        let text = stats.format(&fetch, StatsFormat::Text, 3, false);
+        assert!(text.starts_with("rows_read=12 unique_dates=3 "), "{}", text);
+        assert!(text.contains(" rows_failed_rate_missing=1 "), "{}", text);
+        assert!(
+            !text.contains('\n') && !text.contains("_time_ms"),
+            "{}",
+            text
+        );
+        let json = stats.format(&fetch, StatsFormat::Json, 3, true);
+        assert!(
+            json.starts_with("{\"rows_read\":12,\"unique_dates\":3,"),
+            "{}",
+            json
+        );
+        assert!(json.contains(",\"read_time_ms\":"), "{}", json);
+        assert!(json.ends_with('}'), "{}", json);
+    }
+
+    fn layout(columns: &[(&str, OutValue)]) -> OutLayout {
+        OutLayout {
+            columns: columns
+                .iter()
+                .map(|(name, value)| OutColumn {
+                    after: None,
+                    name: name.to_string(),
+                    value: *value,
+                })
+                .collect(),
+        }
+    }
+
+    fn duplicates(headers: &StringRecord, layout: &OutLayout) -> Vec<(String, Vec<String>)> {
+        let out_headers = get_out_headers(headers, layout);
+        match check_out_headers(headers, &out_headers, layout) {
+            Ok(()) => Vec::new(),
+            Err(ParseError::DuplicateColumns { columns }) => columns,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn duplicate_out_headers() {
+        let headers = headers();
+        let exchange = ("Exchange Rate", OutValue::Exchange);
+        assert!(duplicates(&headers, &layout(&[exchange])).is_empty());
+        assert_eq!(
+            duplicates(&headers, &layout(&[("Amount", OutValue::Exchange)])),
+            [(
+                "Amount".to_string(),
+                vec!["the input".to_string(), "--out-exchange-column".to_string()]
+            )]
+        );
+        let layout = layout(&[
+            exchange,
+            ("Date", OutValue::RateDate),
+            ("Exchange Rate", OutValue::Inverse),
+        ]);
+        let error = check_out_headers(&headers, &get_out_headers(&headers, &layout), &layout);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "Duplicate output columns: \"Date\" (from the input, --out-rate-date-column), \
+             \"Exchange Rate\" (from --out-exchange-column, --out-inverse-column), \
+             rename them or use --allow-duplicate-headers"
+        );
+    }
+
+    #[test]
+    fn duplicate_input_headers_are_kept() {
+        let headers = record(&["Date", "Amount", "Amount"]);
+        let layout = layout(&[("Exchange Rate", OutValue::Exchange)]);
+        assert!(duplicates(&headers, &layout).is_empty());
+        let layout = OutLayout {
+            columns: vec![OutColumn {
+                after: Some(1),
+                name: "Amount".to_string(),
+                value: OutValue::Converted(0),
+            }],
+        };
+        assert_eq!(
+            get_out_headers(&headers, &layout),
+            record(&["Date", "Amount", "Amount", "Amount"])
+        );
+        assert_eq!(duplicates(&headers, &layout)[0].1.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn rows_are_streamed() {
+        let client = fake_client();
+        let config = PipelineConfig::builder(parser("%Y-%m-%d"))
+            .concurrency(2)
+            .build();
+        let stats = RunStats::default();
+        let ctx = RowContext {
+            config: &config,
+            stats: &stats,
+            client: &client,
+        };
+        let events = std::sync::Mutex::new(Vec::new());
+        let records = (1..=10).map(|day| {
+            events.lock().unwrap().push(format!("read {}", day));
+            let date = format!("2023-07-{:02}", day);
+            (record(&[date.as_str()]), true)
+        });
+        let mut rows = Box::pin(process_records(records, &ctx));
+        let mut written = Vec::new();
+        while let Some(row) = rows.next().await {
+            let row = row.unwrap();
+            events
+                .lock()
+                .unwrap()
+                .push(format!("write {}", &row.input[0][8..]));
+            written.push(row.input[0].to_string());
+        }
+        drop(rows);
+        // In the input order, each row written before the rows two or more after it are read.
+        let expected: Vec<String> = (1..=10).map(|day| format!("2023-07-{:02}", day)).collect();
+        assert_eq!(written, expected);
+        let events = events.into_inner().unwrap();
+        let position = |event: String| events.iter().position(|e| *e == event).unwrap();
+        for day in 1..=8 {
+            assert!(
+                position(format!("write {:02}", day)) < position(format!("read {}", day + 2)),
+                "{:?}",
+                events
+            );
+        }
+    }
+
+    /// Config adding the rate, the converted amount and the rate change of August 2023 rows.
+    fn august_config(prefetch: Option<usize>) -> PipelineConfig {
+        let layout = OutLayout {
+            columns: [
+                OutValue::Exchange,
+                OutValue::Converted(0),
+                OutValue::RateChange,
+            ]
+            .into_iter()
+            .map(|value| OutColumn {
+                after: None,
+                name: value.option().to_string(),
+                value,
+            })
+            .collect(),
+        };
+        let amounts = AmountColumns {
+            columns: vec![1],
+            format: AmountFormat::default(),
+            direction: ConvertDirection::ToMdl,
+            precision: None,
+            on_invalid: OnInvalidAmount::Blank,
+        };
+        PipelineConfig::builder(parser("%Y-%m-%d"))
+            .layout(layout)
+            .amounts(Some(amounts))
+            .rate_change_precision(Some(2))
+            .rate_dates(resolver(date(2023, 9, 1)))
+            .prefetch(prefetch)
+            .build()
+    }
+
+    /// Output of the rows (or their error) along with a few counters of the run.
+    async fn august_run(
+        config: &PipelineConfig,
+        client: &BnmClient,
+        rows: &[(StringRecord, bool)],
+    ) -> (Vec<String>, [usize; 3]) {
+        let stats = RunStats::default();
+        let ctx = RowContext {
+            config,
+            stats: &stats,
+            client,
+        };
+        let processed: Vec<_> = process_records(rows.iter().cloned(), &ctx).collect().await;
+        let out = processed
+            .into_iter()
+            .map(|row| match row.unwrap().output {
+                Ok(row) => format!("{:?}", row.map(|row| row.record)),
+                Err(e) => e.to_string(),
+            })
+            .collect();
+        let counters = [
+            &stats.amounts_converted,
+            &stats.invalid_amounts,
+            &stats.rate_changes_missing,
+        ]
+        .map(|counter| counter.load(Ordering::Relaxed));
+        (out, counters)
+    }
+
+    #[tokio::test]
+    async fn cached_rows_are_processed_the_same() {
+        let client = fake_client();
+        let rows: Vec<_> = (1..=40)
+            .map(|i| {
+                let date = format!("2023-08-{:02}", i % 31 + 1);
+                let amount = match i % 7 {
+                    0 => "n/a".to_string(),
+                    _ => format!("{}.{}", i * 3, i % 10),
+                };
+                (record(&[date.as_str(), amount.as_str()]), i % 5 != 0)
+            })
+            .collect();
+        // Fetches the rates, so the second run finds them all cached.
+        let slow = august_run(&august_config(None), &client, &rows).await;
+        let config = august_config(Some(16));
+        let stats = RunStats::default();
+        let ctx = RowContext {
+            config: &config,
+            stats: &stats,
+            client: &client,
+        };
+        assert!(rates_cached(&client, &request_dates(&ctx, &rows).0).await);
+        let fast = august_run(&config, &client, &rows).await;
+        assert_eq!(slow, fast);
+        assert!(slow.0.iter().any(|row| row.contains("17.")), "{:?}", slow.0);
+        assert!(slow.1[0] > 0 && slow.1[1] > 0, "{:?}", slow.1);
+    }
+
+    /// Output of a run, kept once its writer is dropped.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Options of a run dropping the failed rows and writing the grand total.
+    fn may_options() -> RunOptions {
+        RunOptions {
+            on_rate_error: OnRateError::Drop,
+            post_filter: FilterSet::default(),
+            totals: true,
+            group_totals: None,
+            max_errors: None,
+            keep_partial: false,
+            report_failures: true,
+            in_files: Vec::new(),
+            interrupt_grace: std::time::Duration::ZERO,
+            fetch_start: Instant::now(),
+        }
+    }
+
+    /// Runs the "Date,Amount" rows of May 2022, adding the rate and the amount in MDL.
+    /// Returns the output, `None` if it wasn't opened.
+    async fn may_run(
+        options: &RunOptions,
+        rows: &[[&str; 2]],
+        interrupt: impl Future<Output = ()>,
+    ) -> (Result<RunSummary, RunError>, Option<String>) {
+        let client = fake_client();
+        let layout = OutLayout {
+            columns: [
+                ("Rate", OutValue::Exchange),
+                ("MDL", OutValue::Converted(0)),
+            ]
+            .into_iter()
+            .map(|(name, value)| OutColumn {
+                after: None,
+                name: name.to_string(),
+                value,
+            })
+            .collect(),
+        };
+        let amounts = AmountColumns {
+            columns: vec![1],
+            format: AmountFormat::default(),
+            direction: ConvertDirection::ToMdl,
+            precision: Some(2),
+            on_invalid: OnInvalidAmount::Error,
+        };
+        let config = PipelineConfig::builder(parser("%Y-%m-%d"))
+            .layout(layout)
+            .amounts(Some(amounts))
+            .build();
+        let stats = RunStats::default();
+        let ctx = RowContext {
+            config: &config,
+            stats: &stats,
+            client: &client,
+        };
+        let headers = record(&["Date", "Amount"]);
+        let records = rows.iter().map(|fields| (record(fields), true));
+        let output = SharedOutput::default();
+        let mut opened = false;
+        let open = || {
+            opened = true;
+            Ok(csv::Writer::from_writer(output.clone()))
+        };
+        let summary = run(&ctx, records, Some(&headers), options, open, interrupt).await;
+        let written = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        (summary, opened.then_some(written))
+    }
+
+    #[tokio::test]
+    async fn run_writes_the_rows_with_their_total() {
+        let rows = [["2022-05-02", "10"], ["oops", "5"], ["2022-05-03", "20"]];
+        let (summary, output) = may_run(&may_options(), &rows, futures::future::pending()).await;
+        let summary = summary.unwrap();
+        assert_eq!(
+            output.unwrap(),
+            "Date,Amount,Rate,MDL\n\
+             2022-05-02,10,17.02,170.20\n\
+             2022-05-03,20,17.03,340.60\n\
+             TOTAL,30,,510.80\n"
+        );
+        assert_eq!(summary.failed_rows.len(), 1);
+        assert_eq!(summary.failed_rows[0].status, RowStatus::DateParseError);
+        assert!(!summary.interrupted && summary.exceeded.is_none());
+    }
+
+    #[tokio::test]
+    async fn run_writes_nothing_once_too_many_rows_failed() {
+        let rows = [["2022-05-04", "10"], ["oops", "5"], ["2022-05-05", "20"]];
+        let options = RunOptions {
+            max_errors: Some(0),
+            ..may_options()
+        };
+        let (summary, output) = may_run(&options, &rows, futures::future::pending()).await;
+        let exceeded = summary.unwrap().exceeded.unwrap();
+        assert_eq!(exceeded.status(), Some(RowStatus::DateParseError));
+        assert_eq!(output, None);
+        // The rows processed until then are written with --keep-partial.
+        let options = RunOptions {
+            keep_partial: true,
+            ..options
+        };
+        let (summary, output) = may_run(&options, &rows, futures::future::pending()).await;
+        assert!(summary.unwrap().exceeded.is_some());
+        assert_eq!(
+            output.unwrap(),
+            "Date,Amount,Rate,MDL\n2022-05-04,10,17.04,170.40\nTOTAL,10,,170.40\n"
+        );
+        let options = RunOptions {
+            on_rate_error: OnRateError::Fail,
+            ..may_options()
+        };
+        let (summary, output) = may_run(&options, &rows, futures::future::pending()).await;
+        let error = summary.err().unwrap();
+        assert!(matches!(error, RunError::Row { .. }), "{}", error);
+        assert_eq!(output, None);
+    }
+
+    #[tokio::test]
+    async fn interrupted_runs_write_the_rows_processed() {
+        let rows = [["2022-05-06", "10"], ["2022-05-09", "20"]];
+        // The rates take a while, so none of them is fetched within the grace period.
+        let (summary, output) = may_run(&may_options(), &rows, futures::future::ready(())).await;
+        assert!(summary.unwrap().interrupted);
+        assert_eq!(output.unwrap(), "Date,Amount,Rate,MDL\nTOTAL,0,,0.00\n");
+    }
+
+    /// Output record as it was assembled before fields were copied into one buffer:
+    /// a `String` for each field, the added ones inserted, then joined.
+    fn assemble_owned(
+        layout: &OutLayout,
+        fields: &[&str],
+        value: fn(&OutColumn) -> String,
+    ) -> StringRecord {
+        let mut record: Vec<String> = Vec::new();
+        for (i, field) in fields.iter().enumerate() {
+            record.push(field.to_string());
+            for column in layout.columns.iter().filter(|c| c.after == Some(i)) {
+                record.push(value(column));
+            }
+        }
+        for column in layout
+            .columns
+            .iter()
+            .filter(|c| c.after.map(|i| i >= fields.len()).unwrap_or(true))
+        {
+            record.push(value(column));
+        }
+        StringRecord::from(record)
+    }
+
+    fn written(records: &[StringRecord]) -> Vec<u8> {
+        let mut writer = csv::WriterBuilder::new()
+            .flexible(true)
+            .from_writer(Vec::new());
+        for record in records {
+            writer.write_record(record).unwrap();
+        }
+        writer.into_inner().unwrap()
+    }
+
+    fn added_value(column: &OutColumn) -> String {
+        format!("{};\"{}\"", column.name, column.after.unwrap_or_default())
+    }
+
+    #[test]
+    fn assembled_records_are_byte_identical() {
+        let column = |value: OutValue, after| OutColumn {
+            after,
+            name: value.option().to_string(),
+            value,
+        };
+        let layouts = [
+            vec![],
+            vec![column(OutValue::Exchange, None)],
+            vec![
+                column(OutValue::Exchange, Some(0)),
+                column(OutValue::Status, None),
+            ],
+            vec![
+                column(OutValue::Exchange, Some(1)),
+                column(OutValue::Inverse, Some(1)),
+                column(OutValue::Status, Some(9)),
+            ],
+        ];
+        let rows: [&[&str]; 6] = [
+            &["coffee", "2024-03-14", "-3.50"],
+            &["\"quoted\", with a comma", "2024-03-14", ""],
+            &["two\nlines", "", "1,5"],
+            &["short"],
+            &[],
+            &["", "", "", "", ""],
+        ];
+        for layout in layouts {
+            let layout = OutLayout { columns: layout };
+            let (assembled, owned): (Vec<_>, Vec<_>) = rows
+                .iter()
+                .map(|row| {
+                    (
+                        layout.assemble(row.iter().copied(), added_value),
+                        assemble_owned(&layout, row, added_value),
+                    )
+                })
+                .unzip();
+            assert_eq!(assembled, owned);
+            assert_eq!(written(&assembled), written(&owned));
+        }
+    }
+
+    /// Rows of a bank statement, as generated by the "bench" command.
+    fn statement_rows(count: usize) -> Vec<StringRecord> {
+        (0..count)
+            .map(|i| {
+                let date = format!("2024-03-{:02}", i % 28 + 1);
+                let amount = format!("{}.{:02}", i % 1000, i % 100);
+                record(&["card payment", &date, &amount, "MDL", "groceries", "ref"])
+            })
+            .collect()
+    }
+
+    fn exchange_after_date() -> OutLayout {
+        OutLayout {
+            columns: vec![OutColumn {
+                after: Some(1),
+                name: "Exchange".to_string(),
+                value: OutValue::Exchange,
+            }],
+        }
+    }
+
+    #[test]
+    fn assembled_statement_rows_are_byte_identical() {
+        let layout = exchange_after_date();
+        let rows = statement_rows(1000);
+        let assembled: Vec<_> = rows
+            .iter()
+            .map(|row| layout.assemble(row, added_value))
+            .collect();
+        let owned: Vec<_> = rows
+            .iter()
+            .map(|row| assemble_owned(&layout, &row.iter().collect::<Vec<_>>(), added_value))
+            .collect();
+        assert_eq!(written(&assembled), written(&owned));
+    }
+
+    // The two ways of assembling the rows can be timed against each other with
+    // `cargo test --release -- --ignored --exact <test>`.
+    #[test]
+    #[ignore]
+    fn assembling_500k_rows() {
+        let layout = exchange_after_date();
+        let rows = statement_rows(500_000);
+        let assembled = rows.iter().map(|row| layout.assemble(row, added_value));
+        assert_eq!(assembled.count(), rows.len());
+    }
+
+    #[test]
+    #[ignore]
+    fn assembling_500k_rows_by_field() {
+        let layout = exchange_after_date();
+        let rows = statement_rows(500_000);
+        let owned = rows
+            .iter()
+            .map(|row| assemble_owned(&layout, &row.iter().collect::<Vec<_>>(), added_value));
+        assert_eq!(owned.count(), rows.len());
+    }
+
+    #[test]
+    fn appended_rate_is_quoted_as_a_pass_through() {
+        let layout = OutLayout {
+            columns: vec![OutColumn {
+                after: None,
+                name: "Exchange".to_string(),
+                value: OutValue::Exchange,
+            }],
+        };
+        let rows: [&[&str]; 7] = [
+            &["coffee", "2024-03-14", "3.50"],
+            &["a, b; c\td", "2024-03-14", "1,5"],
+            &["\"quoted\"", "say \"hi\"", "\""],
+            &["two\nlines", "cr\r\nlf", ""],
+            &["", "", ""],
+            &[" padded ", "'single'", "#hash"],
+            &["short"],
+        ];
+        for delimiter in [b',', b';', b'\t'] {
+            let builder = || {
+                let mut builder = csv::WriterBuilder::new();
+                builder.delimiter(delimiter).flexible(true);
+                builder
+            };
+            let mut assembled = builder().from_writer(Vec::new());
+            let mut passed = builder().from_writer(Vec::new());
+            for row in rows {
+                let record = record(row);
+                let rate = match delimiter {
+                    b',' => "17,7",
+                    _ => "17.7",
+                };
+                assembled
+                    .write_record(&layout.assemble(&record, |_| rate.to_string()))
+                    .unwrap();
+                // The original record followed by the rate, as written without assembling.
+                for field in record.iter() {
+                    passed.write_field(field).unwrap();
+                }
+                passed.write_field(rate).unwrap();
+                passed.write_record(None::<&[u8]>).unwrap();
+            }
+            let assembled = assembled.into_inner().unwrap();
+            let passed = passed.into_inner().unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&assembled),
+                String::from_utf8_lossy(&passed),
+                "delimiter {}",
+                delimiter as char
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rows_completing_out_of_order_are_yielded_in_order() {
+        let client = fake_client();
+        // Rows after the first one find their rates cached, so they complete first.
+        for day in 2..=10 {
+            client
+                .published_rate(date(2023, 11, day), "USD")
+                .await
+                .unwrap();
+        }
+        let config = PipelineConfig::builder(parser("%Y-%m-%d"))
+            .concurrency(4)
+            .build();
+        let stats = RunStats::default();
+        let ctx = RowContext {
+            config: &config,
+            stats: &stats,
+            client: &client,
+        };
+        let dates: Vec<String> = (1..=10).map(|day| format!("2023-11-{:02}", day)).collect();
+        let records = dates.iter().map(|d| (record(&[d.as_str()]), true));
+        let processed: Vec<_> = process_records(records, &ctx).collect().await;
+        let yielded: Vec<String> = processed
+            .into_iter()
+            .map(|row| row.unwrap().input[0].to_string())
+            .collect();
+        assert_eq!(yielded, dates);
+    }
+
+    #[tokio::test]
+    async fn rows_arent_read_ahead_of_the_consumer() {
+        let client = fake_client();
+        let config = PipelineConfig::builder(parser("%Y-%m-%d"))
+            .concurrency(3)
+            .build();
+        let stats = RunStats::default();
+        let ctx = RowContext {
+            config: &config,
+            stats: &stats,
+            client: &client,
+        };
+        let read = AtomicUsize::new(0);
+        let records = (1..=20).map(|day| {
+            read.fetch_add(1, Ordering::SeqCst);
+            (record(&[format!("2023-12-{:02}", day).as_str()]), true)
+        });
+        let mut rows = Box::pin(process_records(records, &ctx));
+        rows.next().await.unwrap().unwrap();
+        // Without the consumer taking more rows, none is read while time passes.
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(
+            read.load(Ordering::SeqCst) <= 3,
+            "{}",
+            read.load(Ordering::SeqCst)
+        );
+        rows.next().await.unwrap().unwrap();
+        assert!(
+            read.load(Ordering::SeqCst) <= 4,
+            "{}",
+            read.load(Ordering::SeqCst)
+        );
+        assert_eq!(rows.count().await, 18);
+        assert_eq!(read.load(Ordering::SeqCst), 20);
+    }
+
+    #[tokio::test]
+    async fn prefetched_chunks_are_read_one_at_a_time() {
+        let client = fake_client();
+        let config = PipelineConfig::builder(parser("%Y-%m-%d"))
+            .concurrency(2)
+            .prefetch(Some(5))
+            .build();
+        let stats = RunStats::default();
+        let ctx = RowContext {
+            config: &config,
+            stats: &stats,
+            client: &client,
+        };
+        let read = AtomicUsize::new(0);
+        let dates: Vec<String> = (1..=20).map(|day| format!("2023-10-{:02}", day)).collect();
+        let records = dates.iter().map(|d| {
+            read.fetch_add(1, Ordering::SeqCst);
+            (record(&[d.as_str()]), true)
+        });
+        let mut rows = Box::pin(process_records(records, &ctx));
+        let mut yielded = Vec::new();
+        for chunk in 0..4 {
+            for _ in 0..5 {
+                let row = rows.next().await.unwrap().unwrap();
+                yielded.push(row.input[0].to_string());
+                // The rows of the chunk, and no more, were read before the first of them.
+                assert_eq!(read.load(Ordering::SeqCst), (chunk + 1) * 5);
+            }
+        }
+        assert!(rows.next().await.is_none());
+        assert_eq!(yielded, dates);
+        for day in 1..=20 {
+            assert_eq!(requests_of(date(2023, 10, day)), 1, "2023-10-{:02}", day);
+        }
+    }
+
+    #[test]
+    fn pipeline_defaults() {
+        let config = PipelineConfig::builder(parser("%Y-%m-%d")).build();
+        assert!(config.date_range.from.is_none() && config.date_range.to.is_none());
+        assert_eq!(config.rate_dates.offset, 0);
+        assert!(config.rate_dates.calendar.is_none());
+        assert_eq!(config.rate_dates.on_future_date, OnFutureDate::WarnSkip);
+        assert_eq!(config.rate_dates.min_date.to_string(), DEFAULT_MIN_DATE);
+        assert_eq!(config.out_dates.format, None);
+        assert_eq!(config.out_dates.locale, DateLocale::En);
+        let columns: Vec<_> = config
+            .layout
+            .columns
+            .iter()
+            .map(|c| (c.after, c.name.as_str(), c.value.option()))
+            .collect();
+        assert_eq!(
+            columns,
+            [(None, DEFAULT_EXCHANGE_COLUMN, OutValue::Exchange.option())]
+        );
+        assert!(config.amounts.is_none());
+        assert_eq!(config.currency, CURRENCY);
+        assert_eq!(config.rate_precision, None);
+        assert_eq!(config.inverse_precision, None);
+        assert_eq!(config.rate_change_precision, None);
+        assert_eq!(config.reference_rate, None);
+        assert_eq!(config.rate_mode, RateMode::Daily);
+        assert_eq!(config.rounding, Rounding::HalfUp);
+        assert_eq!(config.concurrency, 32);
+        assert_eq!(config.prefetch, None);
+    }
+}
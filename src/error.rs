@@ -0,0 +1,469 @@
+//! Errors of the library, carrying the context of the failure (e.g. the date and the currency
+//! of a rate) as fields, so that callers can tell a rate BNM didn't publish from a network
+//! error. The errors a variant wraps are part of its message rather than its `source()`,
+//! the same as in the command's messages.
+use std::path::PathBuf;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use chrono_tz::Tz;
+use reqwest::StatusCode;
+use thiserror::Error;
+
+use crate::csvproc::{RowStatus, LISTED_COLUMNS};
+
+/// A failed rate lookup.
+#[derive(Debug, Error)]
+pub enum FetchError {
+    /// BNM didn't publish the rate of the currency for the date.
+    #[error("BNM has no {currency} rate for {date}")]
+    NotPublished { date: NaiveDate, currency: String },
+    /// BNM published no rate of the currency in the month (first day of the month), when
+    /// averaging them.
+    #[error("No rates published in {}", .month.format("%Y-%m"))]
+    NoneInMonth { month: NaiveDate, currency: String },
+    #[error("Got unexpected status - {status}")]
+    Status { date: NaiveDate, status: StatusCode },
+    /// The request failed or timed out, including its retries.
+    #[error("{error}")]
+    Network {
+        date: NaiveDate,
+        error: reqwest::Error,
+    },
+    /// The response isn't a rate table, e.g. it's truncated.
+    #[error("{error}")]
+    InvalidResponse { date: NaiveDate, error: ParseError },
+    /// The HTTP client of a [`BnmClient`](crate::rates::BnmClient) can't be initialized.
+    #[error("Failed to create the HTTP client - {0}")]
+    Client(reqwest::Error),
+    /// An error of a custom [`RateSource`](crate::rates::RateSource).
+    #[error("{error}")]
+    Source {
+        date: NaiveDate,
+        error: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+/// An invalid value, file or configuration, e.g. a date cell or a filter expression.
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error(
+        "Cannot find column \"{column}\" in headers{} Available columns: {}",
+        describe_suggestion(.suggestion),
+        describe_headers(.headers)
+    )]
+    UnknownColumn {
+        column: String,
+        /// The header closest to the column name, if any.
+        suggestion: Option<String>,
+        headers: Vec<String>,
+    },
+    #[error("Failed to parse column index - {column}")]
+    ColumnIndex { column: String },
+    #[error("The date parts must be a year,month,day triple - {parts}")]
+    DateParts { parts: String },
+    #[error("The date column is not provided")]
+    NoDateColumn,
+    #[error(
+        "Duplicate output columns: {}, rename them or use --allow-duplicate-headers",
+        describe_duplicates(.columns)
+    )]
+    DuplicateColumns {
+        /// Each duplicate name, along with the input or the options its columns come from.
+        columns: Vec<(String, Vec<String>)>,
+    },
+    /// None of the date columns of the row has a valid date, with the errors of the
+    /// non-empty ones.
+    #[error("{}", describe_date_errors(.errors))]
+    DateColumns { errors: Vec<ParseError> },
+    #[error("\"{value}\" - {error}")]
+    DateCell {
+        column: usize,
+        value: String,
+        error: Box<ParseError>,
+    },
+    /// The row has fewer fields than the index of the date column.
+    #[error("column {column} is missing, the row has {fields} fields")]
+    MissingColumn { column: usize, fields: usize },
+    #[error("Short row, the {part} column {column} is missing as the row has {fields} fields")]
+    MissingDatePart {
+        part: &'static str,
+        column: usize,
+        fields: usize,
+    },
+    #[error("Invalid {part} cell - \"{value}\"")]
+    DatePart { part: &'static str, value: String },
+    #[error("Invalid month cell - {month}")]
+    Month { month: u32 },
+    #[error("Invalid day cell - {day} (for {year}-{month:02})")]
+    Day { year: i32, month: u32, day: u32 },
+    #[error("Date regex doesn't match \"{value}\"")]
+    DateRegex { value: String },
+    #[error(transparent)]
+    Date(#[from] chrono::ParseError),
+    /// The local time is skipped when the clock goes forward.
+    #[error("Time {time} doesn't exist in {}", .timezone.name())]
+    NonexistentTime { time: NaiveDateTime, timezone: Tz },
+    #[error("Date {} doesn't exist in {year}", .date.format("%m-%d"))]
+    PivotDate { date: NaiveDate, year: i32 },
+    #[error("Week {week} doesn't exist in ISO year {year}")]
+    PivotWeek { week: u32, year: i32 },
+    #[error("Invalid Excel serial date - {value}")]
+    ExcelSerial { value: String },
+    #[error("Excel serial date is out of range - {value}")]
+    ExcelSerialRange { value: String },
+    /// Serial 60, which Excel reads as the non-existent 1900-02-29.
+    #[error("Excel serial date {value} is 1900-02-29, which doesn't exist")]
+    ExcelLeapDay { value: String },
+    #[error("Invalid output date format - {format}")]
+    DateFormat { format: String },
+    #[error("The filter must be k=v pair")]
+    FilterSyntax { filter: String },
+    #[error(
+        "{error} (split into column \"{column}\", operator \"{operator}\" and value \"{value}\")"
+    )]
+    FilterExpr {
+        column: String,
+        operator: &'static str,
+        value: String,
+        error: Box<ParseError>,
+    },
+    /// An invalid expression of a list of filters, `index` starting from 1.
+    #[error("Invalid {option} #{index} \"{filter}\" - {error}")]
+    Filter {
+        option: String,
+        index: usize,
+        filter: String,
+        error: Box<ParseError>,
+    },
+    #[error(
+        "Each --filter-column must have a --filter-regex, got {columns} columns and {regexes} regexes"
+    )]
+    FilterRegexes { columns: usize, regexes: usize },
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    #[error("Dates can't be compared with @=")]
+    DateSet,
+    #[error("Invalid number in {operator} comparison - {value}")]
+    FilterNumber {
+        operator: &'static str,
+        value: String,
+    },
+    #[error("Date comparisons aren't supported here")]
+    DatesUnsupported,
+    #[error("Invalid ISO date in {operator} comparison - {value} ({error})")]
+    FilterDate {
+        operator: &'static str,
+        value: String,
+        error: chrono::ParseError,
+    },
+    #[error("Failed to read filter values from {} - {error}", .path.display())]
+    FilterFile {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[error("{}:{line}: invalid regex - {error}", .path.display())]
+    FilterFileRegex {
+        path: PathBuf,
+        line: usize,
+        error: regex::Error,
+    },
+    #[error("Failed to read holidays file {} - {error}", .path.display())]
+    HolidaysFile {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[error("Failed to read rates file {} - {error}", .path.display())]
+    RatesFile { path: PathBuf, error: csv::Error },
+    /// An invalid date line of a holidays or rates file.
+    #[error("{}:{line}: invalid date \"{value}\" - {error}", .path.display())]
+    FileDate {
+        path: PathBuf,
+        line: usize,
+        value: String,
+        error: chrono::ParseError,
+    },
+    #[error("{}:{line}: invalid row - {error}", .path.display())]
+    RatesRow {
+        path: PathBuf,
+        line: usize,
+        error: csv::Error,
+    },
+    #[error("{}:{line}: invalid {currency} rate \"{value}\" - {error}", .path.display())]
+    RatesValue {
+        path: PathBuf,
+        line: usize,
+        currency: String,
+        value: String,
+        error: rust_decimal::Error,
+    },
+    /// BNM's response has neither rates nor the header of an export (e.g. an error page),
+    /// the line is its first one.
+    #[error("Unexpected line {line} \"{text}\"")]
+    TableLine { line: usize, text: String },
+    #[error("Invalid {currency} rate at line {line} \"{text}\" - {error}")]
+    TableRate {
+        currency: String,
+        line: usize,
+        text: String,
+        error: rust_decimal::Error,
+    },
+    #[error("Invalid {currency} nominal at line {line} \"{text}\"")]
+    TableNominal {
+        currency: String,
+        line: usize,
+        text: String,
+    },
+}
+
+impl ParseError {
+    /// Whether the date can't be read as the row is too short, e.g. a wrong delimiter.
+    pub fn is_short_row(&self) -> bool {
+        match self {
+            ParseError::MissingColumn { .. } | ParseError::MissingDatePart { .. } => true,
+            ParseError::DateColumns { errors } => errors.iter().any(ParseError::is_short_row),
+            _ => false,
+        }
+    }
+}
+
+/// A row which can't be processed, or an error aborting the whole run
+/// (see [`PipelineError::is_fatal`]).
+#[derive(Debug, Error)]
+pub enum PipelineError {
+    #[error(transparent)]
+    Fetch(#[from] FetchError),
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error("Empty date cell")]
+    EmptyDate,
+    /// The rate date of the transaction date is after today, `fatal` with
+    /// [`OnFutureDate::Error`](crate::csvproc::OnFutureDate::Error).
+    #[error("Rate date {rate_date} is in the future")]
+    FutureDate {
+        date: NaiveDate,
+        rate_date: NaiveDate,
+        fatal: bool,
+    },
+    #[error("Rate date {rate_date} predates available data (earliest is {min_date})")]
+    BeforeMinDate {
+        date: NaiveDate,
+        rate_date: NaiveDate,
+        min_date: NaiveDate,
+    },
+    #[error("Date offset {offset} is out of range for {date}")]
+    OffsetOutOfRange { date: NaiveDate, offset: i32 },
+    #[error("No business day found within {days} days before {date}")]
+    NoBusinessDay { date: NaiveDate, days: usize },
+    #[error("No month before {date}")]
+    NoPreviousMonth { date: NaiveDate },
+    /// A cell of the amount column (the n-th one) which isn't an amount.
+    #[error("Invalid amount \"{value}\"")]
+    InvalidAmount { column: usize, value: String },
+    #[error("Can't convert \"{value}\" - {error}")]
+    Conversion {
+        column: usize,
+        value: String,
+        error: Box<PipelineError>,
+    },
+    #[error("the exchange rate is zero")]
+    ZeroRate,
+    #[error("the converted amount overflows")]
+    Overflow,
+    #[error("Can't invert a zero exchange rate")]
+    ZeroInverse { rate_date: NaiveDate },
+    #[error("The rate of {date} is zero")]
+    ZeroPreviousRate { date: NaiveDate },
+}
+
+impl PipelineError {
+    /// Status written for a row which failed with the error.
+    pub fn status(&self) -> RowStatus {
+        match self {
+            PipelineError::Fetch(FetchError::NotPublished { .. })
+            | PipelineError::Fetch(FetchError::NoneInMonth { .. }) => RowStatus::RateMissing,
+            PipelineError::Fetch(_) => RowStatus::NetworkError,
+            PipelineError::Parse(ParseError::DateFormat { .. }) => RowStatus::Error,
+            PipelineError::Parse(e) if e.is_short_row() => RowStatus::RaggedRow,
+            PipelineError::Parse(_) => RowStatus::DateParseError,
+            PipelineError::EmptyDate => RowStatus::EmptyDate,
+            PipelineError::FutureDate { fatal: false, .. } => RowStatus::FutureDate,
+            PipelineError::BeforeMinDate { .. }
+            | PipelineError::OffsetOutOfRange { .. }
+            | PipelineError::NoBusinessDay { .. } => RowStatus::RateMissing,
+            PipelineError::InvalidAmount { .. } => RowStatus::AmountParseError,
+            _ => RowStatus::Error,
+        }
+    }
+
+    /// Whether the error aborts the run, rather than failing the row.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, PipelineError::FutureDate { fatal: true, .. })
+    }
+}
+
+/// An error ending a run of [`run`](crate::csvproc::run).
+#[derive(Debug, Error)]
+pub enum RunError {
+    /// A row failed with [`OnRateError::Fail`](crate::csvproc::OnRateError::Fail), the row
+    /// being its label (e.g. "line 3") and the date the content of its date cells.
+    #[error("Failed to add exchange rate to the row at {row} (date \"{date}\") - {error}")]
+    Row {
+        row: String,
+        date: String,
+        error: Box<PipelineError>,
+    },
+    /// A row failed with a fatal error (see [`PipelineError::is_fatal`]).
+    #[error(transparent)]
+    Fatal(Box<PipelineError>),
+    /// More rows failed than the maximum, `status` being the most frequent status of them
+    /// and `failures` the number of rows of each status.
+    #[error("{failed} rows failed, more than --max-errors {max} ({failures})")]
+    TooManyErrors {
+        failed: usize,
+        max: usize,
+        status: RowStatus,
+        failures: String,
+    },
+    /// The output couldn't be created.
+    #[error("{0}")]
+    Create(std::io::Error),
+    /// A row couldn't be written to the output.
+    #[error("{0}")]
+    Write(csv::Error),
+    /// The output couldn't be flushed once the rows were written.
+    #[error("{0}")]
+    Flush(std::io::Error),
+}
+
+impl RunError {
+    /// Status of the rows which ended the run, `None` if it failed writing the output.
+    pub fn status(&self) -> Option<RowStatus> {
+        match self {
+            RunError::Row { error, .. } | RunError::Fatal(error) => Some(error.status()),
+            RunError::TooManyErrors { status, .. } => Some(*status),
+            RunError::Create(_) | RunError::Write(_) | RunError::Flush(_) => None,
+        }
+    }
+}
+
+fn describe_suggestion(suggestion: &Option<String>) -> String {
+    match suggestion {
+        Some(s) => format!(", did you mean \"{}\"?", s),
+        None => ".".to_string(),
+    }
+}
+
+/// The first headers, followed by the number of the others.
+fn describe_headers(headers: &[String]) -> String {
+    let mut listed = headers
+        .iter()
+        .take(LISTED_COLUMNS)
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+    if headers.len() > LISTED_COLUMNS {
+        listed.push_str(&format!(" and {} more", headers.len() - LISTED_COLUMNS));
+    }
+    listed
+}
+
+fn describe_duplicates(columns: &[(String, Vec<String>)]) -> String {
+    columns
+        .iter()
+        .map(|(name, sources)| format!("\"{}\" (from {})", name, sources.join(", ")))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn describe_date_errors(errors: &[ParseError]) -> String {
+    let joined = || {
+        errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("; ")
+    };
+    match errors {
+        _ if errors.iter().any(ParseError::is_short_row) => {
+            format!("Short row, failed to parse date: {}", joined())
+        }
+        [error] => format!("Failed to parse date {}", error),
+        _ => format!("Failed to parse date: {}", joined()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd(2024, 3, 15)
+    }
+
+    #[test]
+    fn provider_failures_are_told_apart() {
+        let fetch = |e| PipelineError::Fetch(e).status();
+        let currency = "USD".to_string();
+        assert_eq!(
+            fetch(FetchError::NotPublished {
+                date: date(),
+                currency: currency.clone()
+            }),
+            RowStatus::RateMissing
+        );
+        assert_eq!(
+            fetch(FetchError::NoneInMonth {
+                month: date(),
+                currency
+            }),
+            RowStatus::RateMissing
+        );
+        assert_eq!(
+            fetch(FetchError::Status {
+                date: date(),
+                status: StatusCode::BAD_GATEWAY
+            }),
+            RowStatus::NetworkError
+        );
+        assert_eq!(
+            fetch(FetchError::Source {
+                date: date(),
+                error: "connection reset".into()
+            }),
+            RowStatus::NetworkError
+        );
+        assert_eq!(
+            PipelineError::BeforeMinDate {
+                date: date(),
+                rate_date: date(),
+                min_date: date()
+            }
+            .status(),
+            RowStatus::RateMissing
+        );
+    }
+
+    #[test]
+    fn row_failures_are_told_apart() {
+        assert_eq!(PipelineError::EmptyDate.status(), RowStatus::EmptyDate);
+        assert_eq!(
+            PipelineError::InvalidAmount {
+                column: 2,
+                value: "12abc34".to_string()
+            }
+            .status(),
+            RowStatus::AmountParseError
+        );
+        let future = |fatal| PipelineError::FutureDate {
+            date: date(),
+            rate_date: date(),
+            fatal,
+        };
+        assert_eq!(future(false).status(), RowStatus::FutureDate);
+        assert!(future(true).is_fatal());
+        assert!(!future(false).is_fatal());
+        assert_eq!(PipelineError::ZeroRate.status(), RowStatus::Error);
+    }
+}
@@ -0,0 +1,993 @@
+//! Record filters, parsed from `{column}{operator}{value}` expressions, where the operator is
+//! either a regex match (`=`, `!=`), a numeric comparison (`>`, `>=`, `<`, `<=`),
+//! or a set membership (`@=`, with values loaded from a file).
+//! Comparisons on the date column (or columns prefixed with `date:`) compare dates instead.
+//!
+//! ```
+//! use bnm_exporter::filter::{FilterExpr, FilterSet};
+//! use csv::StringRecord;
+//! use regex::Regex;
+//! use rust_decimal::Decimal;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let headers = StringRecord::from(vec!["Date", "Type", "Amount"]);
+//! let card = FilterExpr::parse("Type=^Card", Some(&headers))?;
+//! assert_eq!(card.to_string(), "Type=^Card");
+//! let filters = FilterSet::default()
+//!     .with_filter(card)
+//!     .with_filter(FilterExpr::num_gt(2, Decimal::from(100)))
+//!     .with_any_filter(FilterExpr::regex(1, Regex::new("(?i)refund")?));
+//! let row = StringRecord::from(vec!["2024-03-15", "Card refund", "125.00"]);
+//! assert!(filters.matches(&row));
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use chrono::NaiveDate;
+use csv::StringRecord;
+use regex::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
+use rust_decimal::Decimal;
+
+use crate::amount::{parse_amount, AmountFormat};
+use crate::csvproc::get_column_index;
+use crate::error::ParseError;
+
+/// Matches a single cell value.
+trait CellMatcher: Send + Sync {
+    fn matches(&self, value: &str) -> bool;
+}
+
+struct RegexMatcher(Regex);
+
+impl CellMatcher for RegexMatcher {
+    fn matches(&self, value: &str) -> bool {
+        self.0.is_match(value)
+    }
+}
+
+/// Matches cells equal to one of the values (after trimming).
+struct SetMatcher {
+    values: HashSet<String>,
+    ignore_case: bool,
+}
+
+impl CellMatcher for SetMatcher {
+    fn matches(&self, value: &str) -> bool {
+        let value = value.trim();
+        if self.ignore_case {
+            self.values.contains(&value.to_lowercase())
+        } else {
+            self.values.contains(value)
+        }
+    }
+}
+
+struct RegexSetMatcher(RegexSet);
+
+impl CellMatcher for RegexSetMatcher {
+    fn matches(&self, value: &str) -> bool {
+        self.0.is_match(value)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+/// Compares the cell parsed as an amount, non-numeric cells don't match.
+struct NumericMatcher {
+    comparison: Comparison,
+    value: Decimal,
+}
+
+impl CellMatcher for NumericMatcher {
+    fn matches(&self, value: &str) -> bool {
+        match parse_number(value) {
+            Some(cell) => self.comparison.compare(Some(cell.cmp(&self.value))),
+            None => false,
+        }
+    }
+}
+
+impl Comparison {
+    fn compare(&self, ordering: Option<std::cmp::Ordering>) -> bool {
+        use std::cmp::Ordering::*;
+        matches!(
+            (self, ordering),
+            (Comparison::Equal, Some(Equal))
+                | (Comparison::Greater, Some(Greater))
+                | (Comparison::GreaterOrEqual, Some(Greater | Equal))
+                | (Comparison::Less, Some(Less))
+                | (Comparison::LessOrEqual, Some(Less | Equal))
+        )
+    }
+}
+
+/// Compares the cell parsed as a date, cells which can't be parsed don't match.
+struct DateMatcher {
+    comparison: Comparison,
+    value: NaiveDate,
+    parser: DateCellParser,
+}
+
+impl CellMatcher for DateMatcher {
+    fn matches(&self, value: &str) -> bool {
+        match (self.parser)(value) {
+            Some(date) => self.comparison.compare(Some(date.cmp(&self.value))),
+            None => false,
+        }
+    }
+}
+
+/// Prefix of columns which must be compared as dates.
+const DATE_PREFIX: &str = "date:";
+
+/// Parses a cell as a date, using the input date format.
+pub type DateCellParser = Arc<dyn Fn(&str) -> Option<NaiveDate> + Send + Sync>;
+
+/// Enables date comparisons in filter expressions.
+pub struct DateFilterOptions {
+    pub parser: DateCellParser,
+    /// Date columns, compared as dates even without the `date:` prefix.
+    pub columns: Vec<usize>,
+}
+
+/// Special column matching any field of the record.
+const ANY_COLUMN: &str = "*";
+
+#[derive(Debug, PartialEq, Eq)]
+enum FilterColumn {
+    Index(usize),
+    /// The filter matches if any field matches (no field matches for negated filters).
+    Any,
+}
+
+/// A single filter expression, e.g. `Type=^Card` or `Memo!=refund`.
+/// It's displayed as the expression it's parsed from, escaping the column name if needed.
+pub struct FilterExpr {
+    column: FilterColumn,
+    matcher: Box<dyn CellMatcher>,
+    /// Set for `!=` expressions, the filter matches rows not matching the regex.
+    negate: bool,
+    /// The column (with the `date:` prefix if any), the operator and the value as written.
+    source: (String, &'static str, String),
+}
+
+impl FilterExpr {
+    /// Parses a `{column}{operator}{value}` expression with the default options.
+    /// Columns are header names, or indexes starting from 0 without headers.
+    pub fn parse(filter: &str, headers: Option<&StringRecord>) -> Result<Self, ParseError> {
+        FilterExpr::parse_with(filter, headers, &FilterOptions::default())
+    }
+
+    /// Parses the expression with the options, e.g. to compare dates or ignore the case.
+    pub fn parse_with(
+        filter: &str,
+        headers: Option<&StringRecord>,
+        options: &FilterOptions,
+    ) -> Result<Self, ParseError> {
+        let (column, operator, value) = split_expression(filter)?;
+        build_filter(&column, operator, value, headers, options).map_err(|error| {
+            ParseError::FilterExpr {
+                column: column.clone(),
+                operator,
+                value: value.to_string(),
+                error: Box::new(error),
+            }
+        })
+    }
+
+    /// Matches rows whose cell at the index matches the regex, like `{index}={regex}`.
+    pub fn regex(column: usize, regex: Regex) -> Self {
+        FilterExpr {
+            column: FilterColumn::Index(column),
+            source: (column.to_string(), "=", regex.as_str().to_string()),
+            matcher: Box::new(RegexMatcher(regex)),
+            negate: false,
+        }
+    }
+
+    /// Matches rows whose cell at the index is a number greater than the value,
+    /// like `{index}>{value}`.
+    pub fn num_gt(column: usize, value: Decimal) -> Self {
+        FilterExpr {
+            column: FilterColumn::Index(column),
+            source: (column.to_string(), ">", value.to_string()),
+            matcher: Box::new(NumericMatcher {
+                comparison: Comparison::Greater,
+                value,
+            }),
+            negate: false,
+        }
+    }
+
+    /// Whether the cell of the filter's column (any cell for `*`) matches.
+    pub fn matches(&self, record: &StringRecord) -> bool {
+        match self.column {
+            FilterColumn::Index(i) => record
+                .get(i)
+                .map(|v| self.matcher.matches(v) != self.negate)
+                .unwrap_or(false),
+            FilterColumn::Any => record.iter().any(|v| self.matcher.matches(v)) != self.negate,
+        }
+    }
+}
+
+/// Writes the expression so that it's parsed back into the same one
+/// (given the same headers and options).
+impl fmt::Display for FilterExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (column, operator, value) = &self.source;
+        for c in column.chars() {
+            if ESCAPABLE.contains(&c) {
+                write!(f, "\\")?;
+            }
+            write!(f, "{}", c)?;
+        }
+        write!(f, "{}{}", operator, value)
+    }
+}
+
+/// Options applied to all filter expressions.
+#[derive(Default)]
+pub struct FilterOptions {
+    /// Compile regexes case-insensitively.
+    pub ignore_case: bool,
+    /// Treat the lines of `@=` files as regexes rather than exact values.
+    pub file_regex: bool,
+    /// Date comparisons aren't supported if not set.
+    pub dates: Option<DateFilterOptions>,
+    /// Skip invalid expressions with a warning, instead of failing.
+    pub lenient: bool,
+}
+
+/// A row passes the filter set if it matches every filter of `all`
+/// and at least one filter of `any` (when there are any).
+/// The result is negated for inverted sets.
+#[derive(Default)]
+pub struct FilterSet {
+    all: Vec<FilterExpr>,
+    any: Vec<FilterExpr>,
+    invert: bool,
+}
+
+impl FilterSet {
+    /// Parses the filter expressions, failing on the first invalid one
+    /// (unless the options are lenient).
+    pub fn new(
+        all: &[String],
+        any: &[String],
+        headers: Option<&StringRecord>,
+        options: &FilterOptions,
+    ) -> Result<Self, ParseError> {
+        Ok(FilterSet {
+            all: create_filters(all, "--filter", headers, options)?,
+            any: create_filters(any, "--filter-any", headers, options)?,
+            invert: false,
+        })
+    }
+
+    /// Filter set of expressions which must all match, `option` names them in errors.
+    pub fn all_of(
+        filters: &[String],
+        option: &str,
+        headers: Option<&StringRecord>,
+        options: &FilterOptions,
+    ) -> Result<Self, ParseError> {
+        Ok(FilterSet {
+            all: create_filters(filters, option, headers, options)?,
+            any: Vec::new(),
+            invert: false,
+        })
+    }
+
+    /// Adds regex filters given as separate column and regex lists, for expressions which are
+    /// hard to escape.
+    pub fn with_regexes(
+        mut self,
+        columns: &[String],
+        regexes: &[String],
+        headers: Option<&StringRecord>,
+        options: &FilterOptions,
+    ) -> Result<Self, ParseError> {
+        if columns.len() != regexes.len() {
+            return Err(ParseError::FilterRegexes {
+                columns: columns.len(),
+                regexes: regexes.len(),
+            });
+        }
+        for (i, (column, regex)) in columns.iter().zip(regexes).enumerate() {
+            match build_filter(column, "=", regex, headers, options) {
+                Ok(filter) => self.all.push(filter),
+                Err(e) if options.lenient => log::warn!(
+                    "Ignoring invalid --filter-column #{} \"{}\" - {}",
+                    i + 1,
+                    column,
+                    e
+                ),
+                Err(error) => {
+                    return Err(ParseError::Filter {
+                        option: "--filter-column".to_string(),
+                        index: i + 1,
+                        filter: column.clone(),
+                        error: Box::new(error),
+                    })
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    /// Adds a filter which must match.
+    pub fn with_filter(mut self, filter: FilterExpr) -> Self {
+        self.all.push(filter);
+        self
+    }
+
+    /// Adds a filter of which at least one must match.
+    pub fn with_any_filter(mut self, filter: FilterExpr) -> Self {
+        self.any.push(filter);
+        self
+    }
+
+    /// Keeps only the rows which don't pass the filters, like `grep -v`.
+    /// Unlike `!=`, which negates a single expression, this negates the combined result.
+    pub fn inverted(mut self, invert: bool) -> Self {
+        self.invert = invert;
+        self
+    }
+
+    /// Whether the rows not passing the filters are kept instead.
+    pub fn is_inverted(&self) -> bool {
+        self.invert
+    }
+
+    /// Whether there's no filter, so that every row matches (unless inverted).
+    pub fn is_empty(&self) -> bool {
+        self.all.is_empty() && self.any.is_empty()
+    }
+
+    /// Whether the row is kept, taking the inversion into account.
+    pub fn matches(&self, record: &StringRecord) -> bool {
+        let matches = self.all.iter().all(|f| f.matches(record))
+            && (self.any.is_empty() || self.any.iter().any(|f| f.matches(record)));
+        matches != self.invert
+    }
+}
+
+fn create_filters(
+    filters: &[String],
+    option: &str,
+    headers: Option<&StringRecord>,
+    options: &FilterOptions,
+) -> Result<Vec<FilterExpr>, ParseError> {
+    let mut out = Vec::with_capacity(filters.len());
+    for (i, f) in filters.iter().enumerate() {
+        match FilterExpr::parse_with(f, headers, options) {
+            Ok(filter) => out.push(filter),
+            Err(e) if options.lenient => {
+                log::warn!("Ignoring invalid {} #{} \"{}\" - {}", option, i + 1, f, e)
+            }
+            Err(error) => {
+                return Err(ParseError::Filter {
+                    option: option.to_string(),
+                    index: i + 1,
+                    filter: f.clone(),
+                    error: Box::new(error),
+                })
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Characters which can be escaped with a backslash in column names.
+const ESCAPABLE: [char; 6] = ['=', '<', '>', '!', '@', '\\'];
+
+/// Splits the expression into the column, the operator and the value.
+/// The first unescaped operator character decides the operator, so the value may contain any
+/// of them. Operator characters in the column name must be escaped with a backslash
+/// (e.g. "A\=B=x" for the column "A=B").
+fn split_expression(filter: &str) -> Result<(String, &'static str, &str), ParseError> {
+    let mut column = String::new();
+    let mut last_escaped = false;
+    let mut chars = filter.char_indices();
+    let position = loop {
+        match chars.next() {
+            Some((_, '\\')) => {
+                match chars.next() {
+                    Some((_, c)) if ESCAPABLE.contains(&c) => column.push(c),
+                    Some((_, c)) => {
+                        column.push('\\');
+                        column.push(c);
+                    }
+                    None => column.push('\\'),
+                }
+                last_escaped = true;
+            }
+            Some((i, '=' | '<' | '>')) => break i,
+            Some((_, c)) => {
+                column.push(c);
+                last_escaped = false;
+            }
+            None => {
+                return Err(ParseError::FilterSyntax {
+                    filter: filter.to_string(),
+                })
+            }
+        }
+    };
+    let rest = &filter[position..];
+    let (operator, value) = match rest.strip_prefix(['<', '>']) {
+        Some(v) => match (&rest[..1], v.strip_prefix('=')) {
+            ("<", Some(v)) => ("<=", v),
+            (">", Some(v)) => (">=", v),
+            ("<", None) => ("<", v),
+            _ => (">", v),
+        },
+        None => ("=", &rest[1..]),
+    };
+    if operator == "=" && !last_escaped {
+        if let Some(c) = column.strip_suffix(['!', '@']) {
+            let operator = if column.ends_with('!') { "!=" } else { "@=" };
+            return Ok((c.to_string(), operator, value));
+        }
+    }
+    Ok((column, operator, value))
+}
+
+fn build_filter(
+    column: &str,
+    operator: &'static str,
+    value: &str,
+    headers: Option<&StringRecord>,
+    options: &FilterOptions,
+) -> Result<FilterExpr, ParseError> {
+    let source = (column.to_string(), operator, value.to_string());
+    let (column, date_prefix) = match column.strip_prefix(DATE_PREFIX) {
+        Some(c) => (c, true),
+        None => (column, false),
+    };
+    let column = match column {
+        ANY_COLUMN => FilterColumn::Any,
+        _ => FilterColumn::Index(get_column_index(headers, column)?),
+    };
+    let is_date_column = match (&column, options.dates.as_ref()) {
+        (FilterColumn::Index(i), Some(dates)) => dates.columns.contains(i),
+        _ => false,
+    };
+    let (matcher, negate): (Box<dyn CellMatcher>, bool) = match operator {
+        "=" | "!=" if date_prefix => (create_date_matcher("=", value, options)?, operator == "!="),
+        "@=" if date_prefix => return Err(ParseError::DateSet),
+        ">" | ">=" | "<" | "<=" if date_prefix || is_date_column => {
+            (create_date_matcher(operator, value, options)?, false)
+        }
+        "@=" => (load_set_matcher(Path::new(value), options)?, false),
+        "=" | "!=" => {
+            let regex = RegexBuilder::new(value)
+                .case_insensitive(options.ignore_case)
+                .build()?;
+            (Box::new(RegexMatcher(regex)), operator == "!=")
+        }
+        _ => {
+            let comparison = parse_comparison(operator);
+            let value = parse_number(value).ok_or_else(|| ParseError::FilterNumber {
+                operator,
+                value: value.to_string(),
+            })?;
+            (Box::new(NumericMatcher { comparison, value }), false)
+        }
+    };
+    Ok(FilterExpr {
+        column,
+        matcher,
+        negate,
+        source,
+    })
+}
+
+fn parse_comparison(operator: &str) -> Comparison {
+    match operator {
+        "=" => Comparison::Equal,
+        ">" => Comparison::Greater,
+        ">=" => Comparison::GreaterOrEqual,
+        "<" => Comparison::Less,
+        _ => Comparison::LessOrEqual,
+    }
+}
+
+/// Date comparison with an ISO date (YYYY-MM-DD) value.
+fn create_date_matcher(
+    operator: &'static str,
+    value: &str,
+    options: &FilterOptions,
+) -> Result<Box<dyn CellMatcher>, ParseError> {
+    let parser = options
+        .dates
+        .as_ref()
+        .map(|d| d.parser.clone())
+        .ok_or(ParseError::DatesUnsupported)?;
+    let value = NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d").map_err(|error| {
+        ParseError::FilterDate {
+            operator,
+            value: value.to_string(),
+            error,
+        }
+    })?;
+    Ok(Box::new(DateMatcher {
+        comparison: parse_comparison(operator),
+        value,
+        parser,
+    }))
+}
+
+/// Loads values from a file, one per line, ignoring empty lines and "#" comments.
+fn load_set_matcher(
+    path: &Path,
+    options: &FilterOptions,
+) -> Result<Box<dyn CellMatcher>, ParseError> {
+    let content = std::fs::read_to_string(path).map_err(|error| ParseError::FilterFile {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    let lines = content
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'));
+    if !options.file_regex {
+        let values = lines
+            .map(|(_, l)| {
+                if options.ignore_case {
+                    l.to_lowercase()
+                } else {
+                    l.to_string()
+                }
+            })
+            .collect();
+        return Ok(Box::new(SetMatcher {
+            values,
+            ignore_case: options.ignore_case,
+        }));
+    }
+    let mut patterns = Vec::new();
+    for (number, line) in lines {
+        Regex::new(line).map_err(|error| ParseError::FilterFileRegex {
+            path: path.to_path_buf(),
+            line: number,
+            error,
+        })?;
+        patterns.push(line);
+    }
+    let set = RegexSetBuilder::new(patterns)
+        .case_insensitive(options.ignore_case)
+        .build()?;
+    Ok(Box::new(RegexSetMatcher(set)))
+}
+
+/// Parses a number the same way as the amount columns, detecting the decimal separator.
+fn parse_number(value: &str) -> Option<Decimal> {
+    parse_amount(value, &AmountFormat::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers() -> StringRecord {
+        StringRecord::from(vec!["Date", "Type", "Payee", "Amount"])
+    }
+
+    fn row(fields: &[&str]) -> StringRecord {
+        StringRecord::from(fields.to_vec())
+    }
+
+    fn filters(all: &[&str], any: &[&str]) -> FilterSet {
+        let all: Vec<String> = all.iter().map(|f| f.to_string()).collect();
+        let any: Vec<String> = any.iter().map(|f| f.to_string()).collect();
+        FilterSet::new(&all, &any, Some(&headers()), &FilterOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn all_and_any_combination() {
+        let card_acme = row(&["2024-03-15", "Card", "Acme", "10"]);
+        let card_other = row(&["2024-03-15", "Card", "Other", "10"]);
+        let transfer_acme = row(&["2024-03-15", "Transfer", "Acme", "10"]);
+        let transfer_globex = row(&["2024-03-15", "Transfer", "Globex", "10"]);
+        let rows = [&card_acme, &card_other, &transfer_acme, &transfer_globex];
+        let cases: [(&[&str], &[&str], [bool; 4]); 6] = [
+            (&[], &[], [true, true, true, true]),
+            (&["Type=Card"], &[], [true, true, false, false]),
+            (&[], &["Payee=Acme"], [true, false, true, false]),
+            (
+                &[],
+                &["Payee=Acme", "Payee=Globex"],
+                [true, false, true, true],
+            ),
+            (
+                &["Type=Card"],
+                &["Payee=Acme", "Payee=Globex"],
+                [true, false, false, false],
+            ),
+            (
+                &["Type=Card", "Payee=Other"],
+                &["Amount=10"],
+                [false, true, false, false],
+            ),
+        ];
+        for (all, any, expected) in cases {
+            let set = filters(all, any);
+            let matches: Vec<bool> = rows.iter().map(|r| set.matches(r)).collect();
+            assert_eq!(matches, expected, "all {:?} any {:?}", all, any);
+        }
+    }
+
+    #[test]
+    fn any_filters_with_programmatic_filters() {
+        let set = FilterSet::default()
+            .with_any_filter(FilterExpr::regex(2, Regex::new("^Acme$").unwrap()))
+            .with_any_filter(FilterExpr::regex(1, Regex::new("Fee").unwrap()));
+        assert!(set.matches(&row(&["2024-03-15", "Card", "Acme", "10"])));
+        assert!(set.matches(&row(&["2024-03-15", "Card Fee", "Bank", "1"])));
+        assert!(!set.matches(&row(&["2024-03-15", "Card", "Acme Inc", "10"])));
+        // Rows too short for a filter's column don't match it.
+        assert!(!set.matches(&row(&["2024-03-15"])));
+    }
+
+    #[test]
+    fn splits_negated_expressions() {
+        let cases = [
+            ("Payee!=Transfer intern", ("Payee", "!=", "Transfer intern")),
+            ("Payee!=a!=b", ("Payee", "!=", "a!=b")),
+            ("Payee!=a=b!", ("Payee", "!=", "a=b!")),
+            ("Payee=!=", ("Payee", "=", "!=")),
+            ("Payee=a!=b", ("Payee", "=", "a!=b")),
+            ("Payee!=", ("Payee", "!=", "")),
+            ("Wow\\!=x", ("Wow!", "=", "x")),
+        ];
+        for (filter, (column, operator, value)) in cases {
+            let (c, o, v) = split_expression(filter).unwrap();
+            assert_eq!((c.as_str(), o, v), (column, operator, value), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn negated_filters_match_other_rows() {
+        let set = filters(&["Payee!=Transfer intern"], &[]);
+        assert!(set.matches(&row(&["2024-03-15", "Card", "Acme", "10"])));
+        assert!(!set.matches(&row(&["2024-03-15", "Card", "Transfer intern", "10"])));
+        let set = filters(&["Type!=!=x"], &[]);
+        assert!(!set.matches(&row(&["2024-03-15", "a!=x", "Acme", "10"])));
+        assert!(set.matches(&row(&["2024-03-15", "a=x", "Acme", "10"])));
+        // A short row doesn't match, even negated.
+        assert!(!set.matches(&row(&["2024-03-15"])));
+    }
+
+    #[test]
+    fn negated_any_column() {
+        let set = filters(&["*!=refund"], &[]);
+        assert!(set.matches(&row(&["2024-03-15", "Card", "Acme", "10"])));
+        assert!(!set.matches(&row(&["2024-03-15", "Card", "Acme refund", "10"])));
+    }
+
+    #[test]
+    fn numeric_comparisons() {
+        let cases = [
+            ("Amount>1000", "1.234,56", true),
+            ("Amount>1000", "1,234.56", true),
+            ("Amount>1000", "1 234,56", true),
+            ("Amount>1000", "1000", false),
+            ("Amount>=1000", "1000.00", true),
+            ("Amount>=1000", "1,000", true),
+            ("Amount<1000", "999.99 USD", true),
+            ("Amount<1000", "$1,500", false),
+            ("Amount<0", "-25.00", true),
+            ("Amount<0", "(125.00)", true),
+            ("Amount<0", "25.00", false),
+            ("Amount<=-25", "-25,00 MDL", true),
+            ("Amount>-1", "−0,5", true),
+            ("Amount>0.1", "0.125", true),
+            ("Amount<0.2", "0,125", true),
+            ("Amount>0", "", false),
+            ("Amount<0", "", false),
+            ("Amount<1", "n/a", false),
+            ("Amount>=1,5", "1.5", true),
+        ];
+        for (filter, cell, expected) in cases {
+            let set = filters(&[filter], &[]);
+            let record = row(&["2024-03-15", "Card", "Acme", cell]);
+            assert_eq!(set.matches(&record), expected, "{} {}", filter, cell);
+        }
+    }
+
+    #[test]
+    fn invalid_numeric_comparisons() {
+        for filter in ["Amount>", "Amount>=abc", "Amount<USD"] {
+            let error = FilterExpr::parse(filter, Some(&headers())).err().unwrap();
+            assert!(
+                matches!(&error, ParseError::FilterExpr { error, .. }
+                    if matches!(**error, ParseError::FilterNumber { .. })),
+                "{}",
+                filter
+            );
+        }
+    }
+
+    #[test]
+    fn splits_escaped_columns() {
+        let cases = [
+            (r"Amount=^\d+=\d+$", ("Amount", "=", r"^\d+=\d+$")),
+            (r"IBAN=MD\d{2}=?", ("IBAN", "=", r"MD\d{2}=?")),
+            (r"A\=B=x", ("A=B", "=", "x")),
+            (r"A\=B\=C!=x", ("A=B=C", "!=", "x")),
+            (r"A\<B>=1", ("A<B", ">=", "1")),
+            (r"A\>B<1", ("A>B", "<", "1")),
+            (r"A\@B@=list.txt", ("A@B", "@=", "list.txt")),
+            (r"A\\=x", (r"A\", "=", "x")),
+            (r"A\x=y", (r"A\x", "=", "y")),
+            ("Amount>=1000", ("Amount", ">=", "1000")),
+            ("Amount<=-5", ("Amount", "<=", "-5")),
+            ("Amount<>1", ("Amount", "<", ">1")),
+        ];
+        for (filter, (column, operator, value)) in cases {
+            let (c, o, v) = split_expression(filter).unwrap();
+            assert_eq!((c.as_str(), o, v), (column, operator, value), "{}", filter);
+        }
+        for filter in ["", "Amount", r"A\=B"] {
+            assert!(
+                matches!(
+                    split_expression(filter),
+                    Err(ParseError::FilterSyntax { .. })
+                ),
+                "{}",
+                filter
+            );
+        }
+    }
+
+    #[test]
+    fn escaped_column_names_match() {
+        let headers = StringRecord::from(vec!["A=B", "IBAN"]);
+        let filter = FilterExpr::parse(r"A\=B=^x$", Some(&headers)).unwrap();
+        assert!(filter.matches(&row(&["x", "MD24"])));
+        assert!(!filter.matches(&row(&["y", "MD24"])));
+        let filter = FilterExpr::parse(r"IBAN=^MD\d{2}=?$", Some(&headers)).unwrap();
+        assert!(filter.matches(&row(&["x", "MD24="])));
+        assert!(filter.matches(&row(&["x", "MD24"])));
+    }
+
+    #[test]
+    fn errors_echo_the_split() {
+        let error = FilterExpr::parse(r"A=B=x", Some(&headers())).err().unwrap();
+        let message = error.to_string();
+        assert!(
+            message.contains(r#"split into column "A", operator "=" and value "B=x""#),
+            "{}",
+            message
+        );
+        let error = FilterSet::new(
+            &["Type=(".to_string()],
+            &[],
+            Some(&headers()),
+            &FilterOptions::default(),
+        )
+        .err()
+        .unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("Invalid --filter #1 \"Type=(\""));
+    }
+
+    #[test]
+    fn column_and_regex_lists() {
+        let headers = StringRecord::from(vec!["A=B", "Memo"]);
+        let options = FilterOptions::default();
+        let columns = vec!["A=B".to_string(), "Memo".to_string()];
+        let regexes = vec!["^x=y$".to_string(), "!".to_string()];
+        let set = FilterSet::default()
+            .with_regexes(&columns, &regexes, Some(&headers), &options)
+            .unwrap();
+        assert!(set.matches(&row(&["x=y", "hi!"])));
+        assert!(!set.matches(&row(&["x=y", "hi"])));
+        assert!(!set.matches(&row(&["x", "hi!"])));
+        // Same as the escaped expressions.
+        let expressions = vec![r"A\=B=^x=y$".to_string(), "Memo=!".to_string()];
+        let escaped = FilterSet::new(&expressions, &[], Some(&headers), &options).unwrap();
+        for record in [
+            row(&["x=y", "hi!"]),
+            row(&["x=y", "hi"]),
+            row(&["x", "hi!"]),
+        ] {
+            assert_eq!(escaped.matches(&record), set.matches(&record));
+        }
+        assert!(matches!(
+            FilterSet::default().with_regexes(&columns, &regexes[..1], Some(&headers), &options),
+            Err(ParseError::FilterRegexes {
+                columns: 2,
+                regexes: 1
+            })
+        ));
+        assert!(matches!(
+            FilterSet::default().with_regexes(
+                &columns[1..],
+                &["(".to_string()],
+                Some(&headers),
+                &options
+            ),
+            Err(ParseError::Filter { index: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn invert_negate_any_all_matrix() {
+        let card = row(&["2024-03-15", "Card", "Acme", "10"]);
+        let transfer = row(&["2024-03-15", "Transfer", "Acme", "10"]);
+        // All, any, inverted, whether the card and the transfer rows are kept.
+        type Case<'a> = (&'a [&'a str], &'a [&'a str], bool, [bool; 2]);
+        let cases: [Case; 12] = [
+            (&["Type=Card"], &[], false, [true, false]),
+            (&["Type=Card"], &[], true, [false, true]),
+            (&["Type!=Card"], &[], false, [false, true]),
+            (&["Type!=Card"], &[], true, [true, false]),
+            (&[], &["Type=Card", "Payee=Globex"], false, [true, false]),
+            (&[], &["Type=Card", "Payee=Globex"], true, [false, true]),
+            (&[], &["Type!=Card", "Payee=Globex"], false, [false, true]),
+            (&[], &["Type!=Card", "Payee=Globex"], true, [true, false]),
+            (
+                &["Payee=Acme"],
+                &["Type!=Card", "Payee=Globex"],
+                true,
+                [true, false],
+            ),
+            (&["Payee!=Acme"], &["Type=Card"], true, [true, true]),
+            (&["Type=Card", "Payee=Acme"], &[], true, [false, true]),
+            (&[], &[], true, [false, false]),
+        ];
+        for (all, any, inverted, expected) in cases {
+            let set = filters(all, any).inverted(inverted);
+            assert_eq!(set.is_inverted(), inverted);
+            let kept = [set.matches(&card), set.matches(&transfer)];
+            assert_eq!(
+                kept, expected,
+                "all {:?} any {:?} inverted {}",
+                all, any, inverted
+            );
+        }
+    }
+
+    #[test]
+    fn programmatic_numeric_filters() {
+        let filter = FilterExpr::num_gt(1, "0.1".parse().unwrap());
+        assert_eq!(filter.to_string(), "1>0.1");
+        assert!(filter.matches(&row(&["x", "0.10000001"])));
+        assert!(!filter.matches(&row(&["x", "0.1"])));
+        assert!(!filter.matches(&row(&["x", "0,100"])));
+        let parsed = FilterExpr::parse(&filter.to_string(), None).unwrap();
+        for cell in ["0.10000001", "0.1", "-1", "1.234,56", ""] {
+            let record = row(&["x", cell]);
+            assert_eq!(parsed.matches(&record), filter.matches(&record), "{}", cell);
+        }
+    }
+
+    /// Pseudo-random generator of the property tests, seeded so that failures reproduce.
+    struct Xorshift(u64);
+
+    impl Xorshift {
+        fn next(&mut self) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 as usize
+        }
+
+        /// Up to `max` characters of the alphabet.
+        fn text(&mut self, alphabet: &[char], max: usize) -> String {
+            let len = self.next() % (max + 1);
+            (0..len)
+                .map(|_| alphabet[self.next() % alphabet.len()])
+                .collect()
+        }
+    }
+
+    const COLUMN_CHARS: [char; 12] = ['a', 'B', ' ', '=', '<', '>', '!', '@', '\\', '*', ':', '1'];
+    const VALUE_CHARS: [char; 14] = [
+        'a', '1', '0', '.', ',', '-', '=', '<', '>', '!', '(', ')', '^', ' ',
+    ];
+    const OPERATORS: [&str; 7] = ["=", "!=", ">", ">=", "<", "<=", "=="];
+
+    /// Escapes the characters of a column name which would end it.
+    fn escape(column: &str) -> String {
+        column
+            .chars()
+            .flat_map(|c| match ESCAPABLE.contains(&c) {
+                true => vec!['\\', c],
+                false => vec![c],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parsing_never_panics() {
+        let mut random = Xorshift(0x2545_f491_4f6c_dd1d);
+        let headers = headers();
+        let mut chars = COLUMN_CHARS.to_vec();
+        chars.extend(VALUE_CHARS);
+        for _ in 0..5000 {
+            let filter = random.text(&chars, 12);
+            let _ = FilterExpr::parse(&filter, Some(&headers));
+            let _ = FilterExpr::parse(&filter, None);
+        }
+    }
+
+    #[test]
+    fn displayed_expressions_parse_back() {
+        let mut random = Xorshift(0x9e37_79b9_7f4a_7c15);
+        let mut parsed = 0;
+        for _ in 0..5000 {
+            let mut column = random.text(&COLUMN_CHARS, 5);
+            if column.is_empty() {
+                column.push('a');
+            }
+            let headers = StringRecord::from(vec![column.as_str(), "Amount", "b"]);
+            let operator = OPERATORS[random.next() % OPERATORS.len()];
+            let value = random.text(&VALUE_CHARS, 6);
+            let expression = format!("{}{}{}", escape(&column), operator, value);
+            let filter = match FilterExpr::parse(&expression, Some(&headers)) {
+                Ok(filter) => filter,
+                // E.g. an invalid regex or a comparison with something other than a number.
+                Err(_) => continue,
+            };
+            parsed += 1;
+            let displayed = filter.to_string();
+            let reparsed = FilterExpr::parse(&displayed, Some(&headers)).unwrap_or_else(|e| {
+                panic!("{:?} displayed as {:?} - {}", expression, displayed, e)
+            });
+            assert_eq!(reparsed.to_string(), displayed, "{:?}", expression);
+            assert_eq!(reparsed.column, filter.column, "{:?}", expression);
+            for _ in 0..10 {
+                let cells: Vec<String> = (0..3).map(|_| random.text(&VALUE_CHARS, 4)).collect();
+                let record = StringRecord::from(cells.clone());
+                assert_eq!(
+                    reparsed.matches(&record),
+                    filter.matches(&record),
+                    "{:?} on {:?}",
+                    expression,
+                    cells
+                );
+            }
+        }
+        // Enough expressions are valid for the property to mean something.
+        assert!(parsed > 1000, "{}", parsed);
+    }
+
+    #[test]
+    fn display_of_escaped_expressions() {
+        let headers = StringRecord::from(vec!["A=B", "Wow!", "x\\y", "c@", "<", "Amount"]);
+        let cases = [
+            (r"A\=B=^x$", r"A\=B=^x$"),
+            (r"Wow\!=x", r"Wow\!=x"),
+            (r"Wow\!!=x", r"Wow\!!=x"),
+            (r"x\y=1", r"x\\y=1"),
+            (r"x\\y=1", r"x\\y=1"),
+            (r"c\@=1", r"c\@=1"),
+            (r"\<>=5", r"\<>=5"),
+            ("Amount>=1,5", "Amount>=1,5"),
+            ("Amount==x", "Amount==x"),
+            ("*!=refund", "*!=refund"),
+        ];
+        for (expression, displayed) in cases {
+            let filter = FilterExpr::parse(expression, Some(&headers))
+                .unwrap_or_else(|e| panic!("{} - {}", expression, e));
+            assert_eq!(filter.to_string(), displayed, "{}", expression);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Moldovan business day calendar, used to pick the rate of the previous business day.
+use std::collections::HashSet;
+use std::path::Path;
+
+use chrono::{Datelike, Duration, NaiveDate, Weekday};
+
+use crate::error::{ParseError, PipelineError};
+
+/// Upper bound of days to look back for a business day, well above any holiday streak.
+const MAX_LOOKBACK_DAYS: usize = 31;
+
+/// The built-in public holidays and weekends, adjusted by the dates of a holidays file.
+#[derive(Default)]
+pub struct HolidayCalendar {
+    /// Extra holidays, e.g. loaded from a file.
+    added: HashSet<NaiveDate>,
+    /// Dates that are business days despite being a weekend or a built-in holiday.
+    removed: HashSet<NaiveDate>,
+}
+
+impl HolidayCalendar {
+    /// Loads a file with one ISO date (YYYY-MM-DD) per line, extending the built-in holidays.
+    /// Dates prefixed with "!" are business days instead (e.g. working Saturdays).
+    /// Empty lines and lines starting with "#" are ignored.
+    pub fn load(path: &Path) -> Result<Self, ParseError> {
+        let content = std::fs::read_to_string(path).map_err(|error| ParseError::HolidaysFile {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let mut calendar = Self::default();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (set, value) = match line.strip_prefix('!') {
+                Some(v) => (&mut calendar.removed, v.trim()),
+                None => (&mut calendar.added, line),
+            };
+            let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|error| {
+                ParseError::FileDate {
+                    path: path.to_path_buf(),
+                    line: i + 1,
+                    value: value.to_string(),
+                    error,
+                }
+            })?;
+            set.insert(date);
+        }
+        Ok(calendar)
+    }
+
+    /// Whether BNM publishes rates for the date, i.e. it's neither a weekend nor a holiday.
+    pub fn is_business_day(&self, date: NaiveDate) -> bool {
+        if self.removed.contains(&date) {
+            return true;
+        }
+        let weekend = matches!(date.weekday(), Weekday::Sat | Weekday::Sun);
+        !weekend && !self.added.contains(&date) && !is_public_holiday(date)
+    }
+
+    /// Returns the date itself if it's a business day, the closest previous business day otherwise.
+    pub fn previous_business_day(&self, date: NaiveDate) -> Result<NaiveDate, PipelineError> {
+        let mut day = date;
+        for days in 0..MAX_LOOKBACK_DAYS {
+            if self.is_business_day(day) {
+                return Ok(day);
+            }
+            day = day
+                .pred_opt()
+                .ok_or(PipelineError::NoBusinessDay { date, days })?;
+        }
+        Err(PipelineError::NoBusinessDay {
+            date,
+            days: MAX_LOOKBACK_DAYS,
+        })
+    }
+}
+
+/// Public holidays of the Republic of Moldova, as defined by the Labour Code.
+fn is_public_holiday(date: NaiveDate) -> bool {
+    let year = date.year();
+    let fixed = match (date.month(), date.day()) {
+        (1, 1) | (1, 7) | (1, 8) | (3, 8) | (5, 1) | (5, 9) | (8, 27) | (8, 31) => true,
+        (6, 1) => year >= 2016,
+        (12, 25) => year >= 2013,
+        _ => false,
+    };
+    if fixed {
+        return true;
+    }
+    let easter = match orthodox_easter(year) {
+        Some(d) => d,
+        None => return false,
+    };
+    // Easter Sunday and Monday, and the Memorial Day ("Paștele Blajinilor") a week later.
+    [0, 1, 8]
+        .iter()
+        .any(|offset| easter + Duration::days(*offset) == date)
+}
+
+/// Orthodox Easter Sunday (Gregorian calendar), using Meeus' Julian algorithm.
+fn orthodox_easter(year: i32) -> Option<NaiveDate> {
+    if year < 1583 {
+        return None;
+    }
+    let a = year % 4;
+    let b = year % 7;
+    let c = year % 19;
+    let d = (19 * c + 15) % 30;
+    let e = (2 * a + 4 * b - d + 34) % 7;
+    let month = (d + e + 114) / 31;
+    let day = (d + e + 114) % 31 + 1;
+    let julian_shift = year / 100 - year / 400 - 2;
+    NaiveDate::from_ymd_opt(year, month as u32, day as u32)
+        .map(|d| d + Duration::days(julian_shift.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(year, month, day)
+    }
+
+    #[test]
+    fn orthodox_easter_dates() {
+        let cases = [
+            (2008, date(2008, 4, 27)),
+            (2010, date(2010, 4, 4)),
+            (2016, date(2016, 5, 1)),
+            (2021, date(2021, 5, 2)),
+            (2022, date(2022, 4, 24)),
+            (2023, date(2023, 4, 16)),
+            (2024, date(2024, 5, 5)),
+            (2025, date(2025, 4, 20)),
+        ];
+        for (year, expected) in cases {
+            assert_eq!(orthodox_easter(year), Some(expected), "{}", year);
+        }
+        assert_eq!(orthodox_easter(1582), None);
+    }
+
+    #[test]
+    fn easter_dependent_holidays() {
+        let calendar = HolidayCalendar::default();
+        // Easter Monday and Memorial Day, the Sunday being a weekend anyway.
+        assert!(!calendar.is_business_day(date(2024, 5, 6)));
+        assert!(!calendar.is_business_day(date(2024, 5, 13)));
+        assert!(calendar.is_business_day(date(2024, 5, 7)));
+        assert!(calendar.is_business_day(date(2024, 5, 14)));
+        assert!(!calendar.is_business_day(date(2023, 4, 17)));
+        assert!(!calendar.is_business_day(date(2023, 4, 24)));
+        assert!(calendar.is_business_day(date(2024, 4, 1)));
+        assert_eq!(
+            calendar.previous_business_day(date(2024, 5, 13)).unwrap(),
+            date(2024, 5, 10)
+        );
+        assert_eq!(
+            calendar.previous_business_day(date(2023, 4, 17)).unwrap(),
+            date(2023, 4, 14)
+        );
+    }
+
+    #[test]
+    fn year_boundaries() {
+        let calendar = HolidayCalendar::default();
+        assert_eq!(
+            calendar.previous_business_day(date(2024, 1, 1)).unwrap(),
+            date(2023, 12, 29)
+        );
+        // Orthodox Christmas, on Sunday and Monday.
+        assert_eq!(
+            calendar.previous_business_day(date(2024, 1, 8)).unwrap(),
+            date(2024, 1, 5)
+        );
+        assert_eq!(
+            calendar.previous_business_day(date(2023, 1, 2)).unwrap(),
+            date(2023, 1, 2)
+        );
+        assert_eq!(
+            calendar.previous_business_day(date(2020, 1, 1)).unwrap(),
+            date(2019, 12, 31)
+        );
+        assert_eq!(
+            calendar.previous_business_day(date(2024, 12, 25)).unwrap(),
+            date(2024, 12, 24)
+        );
+        // Christmas of December 25th is a holiday since 2013.
+        assert!(calendar.is_business_day(date(2012, 12, 25)));
+    }
+
+    #[test]
+    fn fixed_holidays() {
+        let calendar = HolidayCalendar::default();
+        for day in [
+            date(2024, 3, 8),
+            date(2024, 5, 1),
+            date(2024, 5, 9),
+            date(2024, 8, 27),
+            date(2023, 8, 31),
+            date(2023, 6, 1),
+        ] {
+            assert!(!calendar.is_business_day(day), "{}", day);
+        }
+        assert!(calendar.is_business_day(date(2015, 6, 1)));
+    }
+
+    #[test]
+    fn holidays_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            "# Extra holidays\n2024-03-14\n\n!2024-03-16\n ! 2024-01-08 \n"
+        )
+        .unwrap();
+        let calendar = HolidayCalendar::load(file.path()).unwrap();
+        assert!(!calendar.is_business_day(date(2024, 3, 14)));
+        assert!(calendar.is_business_day(date(2024, 3, 16)));
+        assert!(calendar.is_business_day(date(2024, 1, 8)));
+        assert_eq!(
+            calendar.previous_business_day(date(2024, 3, 17)).unwrap(),
+            date(2024, 3, 16)
+        );
+        assert_eq!(
+            calendar.previous_business_day(date(2024, 3, 15)).unwrap(),
+            date(2024, 3, 15)
+        );
+        assert_eq!(
+            calendar.previous_business_day(date(2024, 3, 14)).unwrap(),
+            date(2024, 3, 13)
+        );
+    }
+
+    #[test]
+    fn invalid_holidays_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "2024-03-14\n14.03.2024\n").unwrap();
+        assert!(matches!(
+            HolidayCalendar::load(file.path()),
+            Err(ParseError::FileDate { line: 2, .. })
+        ));
+        assert!(matches!(
+            HolidayCalendar::load(Path::new("/nonexistent/holidays.txt")),
+            Err(ParseError::HolidaysFile { .. })
+        ));
+    }
+}
@@ -0,0 +1,18 @@
+//! Adds the official exchange rates of the National Bank of Moldova (BNM) to the rows of CSV
+//! files, as done by the `bnm-exporter` command.
+//!
+//! - [`rates`] fetches and caches the official rates,
+//! - [`csvproc`] processes the rows, from parsing their dates to assembling the output rows,
+//! - [`filter`] selects the rows to process,
+//! - [`error`] has the errors of all of them.
+pub mod amount;
+pub mod csvproc;
+pub mod error;
+pub mod filter;
+pub mod holidays;
+pub mod locale;
+pub mod rates;
+
+/// Target of the log messages of the rows and the rates, the same as the command's own
+/// messages.
+const LOG_TARGET: &str = "bnm_exporter";
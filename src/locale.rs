@@ -0,0 +1,396 @@
+//! Localized month and weekday names, used to parse and render dates in languages chrono
+//! doesn't support.
+use chrono::{Datelike, NaiveDate};
+use clap::ArgEnum;
+
+/// Language of the month and weekday names of dates.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DateLocale {
+    En,
+    Ro,
+    Ru,
+}
+
+/// English month names, their first three letters are accepted for both `%B` and `%b` by
+/// chrono when parsing.
+const EN_MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+const RO_MONTHS: [&str; 12] = [
+    "ianuarie",
+    "februarie",
+    "martie",
+    "aprilie",
+    "mai",
+    "iunie",
+    "iulie",
+    "august",
+    "septembrie",
+    "octombrie",
+    "noiembrie",
+    "decembrie",
+];
+
+const RO_MONTHS_SHORT: [&str; 12] = [
+    "ian", "feb", "mar", "apr", "mai", "iun", "iul", "aug", "sep", "oct", "noi", "dec",
+];
+
+/// Russian month names in genitive case, as used in dates ("15 марта 2024").
+const RU_MONTHS: [&str; 12] = [
+    "января",
+    "февраля",
+    "марта",
+    "апреля",
+    "мая",
+    "июня",
+    "июля",
+    "августа",
+    "сентября",
+    "октября",
+    "ноября",
+    "декабря",
+];
+
+const RU_MONTHS_SHORT: [&str; 12] = [
+    "янв", "фев", "мар", "апр", "мая", "июн", "июл", "авг", "сен", "окт", "ноя", "дек",
+];
+
+const EN_WEEKDAYS: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+
+const RO_WEEKDAYS: [&str; 7] = [
+    "luni",
+    "marți",
+    "miercuri",
+    "joi",
+    "vineri",
+    "sâmbătă",
+    "duminică",
+];
+
+/// "mar" isn't accepted for Tuesday when parsing, as it's the abbreviation of March.
+const RO_WEEKDAYS_SHORT: [&str; 7] = ["lun", "mar", "mie", "joi", "vin", "sâm", "dum"];
+
+const RU_WEEKDAYS: [&str; 7] = [
+    "понедельник",
+    "вторник",
+    "среда",
+    "четверг",
+    "пятница",
+    "суббота",
+    "воскресенье",
+];
+
+const RU_WEEKDAYS_SHORT: [&str; 7] = ["пн", "вт", "ср", "чт", "пт", "сб", "вс"];
+
+/// Additional spellings accepted when parsing, besides the names above.
+const RO_MONTHS_ALIASES: [(&str, u32); 3] = [("mart", 3), ("sept", 9), ("nov", 11)];
+
+const RU_MONTHS_ALIASES: [(&str, u32); 16] = [
+    ("январь", 1),
+    ("февраль", 2),
+    ("март", 3),
+    ("апрель", 4),
+    ("май", 5),
+    ("июнь", 6),
+    ("июль", 7),
+    ("август", 8),
+    ("сентябрь", 9),
+    ("октябрь", 10),
+    ("ноябрь", 11),
+    ("декабрь", 12),
+    ("февр", 2),
+    ("апрел", 4),
+    ("сент", 9),
+    ("нояб", 11),
+];
+
+impl DateLocale {
+    /// Full month name, `month` starts from 1.
+    pub fn month_name(&self, month: u32) -> &'static str {
+        let index = month as usize - 1;
+        match self {
+            DateLocale::En => EN_MONTHS[index],
+            DateLocale::Ro => RO_MONTHS[index],
+            DateLocale::Ru => RU_MONTHS[index],
+        }
+    }
+
+    /// Abbreviated month name, `month` starts from 1.
+    pub fn month_short_name(&self, month: u32) -> &'static str {
+        let index = month as usize - 1;
+        match self {
+            DateLocale::En => &EN_MONTHS[index][..3],
+            DateLocale::Ro => RO_MONTHS_SHORT[index],
+            DateLocale::Ru => RU_MONTHS_SHORT[index],
+        }
+    }
+
+    /// Full weekday name, `weekday` starts from 0 (Monday).
+    pub fn weekday_name(&self, weekday: u32) -> &'static str {
+        let index = weekday as usize;
+        match self {
+            DateLocale::En => EN_WEEKDAYS[index],
+            DateLocale::Ro => RO_WEEKDAYS[index],
+            DateLocale::Ru => RU_WEEKDAYS[index],
+        }
+    }
+
+    /// Abbreviated weekday name, `weekday` starts from 0 (Monday).
+    pub fn weekday_short_name(&self, weekday: u32) -> &'static str {
+        let index = weekday as usize;
+        match self {
+            DateLocale::En => &EN_WEEKDAYS[index][..3],
+            DateLocale::Ro => RO_WEEKDAYS_SHORT[index],
+            DateLocale::Ru => RU_WEEKDAYS_SHORT[index],
+        }
+    }
+
+    /// Looks up a localized month name or abbreviation (case and diacritics insensitive).
+    fn parse_month(&self, name: &str) -> Option<u32> {
+        let (full, short, aliases): (&[&str], &[&str], &[(&str, u32)]) = match self {
+            DateLocale::En => return None,
+            DateLocale::Ro => (&RO_MONTHS, &RO_MONTHS_SHORT, &RO_MONTHS_ALIASES),
+            DateLocale::Ru => (&RU_MONTHS, &RU_MONTHS_SHORT, &RU_MONTHS_ALIASES),
+        };
+        full.iter()
+            .chain(short.iter())
+            .position(|m| normalize(m) == name)
+            .map(|i| (i % 12) as u32 + 1)
+            .or_else(|| {
+                aliases
+                    .iter()
+                    .find(|(alias, _)| *alias == name)
+                    .map(|(_, m)| *m)
+            })
+    }
+
+    /// Looks up a localized weekday name or abbreviation, returns the index starting from Monday.
+    fn parse_weekday(&self, name: &str) -> Option<usize> {
+        let (full, short): (&[&str], &[&str]) = match self {
+            DateLocale::En => return None,
+            DateLocale::Ro => (&RO_WEEKDAYS, &RO_WEEKDAYS_SHORT),
+            DateLocale::Ru => (&RU_WEEKDAYS, &RU_WEEKDAYS_SHORT),
+        };
+        full.iter()
+            .chain(short.iter())
+            .position(|d| normalize(d) == name)
+            .map(|i| i % 7)
+    }
+
+    /// Replaces localized month and weekday names in the value with abbreviated English ones,
+    /// so it can be parsed by chrono with either the full or the abbreviated specifiers.
+    /// A dot right after an abbreviated name (e.g. "mart.") is dropped as well.
+    pub fn translate(&self, value: &str) -> String {
+        if *self == DateLocale::En {
+            return value.to_string();
+        }
+        let mut out = String::with_capacity(value.len());
+        let mut chars = value.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            if !c.is_alphabetic() {
+                out.push(c);
+                continue;
+            }
+            let mut end = start + c.len_utf8();
+            while let Some((i, c)) = chars.peek().copied() {
+                if !c.is_alphabetic() {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let word = &value[start..end];
+            let name = normalize(word);
+            let translated = self
+                .parse_month(&name)
+                .map(|m| &EN_MONTHS[m as usize - 1][..3])
+                .or_else(|| self.parse_weekday(&name).map(|d| &EN_WEEKDAYS[d][..3]));
+            match translated {
+                Some(translated) => {
+                    out.push_str(translated);
+                    if matches!(chars.peek(), Some((_, '.'))) {
+                        chars.next();
+                    }
+                }
+                None => out.push_str(word),
+            }
+        }
+        out
+    }
+
+    /// Replaces `%B`, `%b` (`%h`), `%A` and `%a` in a chrono format string with localized
+    /// month and weekday names of the date.
+    pub fn localize_format(&self, format: &str, date: &NaiveDate) -> String {
+        if *self == DateLocale::En {
+            return format.to_string();
+        }
+        let mut out = String::with_capacity(format.len());
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('B') => out.push_str(self.month_name(date.month())),
+                Some('b' | 'h') => out.push_str(self.month_short_name(date.month())),
+                Some('A') => out.push_str(self.weekday_name(date.weekday().num_days_from_monday())),
+                Some('a') => {
+                    out.push_str(self.weekday_short_name(date.weekday().num_days_from_monday()))
+                }
+                Some(c) => {
+                    out.push('%');
+                    out.push(c);
+                }
+                None => out.push('%'),
+            }
+        }
+        out
+    }
+}
+
+/// Lowercases the name and strips Romanian diacritics (both comma and cedilla variants).
+fn normalize(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'ă' | 'â' => 'a',
+            'î' => 'i',
+            'ș' | 'ş' => 's',
+            'ț' | 'ţ' => 't',
+            'ё' => 'е',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(locale: DateLocale, value: &str, format: &str) -> Option<NaiveDate> {
+        NaiveDate::parse_from_str(&locale.translate(value), format).ok()
+    }
+
+    #[test]
+    fn parses_localized_month_names() {
+        let march = NaiveDate::from_ymd(2024, 3, 15);
+        let cases = [
+            (DateLocale::Ro, "15 martie 2024", "%d %B %Y"),
+            (DateLocale::Ro, "15 Martie 2024", "%d %B %Y"),
+            (DateLocale::Ro, "15 MARTIE 2024", "%d %B %Y"),
+            (DateLocale::Ro, "15 mar 2024", "%d %b %Y"),
+            (DateLocale::Ro, "15 Mart. 2024", "%d %b %Y"),
+            (DateLocale::Ro, "15-mart-2024", "%d-%b-%Y"),
+            (DateLocale::Ru, "15 марта 2024", "%d %B %Y"),
+            (DateLocale::Ru, "15 МАРТА 2024", "%d %B %Y"),
+            (DateLocale::Ru, "15 март 2024", "%d %B %Y"),
+            (DateLocale::Ru, "15 мар. 2024", "%d %b %Y"),
+            (DateLocale::En, "15 March 2024", "%d %B %Y"),
+            (DateLocale::En, "15 mar 2024", "%d %b %Y"),
+        ];
+        for (locale, value, format) in cases {
+            assert_eq!(
+                parse(locale, value, format),
+                Some(march),
+                "{:?} {}",
+                locale,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_romanian_diacritics_variants() {
+        let saturday = NaiveDate::from_ymd(2024, 3, 16);
+        for value in ["sâmbătă", "sambata", "SÂMBĂTĂ", "Sâmbăta"] {
+            let value = format!("{}, 16 martie 2024", value);
+            assert_eq!(
+                parse(DateLocale::Ro, &value, "%A, %d %B %Y"),
+                Some(saturday),
+                "{}",
+                value
+            );
+        }
+        // Comma below and cedilla variants of ș and ț.
+        let tuesday = NaiveDate::from_ymd(2024, 3, 12);
+        for value in ["marți", "marţi", "marti"] {
+            let value = format!("{} 12.03.2024", value);
+            assert_eq!(
+                parse(DateLocale::Ro, &value, "%A %d.%m.%Y"),
+                Some(tuesday),
+                "{}",
+                value
+            );
+        }
+        let august = NaiveDate::from_ymd(2024, 8, 27);
+        assert_eq!(
+            parse(DateLocale::Ru, "27 авгУСТА 2024", "%d %B %Y"),
+            Some(august)
+        );
+    }
+
+    #[test]
+    fn keeps_other_words() {
+        assert_eq!(DateLocale::Ro.translate("15.03.2024"), "15.03.2024");
+        assert_eq!(DateLocale::Ro.translate("plata 15 martie"), "plata 15 Mar");
+        assert_eq!(DateLocale::Ru.translate("маржа"), "маржа");
+    }
+
+    #[test]
+    fn localizes_output_formats() {
+        let date = NaiveDate::from_ymd(2024, 3, 15);
+        let cases = [
+            (DateLocale::En, "%d %B %Y", "%d %B %Y"),
+            (DateLocale::Ro, "%d %B %Y", "%d martie %Y"),
+            (DateLocale::Ro, "%a %d %b", "vin %d mar"),
+            (DateLocale::Ru, "%A, %d %B", "пятница, %d марта"),
+            (DateLocale::Ru, "%h %%B", "мар %%B"),
+        ];
+        for (locale, format, expected) in cases {
+            assert_eq!(locale.localize_format(format, &date), expected);
+        }
+    }
+
+    #[test]
+    fn round_trips_all_months() {
+        for locale in [DateLocale::En, DateLocale::Ro, DateLocale::Ru] {
+            for month in 1..=12 {
+                let date = NaiveDate::from_ymd(2024, month, 15);
+                // "%a" isn't round-tripped, the Romanian "mar" is read as March.
+                for format in ["%d %B %Y", "%d %b %Y", "%A %d %B %Y", "%A %d %b %Y"] {
+                    let rendered = date
+                        .format(&locale.localize_format(format, &date))
+                        .to_string();
+                    assert_eq!(
+                        parse(locale, &rendered, format),
+                        Some(date),
+                        "{:?} {}",
+                        locale,
+                        rendered
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Logger setup, with a plain text and a JSON (one object per line) format.
+//! Log sites attach context such as the row, the date or the failure category as key-values,
+//! which are written as fields in JSON format.
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use chrono::{SecondsFormat, Utc};
+use clap::ArgEnum;
+use eyre::{eyre, Result};
+use log::kv::{Error, Key, Value, VisitSource};
+use log::LevelFilter;
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Configures the log level from the verbosity flags (see [`level`]), unless RUST_LOG is set.
+/// Messages of the dependencies are logged from the warning level, or with "-vvv".
+/// Messages are appended to the log file as well if it's set, after a header line
+/// with the time and the arguments of the run.
+pub fn init(verbose: usize, quiet: usize, format: LogFormat, file: Option<&Path>) -> Result<()> {
+    let mut builder = match std::env::var_os("RUST_LOG") {
+        Some(_) => env_logger::Builder::from_default_env(),
+        None => {
+            let level = level(verbose, quiet);
+            let mut builder = env_logger::Builder::new();
+            match level {
+                LevelFilter::Trace => builder.filter_level(level),
+                _ => builder
+                    .filter_level(level.min(LevelFilter::Warn))
+                    .filter_module(env!("CARGO_CRATE_NAME"), level),
+            };
+            builder
+        }
+    };
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let mut line = format!(
+                "{{\"timestamp\":{},\"level\":{},\"target\":{},\"msg\":{}",
+                json_string(&Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+                json_string(record.level().as_str()),
+                json_string(record.target()),
+                json_string(&record.args().to_string())
+            );
+            let _ = record.key_values().visit(&mut JsonFields(&mut line));
+            line.push('}');
+            writeln!(buf, "{}", line)
+        });
+    }
+    if let Some(path) = file {
+        let mut file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| eyre!("Failed to open log file {} - {}", path.display(), e))?;
+        writeln!(
+            file,
+            "--- {} {}",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            std::env::args().collect::<Vec<_>>().join(" ")
+        )
+        .map_err(|e| eyre!("Failed to write log file {} - {}", path.display(), e))?;
+        builder.target(env_logger::Target::Pipe(Box::new(Tee(file))));
+    }
+    builder.init();
+    Ok(())
+}
+
+/// Level of the messages logged: warnings and errors by default (as with "-q"), info ones with
+/// "-v", debug ones with "-vv", trace ones with "-vvv", and only errors with "-qq".
+pub fn level(verbose: usize, quiet: usize) -> LevelFilter {
+    match (verbose, quiet) {
+        (0, 0) | (_, 1) => LevelFilter::Warn,
+        (1, 0) => LevelFilter::Info,
+        (2, 0) => LevelFilter::Debug,
+        (_, 0) => LevelFilter::Trace,
+        _ => LevelFilter::Error,
+    }
+}
+
+/// Writes to both STDERR and the file.
+struct Tee(File);
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        std::io::stderr().write_all(buf)?;
+        self.0.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()?;
+        self.0.flush()
+    }
+}
+
+/// Appends the key-values of a record as JSON fields.
+struct JsonFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let value = match (value.to_u64(), value.to_i64(), value.to_bool()) {
+            (Some(v), _, _) => v.to_string(),
+            (_, Some(v), _) => v.to_string(),
+            (_, _, Some(v)) => v.to_string(),
+            _ => json_string(&value.to_string()),
+        };
+        self.0
+            .push_str(&format!(",{}:{}", json_string(key.as_str()), value));
+        Ok(())
+    }
+}
+
+/// Quotes the value as a JSON string.
+pub fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbosity_levels() {
+        let cases = [
+            ((0, 0), LevelFilter::Warn),
+            ((1, 0), LevelFilter::Info),
+            ((2, 0), LevelFilter::Debug),
+            ((3, 0), LevelFilter::Trace),
+            ((5, 0), LevelFilter::Trace),
+            ((0, 1), LevelFilter::Warn),
+            ((0, 2), LevelFilter::Error),
+            ((0, 3), LevelFilter::Error),
+        ];
+        for ((verbose, quiet), expected) in cases {
+            assert_eq!(
+                level(verbose, quiet),
+                expected,
+                "-v {} -q {}",
+                verbose,
+                quiet
+            );
+        }
+    }
+}
@@ -1,304 +1,2540 @@
-use std::collections::HashMap;
-
-use chrono::NaiveDate;
-use clap::Parser;
-use csv::{Reader, StringRecord, Writer, WriterBuilder};
-use eyre::{eyre, Result};
-use futures::future::join_all;
-use lazy_static::lazy_static;
+use std::ffi::OsStr;
+use std::io::{Read as _, Write as _};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use chrono::{NaiveDate, Utc};
+use chrono_tz::{Europe::Chisinau, Tz};
+use clap::{ArgEnum, Args, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use csv::{Position, Reader, StringRecord, Writer, WriterBuilder};
+use eyre::{eyre, Result, WrapErr};
+use futures::StreamExt;
 use regex::Regex;
-use reqwest::StatusCode;
-use tokio::{
-    fs::read,
-    io::{stdin, AsyncReadExt},
+use rust_decimal::Decimal;
+
+mod logging;
+
+use bnm_exporter::amount::{self, AmountFormat, Rounding};
+use bnm_exporter::csvproc::{
+    check_out_headers, dedupe_records, detect_delimiter, fetch_exchange_rate, format_number,
+    get_column_index, get_column_indexes, get_out_headers, plan_row, read_records, request_dates,
+    row_line, AmountColumns, ConvertDirection, DateFormatter, DateParser, DateSource, DedupeKeep,
+    FailedRow, FilterMode, GroupTotals, OnEmptyDate, OnFutureDate, OnInvalidAmount, OnRateError,
+    OutColumn, OutLayout, OutValue, PipelineConfig, PipelineConfigBuilder, RateDateMode,
+    RateDateResolver, RateMode, RowContext, RowPlan, RowStatus, RunOptions, RunStats, StatsFormat,
+    CURRENCY, DEFAULT_DATE_FORMAT, DEFAULT_EXCHANGE_COLUMN, DEFAULT_MIN_DATE,
 };
+use bnm_exporter::error::{FetchError, PipelineError, RunError};
+use bnm_exporter::filter::{DateFilterOptions, FilterOptions, FilterSet};
+use bnm_exporter::holidays::HolidayCalendar;
+use bnm_exporter::locale::DateLocale;
+use bnm_exporter::rates::{is_currency_code, BnmClient, OfficialRate, RateTable, StaticRates};
+use logging::LogFormat;
+
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0    Success
+    1    Invalid options or configuration (e.g. an unknown column)
+    2    Invalid input (e.g. an unreadable file, or a failed row with --on-rate-error fail)
+    3    Network or BNM errors (e.g. a failed request, or a rate BNM didn't publish)
+    4    Partial success, the output was written without the rows which failed
+    5    Rows failed with --strict, the output was written (with or without them),
+         or none matched the filters and nothing was written
+    130  Interrupted with Ctrl-C, the rows processed until then were written";
+
+/// How long the pending rows are waited for after Ctrl-C, before writing the processed ones.
+const INTERRUPT_GRACE: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Outcome of a run, mapped to the process exit code.
+/// The codes must be kept stable, as scripts rely on them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RunOutcome {
+    Success = 0,
+    Usage = 1,
+    Input = 2,
+    Provider = 3,
+    Partial = 4,
+    Strict = 5,
+    Interrupted = 130,
+}
+
+impl RunOutcome {
+    /// Outcome of a run which failed with the error.
+    fn of(e: &eyre::Report) -> Self {
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<RunError>() {
+                return match (e, e.status()) {
+                    (RunError::Create(_), _) => RunOutcome::Usage,
+                    (_, Some(status)) => RunOutcome::of_status(status),
+                    (_, None) => RunOutcome::Input,
+                };
+            }
+            if cause.is::<FetchError>() || cause.is::<reqwest::Error>() {
+                return RunOutcome::Provider;
+            }
+            if let Some(e) = cause.downcast_ref::<PipelineError>() {
+                return RunOutcome::of_status(e.status());
+            }
+            if cause.is::<csv::Error>() || cause.is::<std::io::Error>() {
+                return RunOutcome::Input;
+            }
+        }
+        RunOutcome::Usage
+    }
+
+    /// Outcome of a run which failed with rows of the status.
+    fn of_status(status: RowStatus) -> Self {
+        match status {
+            RowStatus::RateMissing | RowStatus::NetworkError => RunOutcome::Provider,
+            _ => RunOutcome::Input,
+        }
+    }
+}
 
-lazy_static! {
-    static ref CURRENCY_CACHE: tokio::sync::RwLock<HashMap<String, f64>> =
-        tokio::sync::RwLock::new(HashMap::new());
+impl From<RunOutcome> for std::process::ExitCode {
+    fn from(outcome: RunOutcome) -> Self {
+        std::process::ExitCode::from(outcome as u8)
+    }
 }
 
 #[derive(Debug, Parser)]
+#[clap(
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    after_help = EXIT_CODES_HELP
+)]
 /// CLI helper which parses a CSV file and adds BNM exchange rates for corresponding date.
+/// The "convert" command is run if no command is given, e.g. "bnm-exporter -i file.csv -d Date".
 struct OptionsParser {
-    #[clap(long = "in-file", short = 'i', parse(from_os_str))]
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(
+        long = "verbose",
+        env = "BNM_VERBOSE",
+        short = 'v',
+        parse(from_occurrences),
+        global = true,
+        conflicts_with = "quiet"
+    )]
+    /// Log more details to STDERR: "-v" for info messages (e.g. the run summary), "-vv" for
+    /// debug ones, "-vvv" for trace ones (including the HTTP client's). By default warnings
+    /// (e.g. skipped rows) and errors are logged.
+    /// The RUST_LOG environment variable takes precedence when set.
+    verbose: usize,
+
+    #[clap(
+        long = "quiet",
+        env = "BNM_QUIET",
+        short = 'q',
+        parse(from_occurrences),
+        global = true
+    )]
+    /// Log less to STDERR: "-q" for warnings (e.g. skipped rows) and errors only, as by
+    /// default, "-qq" for errors only.
+    quiet: usize,
+
+    #[clap(
+        long = "log-format",
+        env = "BNM_LOG_FORMAT",
+        arg_enum,
+        default_value = "text",
+        global = true
+    )]
+    /// Format of the log messages, "json" writes an object per line with "timestamp",
+    /// "level", "target" and "msg" fields, as well as the context of the message when
+    /// available (e.g. "row", "date" and "category" of failed rows).
+    log_format: LogFormat,
+
+    #[clap(
+        long = "log-file",
+        env = "BNM_LOG_FILE",
+        parse(from_os_str),
+        global = true
+    )]
+    /// File the log messages are appended to, in addition to STDERR (at the same level and
+    /// format). Each run starts with a line with its time and arguments.
+    log_file: Option<std::path::PathBuf>,
+
+    #[clap(
+        long = "rates-file",
+        env = "BNM_RATES_FILE",
+        parse(from_os_str),
+        global = true
+    )]
+    /// Read the rates from a CSV file rather than requesting them from BNM, e.g. one written
+    /// by the "range" command: a header with "Date" and currency codes, and a row per date
+    /// (ISO format) with the rates of one unit. Dates missing from the file, or with an empty
+    /// rate, have no published rate.
+    rates_file: Option<std::path::PathBuf>,
+
+    #[clap(
+        long = "request-timeout",
+        env = "BNM_REQUEST_TIMEOUT",
+        default_value = "30",
+        global = true
+    )]
+    /// Time limit of each request to BNM in seconds, up to the end of the response.
+    request_timeout: u64,
+
+    #[clap(
+        long = "retries",
+        env = "BNM_RETRIES",
+        default_value = "2",
+        global = true
+    )]
+    /// Number of times a request to BNM is repeated after a network error (including a
+    /// timeout) or a server error, waiting 1 second before the first retry and twice as long
+    /// before each next one.
+    retries: usize,
+
+    #[clap(flatten)]
+    convert: ConvertArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Add exchange rates to the rows of a CSV file (the default command).
+    Convert(Box<ConvertArgs>),
+    /// Print the official rates of a date.
+    Rate(RateArgs),
+    /// Write the official rates of a date range to a CSV file, a row per date.
+    Range(RangeArgs),
+    /// Convert an amount with the official rates of a date.
+    Calc(CalcArgs),
+    /// Print how the input file is read with the "convert" options, without fetching any rate.
+    /// Options not related to the input are ignored.
+    Inspect(Box<ConvertArgs>),
+    /// Validate the input file and the "convert" options without fetching any rate,
+    /// printing the rows which would fail and exiting with an error if there are any.
+    Check(Box<ConvertArgs>),
+    /// Measure the throughput of "convert" on a generated file, with generated rates.
+    #[clap(hide = true)]
+    Bench(BenchArgs),
+    /// Print a shell completion script, e.g. "bnm-exporter completions zsh > _bnm-exporter".
+    Completions {
+        #[clap(arg_enum)]
+        shell: Shell,
+    },
+}
+
+/// Number of rows printed by the inspect command.
+const INSPECT_ROWS: usize = 5;
+
+/// Currency the official rates are in.
+const LOCAL_CURRENCY: &str = "MDL";
+
+#[derive(Debug, Args)]
+struct CalcArgs {
+    /// Amount to convert, e.g. "250" or "1,234.56".
+    #[clap(allow_hyphen_values = true)]
+    amount: String,
+
+    /// Currency code of the amount (e.g. "USD"), "MDL" to convert from MDL.
+    currency: String,
+
+    #[clap(long = "date", env = "BNM_DATE")]
+    /// Date (ISO format) of the rates.
+    date: NaiveDate,
+
+    #[clap(long = "to", env = "BNM_CALC_TO", default_value = LOCAL_CURRENCY)]
+    /// Currency code the amount is converted to, other currencies than MDL are
+    /// cross-converted through MDL.
+    to: String,
+
+    #[clap(long = "amount-precision", env = "BNM_AMOUNT_PRECISION")]
+    /// Number of decimals of the converted amount (e.g. 2), all of them are kept if not set.
+    amount_precision: Option<usize>,
+
+    #[clap(
+        long = "rounding",
+        env = "BNM_ROUNDING",
+        arg_enum,
+        default_value = "half-up"
+    )]
+    /// How the converted amount is rounded to the precision, same as for CSV files.
+    rounding: Rounding,
+
+    #[clap(long = "json", env = "BNM_JSON")]
+    /// Print a JSON object, e.g.
+    /// {"date":"2024-03-15","amount":250,"currency":"USD","converted":4425,"to":"MDL"}.
+    json: bool,
+}
+
+#[derive(Debug, Args)]
+struct BenchArgs {
+    #[clap(long = "rows", env = "BNM_BENCH_ROWS", default_value = "200000")]
+    /// Number of rows of the generated file.
+    rows: usize,
+
+    #[clap(long = "dates", env = "BNM_BENCH_DATES", default_value = "30")]
+    /// Number of distinct dates of the rows, consecutive days from 2024-01-01.
+    dates: usize,
+}
+
+#[derive(Debug, Args)]
+struct RangeArgs {
+    #[clap(long = "from", env = "BNM_RANGE_FROM")]
+    /// First date (ISO format) of the range.
+    from: NaiveDate,
+
+    #[clap(long = "to", env = "BNM_RANGE_TO")]
+    /// Last date (ISO format) of the range, included.
+    to: NaiveDate,
+
+    #[clap(
+        long = "currency", env = "BNM_CURRENCY",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        default_value = CURRENCY
+    )]
+    /// Currency code (e.g. "EUR"), can be repeated to add a column per currency,
+    /// in the same order. Rates are written per one unit of the currency.
+    currency: Vec<String>,
+
+    #[clap(long = "include-missing", env = "BNM_INCLUDE_MISSING")]
+    /// Write the dates BNM didn't publish rates for with empty rates,
+    /// rather than skipping them with a warning.
+    include_missing: bool,
+
+    #[clap(long = "out-date-format", env = "BNM_OUT_DATE_FORMAT")]
+    /// Date format of the date column, ISO format (%Y-%m-%d) if not provided.
+    out_date_format: Option<String>,
+
+    #[clap(
+        long = "out-column-delimiter",
+        env = "BNM_OUT_COLUMN_DELIMITER",
+        default_value = ","
+    )]
+    /// Column delimiter of the output CSV file.
+    out_column_delimiter: char,
+
+    #[clap(long = "out-file", env = "BNM_OUT_FILE", short = 'o')]
+    /// Path to the output CSV file. If omitted will be printed to STDOUT.
+    out_file: Option<std::path::PathBuf>,
+
+    #[clap(
+        long = "max-concurrency",
+        env = "BNM_MAX_CONCURRENCY",
+        default_value = "32"
+    )]
+    /// Maximum number of dates requested at once.
+    max_concurrency: usize,
+}
+
+#[derive(Debug, Args)]
+struct RateArgs {
+    #[clap(long = "date", env = "BNM_DATE")]
+    /// Date (ISO format) of the rate.
+    date: NaiveDate,
+
+    #[clap(
+        long = "currency", env = "BNM_CURRENCY",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        default_value = CURRENCY
+    )]
+    /// Currency code (e.g. "EUR"), can be repeated to print a rate per line, in the same order.
+    /// The rate of one unit is printed, even for currencies BNM publishes the rate of
+    /// a nominal amount of (e.g. 100 RUB).
+    currency: Vec<String>,
+
+    #[clap(long = "json", env = "BNM_JSON")]
+    /// Print a JSON object per currency, e.g. {"date":"2024-02-29","currency":"USD","rate":17.7}.
+    json: bool,
+}
+
+#[derive(Debug, Args)]
+struct ConvertArgs {
+    #[clap(
+        long = "in-file",
+        env = "BNM_IN_FILE",
+        short = 'i',
+        parse(from_os_str),
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
     /// Path to the input file in CSV format.
     /// By default the file is expected to have headers as the first row.
     /// If the input file is not provided, the content will be read from STDIN.
-    in_file: Option<std::path::PathBuf>,
+    /// Can be repeated (e.g. for monthly statements), the files must have the same headers.
+    /// They're read at once, and their rows are written one file after the other,
+    /// in the same order. "inspect" and "check" read the first file only.
+    in_file: Vec<std::path::PathBuf>,
 
-    #[clap(long = "in-no-headers")]
+    #[clap(long = "in-no-headers", env = "BNM_IN_NO_HEADERS")]
     /// Must be set, in case the CSV file has no headers.
     /// In case CSV file has no headers, all options that allow specifying a column
     /// are interpreted as indexes (starting from 0).
     in_no_headers: bool,
 
-    #[clap(long = "in-date-format", default_value = "%m/%d/%Y")]
+    #[clap(
+        long = "headers",
+        env = "BNM_HEADERS",
+        arg_enum,
+        conflicts_with = "in-no-headers"
+    )]
+    /// Whether the first row is headers, "no" is the same as --in-no-headers.
+    /// "auto" reads the first row as data if the date column given as an index has a date
+    /// in it, and as headers otherwise.
+    headers: Option<Headers>,
+
+    #[clap(long = "pad-short-rows", env = "BNM_PAD_SHORT_ROWS")]
+    /// Pad rows with fewer fields than the headers with empty cells, rather than failing
+    /// them if they're too short to have a date ("ragged-row" status).
+    /// Ignored for files without headers.
+    pad_short_rows: bool,
+
+    #[clap(
+        long = "in-date-format",
+        env = "BNM_IN_DATE_FORMAT",
+        default_value = DEFAULT_DATE_FORMAT
+    )]
     /// Date format of the input CSV file.
+    /// ISO week dates are supported as well (e.g. "%G-W%V-%u" for "2024-W11-5").
+    /// Use "excel-serial" for Excel serial date numbers (e.g. 45366).
     in_date_format: String,
 
-    #[clap(long = "in-column-delimiter", default_value = ",")]
+    #[clap(
+        long = "in-date-locale",
+        env = "BNM_IN_DATE_LOCALE",
+        arg_enum,
+        default_value = "en"
+    )]
+    /// Language of month names in the input dates (e.g. "15 martie 2024" with "%d %B %Y").
+    /// Matching is case-insensitive and a dot after abbreviated names ("mart.") is ignored.
+    /// Weekday names ("%A"/"%a") are translated as well.
+    in_date_locale: DateLocale,
+
+    #[clap(long = "in-year-pivot", env = "BNM_IN_YEAR_PIVOT")]
+    /// Pivot for two-digit years ("%y", or "%g" for ISO week dates), between 0 and 100.
+    /// Years below the pivot are mapped to 20xx, the rest to 19xx.
+    /// By default chrono's mapping is used (00-69 -> 20xx, 70-99 -> 19xx).
+    /// Ignored when the input date format has no two-digit year (e.g. full years with "%Y").
+    in_year_pivot: Option<u32>,
+
+    #[clap(long = "in-date-regex", env = "BNM_IN_DATE_REGEX")]
+    /// Regex used to extract the date from the date column cell (e.g. "TRX/(\d{4}-\d{2}-\d{2})/").
+    /// The first capture group is parsed using the input date format.
+    in_date_regex: Option<String>,
+
+    #[clap(long = "in-timezone", env = "BNM_IN_TIMEZONE")]
+    /// Timezone (IANA name, e.g. "UTC") of timestamps in the date column.
+    /// The input date format must contain the time of day, e.g. "%Y-%m-%d %H:%M:%S".
+    /// Timestamps are converted to the target timezone before taking the date.
+    /// Formats with an UTC offset ("%z") are converted even without this option.
+    in_timezone: Option<Tz>,
+
+    #[clap(
+        long = "target-timezone",
+        env = "BNM_TARGET_TIMEZONE",
+        default_value = "Europe/Chisinau"
+    )]
+    /// Timezone used to get the transaction date from timestamps.
+    target_timezone: Tz,
+
+    #[clap(
+        long = "in-column-delimiter",
+        env = "BNM_IN_COLUMN_DELIMITER",
+        default_value = ","
+    )]
     /// Column delimiter of the input CSV file.
     in_column_delimiter: char,
 
-    #[clap(long = "in-date-column", short = 'd')]
+    #[clap(
+        long = "in-date-column",
+        env = "BNM_IN_DATE_COLUMN",
+        short = 'd',
+        required_unless_present = "in-date-parts"
+    )]
     /// In case the input CSV file has header, it's used as header name.
     /// Otherwise it's used as an index (starting from 0).
-    in_date_column: String,
+    /// A comma-separated list of columns (e.g. "Booking Date,Transaction Date") is tried in
+    /// order, the first non-empty cell with a valid date is used.
+    in_date_column: Option<String>,
+
+    #[clap(
+        long = "in-date-parts",
+        env = "BNM_IN_DATE_PARTS",
+        conflicts_with = "in-date-column"
+    )]
+    /// Comma-separated year, month and day columns (e.g. "An,Luna,Zi") with numeric values,
+    /// used instead of a date column. In this mode the date format options and
+    /// "--out-date-format" are ignored, and the three columns are written unchanged.
+    in_date_parts: Option<String>,
+
+    #[clap(
+        long = "date-offset",
+        env = "BNM_DATE_OFFSET",
+        default_value = "0",
+        allow_hyphen_values = true
+    )]
+    /// Number of days (negative or positive) added to the transaction date to get the date
+    /// of the exchange rate, e.g. -1 uses the rate of the day before the transaction.
+    /// The output date column keeps the transaction date.
+    date_offset: i32,
+
+    #[clap(
+        long = "rate-date",
+        env = "BNM_RATE_DATE",
+        arg_enum,
+        default_value = "exact"
+    )]
+    /// Which date's exchange rate is used.
+    /// "previous-business-day" uses the rate of the last business day, when the transaction
+    /// (after the date offset) falls on a weekend or a Moldovan public holiday.
+    rate_date: RateDateMode,
+
+    #[clap(
+        long = "rate-mode",
+        env = "BNM_RATE_MODE",
+        arg_enum,
+        default_value = "daily"
+    )]
+    /// "monthly-average" uses the average of BNM's rates over the calendar month of the
+    /// rate date, rounded to 4 decimals, e.g. for simplified tax regimes.
+    /// All the days of the month up to today are averaged, weekends included as BNM publishes
+    /// a rate for every day, days without a published rate are skipped.
+    /// The rate date column then contains the month (e.g. "2024-03"), and the rate change
+    /// is computed versus the previous month's average.
+    rate_mode: RateMode,
+
+    #[clap(
+        long = "on-future-date",
+        env = "BNM_ON_FUTURE_DATE",
+        arg_enum,
+        default_value = "warn-skip"
+    )]
+    /// What to do with rows whose rate date is after today (in Europe/Chisinau timezone):
+    /// abort with an error, skip the row with a warning, or use the latest (today's) rate.
+    on_future_date: OnFutureDate,
+
+    #[clap(
+        long = "on-empty-date",
+        env = "BNM_ON_EMPTY_DATE",
+        arg_enum,
+        default_value = "blank"
+    )]
+    /// What to do with rows having an empty date cell (e.g. pending transactions):
+    /// skip them silently, keep them with an empty exchange rate, or treat them as failed.
+    on_empty_date: OnEmptyDate,
+
+    #[clap(
+        long = "on-rate-error",
+        env = "BNM_ON_RATE_ERROR",
+        arg_enum,
+        default_value = "drop"
+    )]
+    /// What to do with rows the exchange rate couldn't be added to (e.g. an invalid date or
+    /// a failed request): drop them with a warning, keep them with an empty exchange rate,
+    /// or abort naming the first failing row.
+    on_rate_error: OnRateError,
+
+    #[clap(
+        long = "fail-fast",
+        env = "BNM_FAIL_FAST",
+        conflicts_with = "on-rate-error"
+    )]
+    /// Shorthand for "--on-rate-error fail": abort on the first row the exchange rate
+    /// couldn't be added to, cancelling the pending requests.
+    fail_fast: bool,
+
+    #[clap(
+        long = "keep-failed",
+        env = "BNM_KEEP_FAILED",
+        conflicts_with_all = &["on-rate-error", "fail-fast"]
+    )]
+    /// Shorthand for "--on-rate-error blank": write the rows the exchange rate couldn't be
+    /// added to unchanged, with empty added cells.
+    keep_failed: bool,
+
+    #[clap(long = "max-errors", env = "BNM_MAX_ERRORS")]
+    /// Stop processing with an error once more rows than this failed (e.g. invalid dates or
+    /// failed requests), cancelling the pending requests. Unlimited if not set.
+    max_errors: Option<usize>,
 
-    #[clap(long = "out-file", short = 'o')]
+    #[clap(
+        long = "keep-partial",
+        env = "BNM_KEEP_PARTIAL",
+        requires = "max-errors"
+    )]
+    /// Write the rows processed before "--max-errors" was exceeded, the run still fails.
+    keep_partial: bool,
+
+    #[clap(long = "error-report", env = "BNM_ERROR_REPORT", parse(from_os_str))]
+    /// File listing the rows the exchange rate couldn't be added to, with their line,
+    /// failure category (same as the status column), message and original fields.
+    /// It's written on every run, empty if no row failed.
+    error_report: Option<std::path::PathBuf>,
+
+    #[clap(
+        long = "error-report-format",
+        env = "BNM_ERROR_REPORT_FORMAT",
+        arg_enum,
+        default_value = "jsonl",
+        requires = "error-report"
+    )]
+    /// "jsonl" writes an object per row, e.g.
+    /// {"line":3,"category":"date-parse-error","message":"...","fields":["bad","3"]},
+    /// "csv" writes the line, category and message columns followed by the input columns.
+    error_report_format: ErrorReportFormat,
+
+    #[clap(long = "strict", env = "BNM_STRICT")]
+    /// Exit with a distinct code (5) if any row was dropped or written without an exchange
+    /// rate. Rows are still processed as usual, so the output and the error report are
+    /// complete. Filters matching none of the rows fail the run (5) before any request, without
+    /// writing the output.
+    strict: bool,
+
+    #[clap(long = "min-date", env = "BNM_MIN_DATE", default_value = DEFAULT_MIN_DATE)]
+    /// Earliest rate date (ISO format) BNM has official rates for.
+    /// Rows with an earlier rate date are skipped without querying BNM.
+    min_date: NaiveDate,
+
+    #[clap(long = "holidays-file", env = "BNM_HOLIDAYS_FILE", parse(from_os_str))]
+    /// File with additional holidays, one ISO date (YYYY-MM-DD) per line.
+    /// Dates prefixed with "!" are considered business days (e.g. working Saturdays).
+    holidays_file: Option<std::path::PathBuf>,
+
+    #[clap(long = "out-file", env = "BNM_OUT_FILE", short = 'o')]
     /// Path to the output CSV file. If  omitted will be printed to STDOUT
     out_file: Option<std::path::PathBuf>,
 
-    #[clap(long = "out-column-delimiter")]
+    #[clap(long = "out-column-delimiter", env = "BNM_OUT_COLUMN_DELIMITER")]
     // Column delimiter of the output CSV file.
     out_column_delimiter: Option<char>,
 
-    #[clap(long = "out-date-format")]
+    #[clap(
+        long = "out-buffer-size",
+        env = "BNM_OUT_BUFFER_SIZE",
+        default_value = "65536"
+    )]
+    /// Size in bytes of the output buffer, which is written out whenever it's full.
+    /// It's flushed at the end of the run, including when interrupted with Ctrl-C
+    /// (but not on a second Ctrl-C).
+    out_buffer_size: usize,
+
+    #[clap(long = "out-date-format", env = "BNM_OUT_DATE_FORMAT")]
     /// Date format of the output file.
     /// If not provided, same format as input date will be used.
     out_date_format: Option<String>,
 
-    #[clap(long = "out-exchange-column", default_value = "Exchange Rate")]
+    #[clap(long = "out-date-locale", env = "BNM_OUT_DATE_LOCALE", arg_enum)]
+    /// Language of month ("%B"/"%b") and weekday ("%A"/"%a") names in the output date format.
+    /// If not provided, the input date language is used.
+    out_date_locale: Option<DateLocale>,
+
+    #[clap(
+        long = "out-exchange-column",
+        env = "BNM_OUT_EXCHANGE_COLUMN",
+        default_value = DEFAULT_EXCHANGE_COLUMN
+    )]
     /// Column name of the exchange rate.
     out_exchange_column: String,
 
-    #[clap(long = "out-exchange-insert-after")]
+    #[clap(long = "out-inverse-column", env = "BNM_OUT_INVERSE_COLUMN")]
+    /// Name of an additional column with the inverse rate (USD per MDL),
+    /// inserted right after the exchange rate column.
+    out_inverse_column: Option<String>,
+
+    #[clap(
+        long = "inverse-precision",
+        env = "BNM_INVERSE_PRECISION",
+        default_value = "6"
+    )]
+    /// Number of decimals of the inverse rate.
+    inverse_precision: usize,
+
+    #[clap(long = "out-rate-change-column", env = "BNM_OUT_RATE_CHANGE_COLUMN")]
+    /// Name of an additional column with the percent change of the rate versus the previous
+    /// business day's rate, inserted right after the exchange rate column.
+    out_rate_change_column: Option<String>,
+
+    #[clap(
+        long = "rate-change-precision",
+        env = "BNM_RATE_CHANGE_PRECISION",
+        default_value = "2"
+    )]
+    /// Number of decimals of the rate change.
+    rate_change_precision: usize,
+
+    #[clap(long = "reference-date", env = "BNM_REFERENCE_DATE")]
+    /// Date (ISO format) of a reference rate, e.g. the contract date, fetched once at start.
+    reference_date: Option<NaiveDate>,
+
+    #[clap(
+        long = "out-reference-column",
+        env = "BNM_OUT_REFERENCE_COLUMN",
+        requires = "reference-date"
+    )]
+    /// Name of an additional column with the rate of the reference date,
+    /// inserted right after the exchange rate column.
+    out_reference_column: Option<String>,
+
+    #[clap(
+        long = "out-reference-delta-column",
+        env = "BNM_OUT_REFERENCE_DELTA_COLUMN",
+        requires = "reference-date"
+    )]
+    /// Name of an additional column with the difference between the rate and the reference
+    /// rate, inserted right after the exchange rate column.
+    out_reference_delta_column: Option<String>,
+
+    #[clap(
+        long = "amount-column",
+        env = "BNM_AMOUNT_COLUMN",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    /// Column with amounts to be converted with the exchange rate.
+    /// In case the input CSV file has header, it's used as header name.
+    /// Otherwise it's used as an index.
+    /// Can be repeated (e.g. for "Debit" and "Credit" columns), the converted columns are
+    /// placed after the exchange rate in the same order. Empty cells are left empty.
+    amount_column: Vec<String>,
+
+    #[clap(long = "in-decimal-separator", env = "BNM_IN_DECIMAL_SEPARATOR")]
+    /// Decimal separator of the amounts, "." or ",".
+    /// If not provided, it's detected from each amount (e.g. "1.234,56" and "1,234.56"),
+    /// a single separator followed by three digits ("1,234") is a thousands separator.
+    in_decimal_separator: Option<char>,
+
+    #[clap(long = "amount-negative-marker", env = "BNM_AMOUNT_NEGATIVE_MARKER")]
+    /// Trailing marker of negative amounts, e.g. "DR" for "1,250.00 DR" (case-insensitive).
+    /// Amounts in parentheses ("(1,250.00)") and with a minus sign are negative as well.
+    amount_negative_marker: Option<String>,
+
+    #[clap(
+        long = "convert-direction",
+        env = "BNM_CONVERT_DIRECTION",
+        arg_enum,
+        default_value = "to-mdl"
+    )]
+    /// Whether USD amounts are converted to MDL (amount * rate),
+    /// or MDL amounts to USD (amount / rate).
+    /// The rate is always BNM's MDL per USD rate, so the direction is the only way to invert it.
+    convert_direction: ConvertDirection,
+
+    #[clap(
+        long = "out-converted-column",
+        env = "BNM_OUT_CONVERTED_COLUMN",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    /// Column name of the converted amount, inserted right after the exchange rate column.
+    /// Defaults to "Amount MDL", or "Amount USD" when converting from MDL.
+    /// With several amount columns, it can be repeated to name them in the same order,
+    /// the default names are "{column} MDL" (or "{column} USD").
+    out_converted_column: Vec<String>,
+
+    #[clap(long = "amount-precision", env = "BNM_AMOUNT_PRECISION")]
+    /// Number of decimals of the converted amount (e.g. 2), all of them are kept if not set.
+    amount_precision: Option<usize>,
+
+    #[clap(long = "rate-precision", env = "BNM_RATE_PRECISION")]
+    /// Number of decimals of the exchange rate, BNM's value is kept if not set.
+    rate_precision: Option<usize>,
+
+    #[clap(
+        long = "rounding",
+        env = "BNM_ROUNDING",
+        arg_enum,
+        default_value = "half-up"
+    )]
+    /// How the converted amount and the rate are rounded to the precision:
+    /// "half-up" rounds ties away from zero, "half-even" to the even digit,
+    /// "truncate" drops the extra digits.
+    rounding: Rounding,
+
+    #[clap(
+        long = "out-totals",
+        env = "BNM_OUT_TOTALS",
+        requires = "amount-column"
+    )]
+    /// Append a "TOTAL" row with the sums of the amount and the converted amount columns,
+    /// other cells are left empty. Unparsable amounts are skipped.
+    out_totals: bool,
+
+    #[clap(
+        long = "out-group-totals",
+        env = "BNM_OUT_GROUP_TOTALS",
+        arg_enum,
+        requires = "amount-column"
+    )]
+    /// Insert a subtotal row after each month or year, labeled with it (e.g. "2024-03"),
+    /// with the sums of the amount and the converted amount columns.
+    /// Note that this sorts the rows by transaction date, rows without a date are kept last.
+    /// Can be combined with "--out-totals" for a grand total.
+    out_group_totals: Option<GroupTotals>,
+
+    #[clap(
+        long = "on-invalid-amount",
+        env = "BNM_ON_INVALID_AMOUNT",
+        arg_enum,
+        default_value = "blank"
+    )]
+    /// What to do with rows whose amount isn't a number: keep them with an empty converted
+    /// amount, or treat them as failed.
+    on_invalid_amount: OnInvalidAmount,
+
+    #[clap(long = "out-rate-date-column", env = "BNM_OUT_RATE_DATE_COLUMN")]
+    /// Name of an additional column with the date the exchange rate was taken for,
+    /// inserted right after the exchange rate column.
+    /// It differs from the transaction date when the date offset, the previous business day
+    /// or the latest rate for future dates were used.
+    /// Uses the output date format if provided, ISO format (%Y-%m-%d) otherwise.
+    out_rate_date_column: Option<String>,
+
+    #[clap(long = "out-status-column", env = "BNM_OUT_STATUS_COLUMN")]
+    /// Name of an additional column with the outcome of each row, placed after the other
+    /// added columns: "ok", or why the exchange rate (or the converted amount) is missing:
+    /// "not-matched", "empty-date", "date-parse-error", "ragged-row" (the row is too short to
+    /// have a date), "future-date", "rate-missing" (BNM didn't publish it), "network-error"
+    /// (the request failed), "amount-parse-error", or "error" for other failures.
+    /// The same categories are used in the summary and the error report.
+    /// Failed rows are only written with "--on-rate-error blank", rows with invalid amounts
+    /// are kept by default ("--on-invalid-amount blank").
+    out_status_column: Option<String>,
+
+    #[clap(long = "out-date-split", env = "BNM_OUT_DATE_SPLIT")]
+    /// Add year, month and day columns right after the date column.
+    out_date_split: bool,
+
+    #[clap(
+        long = "out-date-split-names",
+        env = "BNM_OUT_DATE_SPLIT_NAMES",
+        default_value = "Year,Month,Day"
+    )]
+    /// Comma-separated names of the year, month and day columns.
+    /// "{column}" is replaced with the date column name (e.g. "{column} Year").
+    out_date_split_names: String,
+
+    #[clap(
+        long = "out-exchange-insert-after",
+        env = "BNM_OUT_EXCHANGE_INSERT_AFTER"
+    )]
     /// The column name/index exchange rate must be appended after.
     /// In case the input CSV file has header, it's used as header name.
     /// Otherwise it's used as an index.
     /// If not provided, it'll be appended as the last column.
     out_exchange_insert_after: Option<String>,
 
-    #[clap(long = "filter", short = 'f')]
-    /// The filter expression must be in {column}={regex} format.
+    #[clap(long = "allow-duplicate-headers", env = "BNM_ALLOW_DUPLICATE_HEADERS")]
+    /// Write added columns even if their name is already used by another output column.
+    allow_duplicate_headers: bool,
+
+    #[clap(long = "from-date", env = "BNM_FROM_DATE")]
+    /// Only process rows with a transaction date on or after the given date (ISO format).
+    from_date: Option<NaiveDate>,
+
+    #[clap(long = "to-date", env = "BNM_TO_DATE")]
+    /// Only process rows with a transaction date on or before the given date (ISO format).
+    to_date: Option<NaiveDate>,
+
+    #[clap(
+        long = "filter",
+        env = "BNM_FILTER",
+        short = 'f',
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    /// The filter expression must be in {column}={regex} format,
+    /// or {column}!={regex} to keep rows not matching the regex.
+    /// Numeric comparisons are supported as well: {column}>{number} (also >=, <, <=),
+    /// cells are parsed like amounts (e.g. "1.234,56", "(125.00)" or "-25.00 USD"),
+    /// cells which are not numbers don't match.
+    /// Comparisons on the date column compare dates instead, with an ISO date value
+    /// (e.g. "Date>=2024-01-01"), cells are parsed using the input date format.
+    /// Prefix the column with "date:" to compare another column as dates,
+    /// "date:{column}={date}" matches the exact date. Unparsable dates don't match.
+    /// Use "*" as {column} to match any field of the row (e.g. "*=Stripe"),
+    /// "*!={regex}" keeps rows where no field matches.
+    /// {column}@={path} keeps rows whose cell equals one of the values listed in the file
+    /// (one per line, empty lines and lines starting with "#" are ignored).
     /// In case the input CSV file has header, {column} is used as header name.
     /// Otherwise it's used as an index.
-    filter: Option<String>,
+    /// "=", "<", ">", "!", "@" and "\" in the column name must be escaped with a backslash
+    /// (e.g. "A\=B=x"), the value is used as is.
+    /// Can be repeated, a row is kept only if it matches all the filters.
+    filter: Vec<String>,
+
+    #[clap(
+        long = "filter-column",
+        env = "BNM_FILTER_COLUMN",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    /// Column of a regex filter given with "--filter-regex", an alternative to
+    /// "--filter {column}={regex}" which needs no escaping. Can be repeated,
+    /// the n-th column is matched against the n-th regex.
+    filter_column: Vec<String>,
+
+    #[clap(
+        long = "filter-regex",
+        env = "BNM_FILTER_REGEX",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    /// Regex of the corresponding "--filter-column".
+    filter_regex: Vec<String>,
+
+    #[clap(
+        long = "filter-any",
+        env = "BNM_FILTER_ANY",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    /// Same format as "--filter", can be repeated.
+    /// A row is kept if it matches all "--filter" expressions and at least
+    /// one of "--filter-any" expressions.
+    filter_any: Vec<String>,
+
+    #[clap(long = "invert-match", env = "BNM_INVERT_MATCH")]
+    /// Keep only the rows which don't pass the filters (like "grep -v"), i.e. drop the rows
+    /// matching all "--filter" and at least one "--filter-any" expressions.
+    /// Unlike "!=", which negates a single expression, this negates the combined result:
+    /// "-f A=x -f B=y --invert-match" drops rows where A matches x and B matches y.
+    invert_match: bool,
+
+    #[clap(
+        long = "filter-mode",
+        env = "BNM_FILTER_MODE",
+        arg_enum,
+        default_value = "exclude"
+    )]
+    /// How filters are applied: "exclude" drops rows which don't match,
+    /// "annotate" keeps all rows, adding the exchange rate only to the matching ones.
+    filter_mode: FilterMode,
+
+    #[clap(
+        long = "post-filter",
+        env = "BNM_POST_FILTER",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    /// Same format as "--filter", but applied to the output rows,
+    /// so added columns (e.g. the exchange rate) can be referenced by their output name
+    /// (or output index for files without headers). Can be repeated.
+    post_filter: Vec<String>,
+
+    #[clap(long = "dedupe-by", env = "BNM_DEDUPE_BY")]
+    /// Comma-separated columns identifying a row (e.g. "Date,Reference,Amount").
+    /// Rows with the same values in all of them are written once, before fetching any rate.
+    /// In case the input CSV file has header, it's used as header names.
+    /// Otherwise they're used as indexes.
+    dedupe_by: Option<String>,
+
+    #[clap(
+        long = "dedupe-keep",
+        env = "BNM_DEDUPE_KEEP",
+        arg_enum,
+        default_value = "first"
+    )]
+    /// Which of the duplicate rows is kept.
+    dedupe_keep: DedupeKeep,
+
+    #[clap(long = "filter-ignore-case", env = "BNM_FILTER_IGNORE_CASE")]
+    /// Match "--filter" and "--filter-any" regexes case-insensitively.
+    filter_ignore_case: bool,
+
+    #[clap(long = "filter-file-regex", env = "BNM_FILTER_FILE_REGEX")]
+    /// Treat the lines of "{column}@={path}" filter files as regexes.
+    filter_file_regex: bool,
+
+    #[clap(long = "lenient-filter", env = "BNM_LENIENT_FILTER")]
+    /// Ignore invalid filter expressions with a warning, rather than aborting the run.
+    lenient_filter: bool,
+
+    #[clap(long = "stats", env = "BNM_STATS")]
+    /// Print counters of the run to STDERR at the end, as "name=value" pairs: rows read,
+    /// filtered and written, unique dates, rate lookups and requests, cache hits,
+    /// request errors by category, and the time of each phase in milliseconds.
+    stats: bool,
+
+    #[clap(long = "stats-format", env = "BNM_STATS_FORMAT", arg_enum)]
+    /// Format of the "--stats" output, setting it implies "--stats".
+    stats_format: Option<StatsFormat>,
+
+    #[clap(long = "timing", env = "BNM_TIMING")]
+    /// Add timings to the "--stats" output (implying it), in milliseconds: the time spent
+    /// reading and filtering the rows, prefetching rates, assembling the output rows and
+    /// writing them, and the 50th, 90th and 99th percentiles and the maximum of the
+    /// request latencies.
+    /// The steps overlap as the rows are processed concurrently, so their times don't add
+    /// up to the time of the run.
+    timing: bool,
+
+    #[clap(long = "dry-run", env = "BNM_DRY_RUN")]
+    /// Read and filter the rows, then print the dates whose rates would be fetched
+    /// (and how many of them are cached) and where the output would be written,
+    /// without any request or output.
+    dry_run: bool,
+
+    #[clap(
+        long = "unique-dates",
+        env = "BNM_UNIQUE_DATES",
+        conflicts_with = "dry-run"
+    )]
+    /// Read and filter the rows, then print the sorted dates whose rates would be fetched
+    /// (after the date offset and the business day adjustment), one per line in ISO format,
+    /// without any request or output.
+    unique_dates: bool,
+
+    #[clap(long = "prefetch", env = "BNM_PREFETCH")]
+    /// Read all the rows first and fetch the rates of their dates, then add them to the rows.
+    /// The number of dates to fetch is logged upfront, at the cost of holding the whole
+    /// input in memory (or a chunk of it with "--chunk-size").
+    prefetch: bool,
+
+    #[clap(
+        long = "chunk-size",
+        env = "BNM_CHUNK_SIZE",
+        conflicts_with = "out-group-totals"
+    )]
+    /// Read the rows N at a time: the rates of a chunk's dates are fetched first (as with
+    /// "--prefetch"), then its rows are written before the next chunk is read.
+    /// Can't be used with "--out-group-totals", which sorts all the rows, while "--out-totals"
+    /// sums the rows of all the chunks. The whole output is still held until the end with
+    /// "--fail-fast" and "--max-errors" (unless "--keep-partial" is set).
+    chunk_size: Option<usize>,
+
+    #[clap(
+        long = "max-concurrency",
+        env = "BNM_MAX_CONCURRENCY",
+        default_value = "32"
+    )]
+    /// Maximum number of rows processed at once, which bounds the requests in flight.
+    /// Rows of the same date share a request. The output is in the input order regardless.
+    max_concurrency: usize,
 }
 
-struct RecordFilter {
-    column: usize,
-    regex: Regex,
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Headers {
+    Yes,
+    No,
+    Auto,
 }
 
-impl RecordFilter {
-    fn matches(&self, record: &StringRecord) -> bool {
-        record
-            .get(self.column)
-            .map(|v| self.regex.find(v).is_some())
-            .unwrap_or(false)
-    }
-}
-
-async fn fetch_exchange_rate(date: &NaiveDate) -> Result<f64> {
-    let formatted_date = date.format("%d.%m.%Y").to_string();
-    if CURRENCY_CACHE.read().await.contains_key(&formatted_date) {
-        return CURRENCY_CACHE
-            .read()
-            .await
-            .get(&formatted_date)
-            .ok_or(eyre!("Failed to read from cache"))
-            .map(|f| *f);
-    }
-    let url = format!("https://www.bnm.md/ro/export-official-exchange-rates?date={formatted_date}");
-    log::debug!("Fetching exchange from {}", &url);
-    let response = reqwest::get(&url).await?;
-    if response.status() != StatusCode::OK {
-        return Err(eyre!("Got unexpected status - {}", response.status()));
-    }
-    let body = response.text().await?;
-    for line in body.lines().skip(2) {
-        if line.contains(";USD;") {
-            let rate: f64 = line.split(';').last().unwrap().replace(',', ".").parse()?;
-            return Ok(rate);
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ErrorReportFormat {
+    Jsonl,
+    Csv,
+}
+
+/// Prints what would be fetched and written, for dry runs.
+async fn print_request_plan(
+    args: &ConvertArgs,
+    ctx: &RowContext<'_>,
+    records: &[(StringRecord, bool)],
+) -> Result<()> {
+    let (mut dates, failed) = request_dates(ctx, records);
+    dates.extend(args.reference_date);
+    let mut cached = 0;
+    for date in dates.iter() {
+        if ctx.client.is_cached(*date).await {
+            cached += 1;
         }
     }
-    Err(eyre!("Didn't find required currency"))
+    let matching = records.iter().filter(|(_, matches)| *matches).count();
+    println!("Rows passing the filters: {}", matching);
+    if matching < records.len() {
+        println!(
+            "Rows written without a rate (not matching the filters): {}",
+            records.len() - matching
+        );
+    }
+    println!("Rows failing before any request: {}", failed);
+    println!(
+        "{} rates of {} dates to fetch ({} cached):",
+        ctx.config.currency,
+        dates.len(),
+        cached
+    );
+    for date in dates.iter() {
+        println!("  {}", date);
+    }
+    match args.out_file.as_ref() {
+        Some(p) => println!("Output: {}", p.display()),
+        None => println!("Output: STDOUT"),
+    }
+    Ok(())
 }
 
-fn get_column_index(headers: Option<&StringRecord>, column: &str) -> Result<usize> {
-    match headers {
-        Some(h) => h
-            .iter()
-            .position(|h| h == column)
-            .ok_or_else(|| eyre!("Cannot find column \"{}\" in headers", column)),
-        None => column
-            .parse::<usize>()
-            .map_err(|_| eyre!("Failed to parse column index - {}", column)),
-    }
-}
-
-async fn add_exchange(
-    date_column: usize,
-    date_format: &str,
-    out_date_format: Option<&String>,
-    exchange_index: Option<usize>,
-    record: StringRecord,
-) -> Result<StringRecord> {
-    let original_date = record
-        .get(date_column)
-        .ok_or_else(|| eyre!("Failed to lookup column {}", date_column))?;
-    let date = NaiveDate::parse_from_str(original_date, date_format)?;
-    let out_date = match out_date_format {
-        Some(f) => date.format(f).to_string(),
-        None => original_date.to_string(),
-    };
-    let exchange_rate = fetch_exchange_rate(&date).await?;
-    let mut record: Vec<String> = record.iter().map(|v| v.to_string()).collect();
-    record[date_column] = out_date;
-    match exchange_index {
-        Some(v) => record.insert(v + 1, exchange_rate.to_string()),
-        None => record.push(exchange_rate.to_string()),
-    };
-    Ok(StringRecord::from(record))
-}
-
-fn create_filter(filter: &str, headers: Option<&StringRecord>) -> Result<RecordFilter> {
-    let (column, re) = filter
-        .split_once('=')
-        .ok_or(eyre!("The filter must be k=v pair"))?;
-    let regex = Regex::new(re)?;
-    let column = get_column_index(headers, column)?;
-    Ok(RecordFilter { regex, column })
-}
-
-fn get_out_headers(
-    headers: &StringRecord,
-    exchange_column: &str,
-    exchange_column_insert_after: Option<&String>,
-) -> StringRecord {
-    let exchange_column_index = exchange_column_insert_after.and_then(|v| {
-        let index = get_column_index(Some(headers), v);
-        index
+fn create_layout(
+    args: &ConvertArgs,
+    headers: Option<&StringRecord>,
+    date_source: &DateSource,
+) -> Result<OutLayout> {
+    let mut columns = Vec::new();
+    if args.out_date_split {
+        let date_index = date_source.main_column();
+        let date_column = headers.and_then(|h| h.get(date_index)).unwrap_or_default();
+        let names: Vec<&str> = args.out_date_split_names.split(',').collect();
+        if names.len() != 3 {
+            return Err(eyre!(
+                "The date split names must be a year,month,day triple - {}",
+                args.out_date_split_names
+            ));
+        }
+        let values = [OutValue::Year, OutValue::Month, OutValue::Day];
+        for (name, value) in names.into_iter().zip(values) {
+            columns.push(OutColumn {
+                after: Some(date_index),
+                name: name.replace("{column}", date_column),
+                value,
+            });
+        }
+    }
+    let exchange_index = args.out_exchange_insert_after.as_ref().and_then(|v| {
+        get_column_index(headers, v)
             .map_err(|e| {
                 log::warn!("Failed to get exchange column index - {}", e);
                 e
             })
             .ok()
     });
-    let mut record: Vec<String> = headers.iter().map(|v| v.to_string()).collect();
-    match exchange_column_index {
-        Some(v) => record.insert(v, exchange_column.to_string()),
-        None => record.push(exchange_column.to_string()),
+    columns.push(OutColumn {
+        after: exchange_index,
+        name: args.out_exchange_column.clone(),
+        value: OutValue::Exchange,
+    });
+    if args.out_converted_column.len() > args.amount_column.len() {
+        return Err(eyre!(
+            "Got {} converted column names for {} amount columns",
+            args.out_converted_column.len(),
+            args.amount_column.len()
+        ));
+    }
+    let currency = match args.convert_direction {
+        ConvertDirection::ToMdl => "MDL",
+        ConvertDirection::FromMdl => "USD",
     };
-    StringRecord::from(record)
+    let converted_columns = args.amount_column.iter().enumerate().map(|(i, column)| {
+        let name = match (args.out_converted_column.get(i), args.amount_column.len()) {
+            (Some(name), _) => name.clone(),
+            (None, 1) => format!("Amount {}", currency),
+            (None, _) => format!("{} {}", column, currency),
+        };
+        (Some(name), OutValue::Converted(i))
+    });
+    // Columns with the same anchor are written in this order, right after the exchange rate.
+    let exchange_columns = [
+        (args.out_rate_date_column.clone(), OutValue::RateDate),
+        (args.out_reference_column.clone(), OutValue::Reference),
+        (
+            args.out_reference_delta_column.clone(),
+            OutValue::ReferenceDelta,
+        ),
+        (args.out_rate_change_column.clone(), OutValue::RateChange),
+        (args.out_inverse_column.clone(), OutValue::Inverse),
+    ];
+    let status_column = (args.out_status_column.clone(), OutValue::Status);
+    for (name, value) in exchange_columns
+        .into_iter()
+        .chain(converted_columns)
+        .chain([status_column])
+    {
+        if let Some(name) = name {
+            columns.push(OutColumn {
+                after: exchange_index,
+                name,
+                value,
+            });
+        }
+    }
+    Ok(OutLayout { columns })
 }
 
-fn read_records<T>(reader: &'_ mut Reader<T>, filter: Option<&RecordFilter>) -> Vec<StringRecord>
-where
-    T: std::io::Read,
-{
-    reader
-        .records()
-        .filter_map(|r| {
-            r.map_err(|e| {
-                log::warn!("Skipping row due to parse error - {}", e);
-                e
-            })
-            .ok()
-        })
-        .filter(|r| match filter {
-            Some(f) => f.matches(r),
-            None => true,
-        })
-        .collect()
+/// Number of fields short rows are padded to, if enabled.
+fn pad_width(args: &ConvertArgs, headers: Option<&StringRecord>) -> Option<usize> {
+    headers.filter(|_| args.pad_short_rows).map(|h| h.len())
 }
 
+/// Writes the records, the footer (e.g. the totals row) is written after them.
 fn write_records<T>(
     records: &[StringRecord],
     headers: Option<StringRecord>,
+    footer: Option<&StringRecord>,
     writer: &mut Writer<T>,
 ) -> Result<()>
 where
     T: std::io::Write,
 {
-    if headers.is_some() {
-        writer.write_record(&headers.unwrap())?;
+    if let Some(h) = headers {
+        writer.write_record(&h)?;
     };
     for record in records {
         writer.write_record(record)?;
     }
+    if let Some(f) = footer {
+        writer.write_record(f)?;
+    }
     Ok(())
 }
 
-async fn read_in_file(args: &OptionsParser) -> Result<Vec<u8>> {
-    match args.in_file.as_ref() {
-        Some(p) => Ok(read(p).await?),
-        None => {
-            let mut buf = Vec::with_capacity(4096);
-            stdin().read_to_end(&mut buf).await?;
-            Ok(buf)
+/// Writes the failed rows to the error report file.
+fn write_error_report(
+    path: &std::path::Path,
+    format: ErrorReportFormat,
+    headers: Option<&StringRecord>,
+    rows: &[FailedRow],
+) -> Result<()> {
+    let write = || -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        match format {
+            ErrorReportFormat::Jsonl => {
+                let mut out = std::io::BufWriter::new(file);
+                for row in rows {
+                    let fields = row
+                        .record
+                        .iter()
+                        .map(logging::json_string)
+                        .collect::<Vec<_>>();
+                    writeln!(
+                        out,
+                        "{{\"line\":{},\"category\":{},\"message\":{},\"fields\":[{}]}}",
+                        row.line,
+                        logging::json_string(row.status.as_str()),
+                        logging::json_string(&row.message),
+                        fields.join(",")
+                    )?;
+                }
+                out.flush()?;
+            }
+            ErrorReportFormat::Csv => {
+                let mut writer = WriterBuilder::new().flexible(true).from_writer(file);
+                if rows.is_empty() {
+                    return Ok(());
+                }
+                let columns = ["line", "category", "message"];
+                match headers {
+                    Some(h) => writer.write_record(columns.iter().copied().chain(h.iter()))?,
+                    None => writer.write_record(columns)?,
+                }
+                for row in rows {
+                    let line = row.line.to_string();
+                    let prefix = [line.as_str(), row.status.as_str(), row.message.as_str()];
+                    writer.write_record(prefix.into_iter().chain(row.record.iter()))?;
+                }
+                writer.flush()?;
+            }
         }
+        Ok(())
+    };
+    write().map_err(|e| eyre!("Failed to write error report {} - {}", path.display(), e))
+}
+
+/// Checks the options which can't be validated by the parser.
+fn validate_args(args: &ConvertArgs) -> Result<()> {
+    if let Some(c) = args
+        .in_decimal_separator
+        .filter(|c| !matches!(c, '.' | ','))
+    {
+        return Err(eyre!(
+            "The decimal separator must be \".\" or \",\" - {}",
+            c
+        ));
+    }
+    if args.max_concurrency == 0 {
+        return Err(eyre!("The maximum concurrency must be at least 1"));
     }
+    if args.out_buffer_size == 0 {
+        return Err(eyre!("The output buffer size must be at least 1"));
+    }
+    if args.chunk_size == Some(0) {
+        return Err(eyre!("The chunk size must be at least 1"));
+    }
+    if let Some(path) = args.out_file.as_ref() {
+        check_writable(path, "output file")?;
+    }
+    if let Some(path) = args.error_report.as_ref() {
+        check_writable(path, "error report")?;
+    }
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    color_eyre::install()?;
-    env_logger::init();
-    let args = OptionsParser::parse();
-    let data = read_in_file(&args).await?;
-    let mut reader = csv::ReaderBuilder::new()
+/// Checks the file can be written, so that a wrong path fails the run before any request.
+/// An existing file is left unchanged (it's not truncated), a new one is removed.
+fn check_writable(path: &std::path::Path, what: &str) -> Result<()> {
+    let existed = path.exists();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| eyre!("Cannot write the {} {} - {}", what, path.display(), e))?;
+    if !existed {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(())
+}
+
+fn create_rate_dates(args: &ConvertArgs) -> Result<RateDateResolver> {
+    let calendar = match (args.rate_date, args.holidays_file.as_ref()) {
+        (RateDateMode::Exact, _) => None,
+        (RateDateMode::PreviousBusinessDay, Some(p)) => Some(HolidayCalendar::load(p)?),
+        (RateDateMode::PreviousBusinessDay, None) => Some(HolidayCalendar::default()),
+    };
+    Ok(RateDateResolver {
+        offset: args.date_offset,
+        calendar,
+        on_future_date: args.on_future_date,
+        today: Utc::now().with_timezone(&Chisinau).date().naive_local(),
+        min_date: args.min_date,
+    })
+}
+
+/// Configuration of the rows from the "convert" options, but for the reference rate which
+/// is fetched first.
+fn create_config(
+    args: &ConvertArgs,
+    date_parser: Arc<DateParser>,
+    layout: OutLayout,
+    amounts: Option<AmountColumns>,
+    rate_dates: RateDateResolver,
+) -> PipelineConfigBuilder {
+    PipelineConfig::builder(date_parser)
+        .date_range(args.from_date, args.to_date)
+        .rate_dates(rate_dates)
+        .out_dates(DateFormatter::new(
+            args.out_date_format.clone(),
+            args.out_date_locale.unwrap_or(args.in_date_locale),
+        ))
+        .layout(layout)
+        .amounts(amounts)
+        .rate_precision(args.rate_precision)
+        .inverse_precision(
+            args.out_inverse_column
+                .as_ref()
+                .map(|_| args.inverse_precision),
+        )
+        .rate_change_precision(
+            args.out_rate_change_column
+                .as_ref()
+                .map(|_| args.rate_change_precision),
+        )
+        .rate_mode(args.rate_mode)
+        .rounding(args.rounding)
+        .concurrency(args.max_concurrency)
+        .prefetch(match (args.prefetch, args.chunk_size) {
+            (false, None) => None,
+            (_, size) => Some(size.unwrap_or(usize::MAX)),
+        })
+}
+
+fn create_amounts(
+    args: &ConvertArgs,
+    headers: Option<&StringRecord>,
+) -> Result<Option<AmountColumns>> {
+    if args.amount_column.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(AmountColumns {
+        columns: args
+            .amount_column
+            .iter()
+            .map(|c| get_column_index(headers, c))
+            .collect::<Result<_, _>>()?,
+        format: AmountFormat {
+            decimal_separator: args.in_decimal_separator,
+            negative_marker: args.amount_negative_marker.clone(),
+        },
+        direction: args.convert_direction,
+        precision: args.amount_precision,
+        on_invalid: args.on_invalid_amount,
+    }))
+}
+
+fn create_reader(args: &ConvertArgs, input: Input) -> Reader<Box<dyn std::io::Read + Send>> {
+    let has_headers = has_headers(args, &input.head);
+    csv::ReaderBuilder::new()
         .flexible(true)
         .delimiter(args.in_column_delimiter as u8)
-        .has_headers(!args.in_no_headers)
-        .from_reader(data.as_slice());
-    let headers = if reader.has_headers() {
-        Some(reader.headers()?.clone())
-    } else {
-        None
-    };
-    let date_format = args.in_date_format.as_str();
-    let out_date_format = args.out_date_format.as_ref();
-    let date_index = get_column_index(headers.as_ref(), &args.in_date_column)?;
-    let exchange_index = args
-        .out_exchange_insert_after
-        .as_ref()
-        .and_then(|v| get_column_index(headers.as_ref(), v).ok());
-    let filter = args
-        .filter
-        .as_ref()
-        .and_then(|f| create_filter(f, headers.as_ref()).ok());
-    let futures = read_records(&mut reader, filter.as_ref())
-        .into_iter()
-        .map(|r| async move {
-            add_exchange(date_index, date_format, out_date_format, exchange_index, r).await
-        });
-    let records = join_all(futures).await;
-    let out_records: Vec<StringRecord> = records
-        .into_iter()
-        .filter_map(|r| {
-            r.map_err(|e| {
-                log::warn!("Failed to add exchange rate - {}", e);
-                e
+        .has_headers(has_headers)
+        .from_reader(Box::new(std::io::Cursor::new(input.head).chain(input.rest)))
+}
+
+/// Reads the rows of all the input files, each file in a thread of its own, as parsing and
+/// filtering them doesn't wait on anything. The rows are returned one file after the other,
+/// in the order of the files, the record number of their position being the index of their
+/// file (see [`input_file`](bnm_exporter::csvproc::input_file)), the lines being those in their file.
+/// `first` is the reader of the first file, its headers already read.
+fn read_files(
+    args: &ConvertArgs,
+    first: Reader<Box<dyn std::io::Read + Send>>,
+    headers: Option<&StringRecord>,
+    filter: &FilterSet,
+    stats: &RunStats,
+) -> Result<Vec<(StringRecord, bool)>> {
+    let mut readers = vec![first];
+    for path in &args.in_file[1..] {
+        let mut reader = create_reader(args, open_input_file(Some(path))?);
+        if read_headers(&mut reader)?.as_ref() != headers {
+            return Err(eyre!(
+                "The headers of {} aren't the same as those of {}",
+                path.display(),
+                args.in_file[0].display()
+            ));
+        }
+        readers.push(reader);
+    }
+    let pad_to = pad_width(args, headers);
+    let files = std::thread::scope(|scope| {
+        let threads: Vec<_> = readers
+            .into_iter()
+            .zip(&args.in_file)
+            .enumerate()
+            .map(|(i, (mut reader, path))| {
+                scope.spawn(move || {
+                    read_records(
+                        &mut reader,
+                        Some(path),
+                        pad_to,
+                        filter,
+                        args.filter_mode,
+                        stats,
+                    )
+                    .map(|(mut record, matches)| {
+                        let mut position = record.position().cloned().unwrap_or_else(Position::new);
+                        position.set_record(i as u64);
+                        record.set_position(Some(position));
+                        (record, matches)
+                    })
+                    .collect::<Vec<_>>()
+                })
             })
-            .ok()
-        })
-        .collect();
-    let out_headers = headers.as_ref().map(|h| {
-        get_out_headers(
-            h,
-            &args.out_exchange_column,
-            args.out_exchange_insert_after.as_ref(),
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| thread.join().expect("reading an input file panicked"))
+            .collect::<Vec<_>>()
+    });
+    for (path, records) in args.in_file.iter().zip(&files) {
+        log::debug!("Read {} rows of {}", records.len(), path.display());
+    }
+    Ok(files.into_iter().flatten().collect())
+}
+
+/// Whether the first row of the input is headers, detected with "--headers auto".
+fn has_headers(args: &ConvertArgs, data: &[u8]) -> bool {
+    match (args.headers, args.in_no_headers) {
+        (Some(Headers::Auto), _) => {
+            let detected = !starts_with_date(args, data);
+            log::debug!(
+                "Detected {} headers in the input",
+                if detected { "the" } else { "no" }
+            );
+            detected
+        }
+        (Some(Headers::No), _) | (None, true) => false,
+        _ => true,
+    }
+}
+
+/// Whether the date of the first row parses, taking the date columns as indexes.
+fn starts_with_date(args: &ConvertArgs, data: &[u8]) -> bool {
+    let first = csv::ReaderBuilder::new()
+        .flexible(true)
+        .delimiter(args.in_column_delimiter as u8)
+        .has_headers(false)
+        .from_reader(data)
+        .records()
+        .next();
+    match (first, create_date_parser(args, None)) {
+        (Some(Ok(record)), Ok(parser)) => parser.parse_record(&record).is_ok(),
+        _ => false,
+    }
+}
+
+/// Hint for a date column which can't be found as the headers are a data row.
+fn headers_hint(args: &ConvertArgs, headers: Option<&StringRecord>, data: &[u8]) -> Option<String> {
+    let detected = args.headers != Some(Headers::Auto) && starts_with_date(args, data);
+    (headers.is_some() && detected).then(|| {
+        "the first row has a date, the file may have no headers, \
+         use --in-no-headers or --headers auto"
+            .to_string()
+    })
+}
+
+fn read_headers<T: std::io::Read>(reader: &mut Reader<T>) -> Result<Option<StringRecord>> {
+    match reader.has_headers() {
+        true => Ok(Some(reader.headers()?.clone())),
+        false => Ok(None),
+    }
+}
+
+fn create_date_parser(
+    args: &ConvertArgs,
+    headers: Option<&StringRecord>,
+) -> Result<Arc<DateParser>> {
+    if args.in_year_pivot.map(|p| p > 100).unwrap_or(false) {
+        return Err(eyre!("The year pivot must be between 0 and 100"));
+    }
+    let date_regex = match args.in_date_regex.as_ref() {
+        Some(r) => {
+            let re = Regex::new(r)?;
+            if re.captures_len() < 2 {
+                return Err(eyre!("The date regex must have a capture group - {}", r));
+            }
+            Some(re)
+        }
+        None => None,
+    };
+    Ok(Arc::new(DateParser {
+        source: DateSource::new(
+            headers,
+            args.in_date_column.as_ref(),
+            args.in_date_parts.as_ref(),
+        )?,
+        on_empty: args.on_empty_date,
+        format: args.in_date_format.clone(),
+        locale: args.in_date_locale,
+        year_pivot: args.in_year_pivot,
+        regex: date_regex,
+        in_timezone: args.in_timezone,
+        target_timezone: args.target_timezone,
+        memo: Default::default(),
+    }))
+}
+
+fn create_filter_options(args: &ConvertArgs, date_parser: &Arc<DateParser>) -> FilterOptions {
+    let date_columns = match &date_parser.source {
+        DateSource::Columns(columns) => columns.clone(),
+        DateSource::Parts { .. } => Vec::new(),
+    };
+    let cell_parser = date_parser.clone();
+    FilterOptions {
+        ignore_case: args.filter_ignore_case,
+        file_regex: args.filter_file_regex,
+        lenient: args.lenient_filter,
+        dates: Some(DateFilterOptions {
+            parser: Arc::new(move |v| cell_parser.parse(v).ok()),
+            columns: date_columns,
+        }),
+    }
+}
+
+fn create_filter(
+    args: &ConvertArgs,
+    headers: Option<&StringRecord>,
+    options: &FilterOptions,
+) -> Result<FilterSet> {
+    Ok(
+        FilterSet::new(&args.filter, &args.filter_any, headers, options)?
+            .with_regexes(&args.filter_column, &args.filter_regex, headers, options)?
+            .inverted(args.invert_match),
+    )
+}
+
+/// Warning of a run whose filters matched none of the rows read, if they did.
+fn describe_no_match(args: &ConvertArgs, filter: &FilterSet, stats: &RunStats) -> Option<String> {
+    let rows_read = stats.rows_read.load(Ordering::Relaxed);
+    let none_matched = stats.rows_matched.load(Ordering::Relaxed) == 0;
+    (!filter.is_empty() && rows_read > 0 && none_matched).then(|| {
+        format!(
+            "None of the {} rows matched the filters: {}",
+            rows_read,
+            describe_filters(args)
         )
+    })
+}
+
+/// The filter options as given, e.g. `--filter "Currency=USD", --invert-match`.
+fn describe_filters(args: &ConvertArgs) -> String {
+    let mut filters: Vec<String> = Vec::new();
+    filters.extend(args.filter.iter().map(|f| format!("--filter \"{}\"", f)));
+    filters.extend(
+        args.filter_any
+            .iter()
+            .map(|f| format!("--filter-any \"{}\"", f)),
+    );
+    filters.extend(
+        args.filter_column
+            .iter()
+            .zip(&args.filter_regex)
+            .map(|(c, r)| format!("--filter-column \"{}\" --filter-regex \"{}\"", c, r)),
+    );
+    if args.invert_match {
+        filters.push("--invert-match".to_string());
+    }
+    filters.join(", ")
+}
+
+/// Writer to the file, or to STDOUT if it's not provided.
+fn create_writer(
+    builder: &WriterBuilder,
+    path: Option<&std::path::PathBuf>,
+) -> Result<Writer<Box<dyn std::io::Write>>> {
+    let out = open_output(path).map_err(|e| eyre!("{}", e))?;
+    Ok(builder.from_writer(out))
+}
+
+/// The output file, or STDOUT if it's not provided, the error telling which file failed.
+fn open_output(path: Option<&std::path::PathBuf>) -> std::io::Result<Box<dyn std::io::Write>> {
+    match path {
+        Some(p) => match std::fs::File::create(p) {
+            Ok(file) => Ok(Box::new(file)),
+            Err(e) => Err(std::io::Error::new(
+                e.kind(),
+                format!("Failed to create {} - {}", p.display(), e),
+            )),
+        },
+        None => Ok(Box::new(std::io::stdout())),
+    }
+}
+
+/// Size of the beginning of the input which is read before the rows, to detect its format.
+const INPUT_HEAD_SIZE: u64 = 64 * 1024;
+
+/// The input file, or STDIN if it's not provided, read as the rows are processed.
+/// Its beginning, at least the first line, is read upfront to detect the delimiter
+/// and the headers.
+struct Input {
+    head: Vec<u8>,
+    rest: Box<dyn std::io::Read + Send>,
+}
+
+/// The first input file.
+fn open_input(args: &ConvertArgs) -> Result<Input> {
+    open_input_file(args.in_file.first())
+}
+
+fn open_input_file(path: Option<&std::path::PathBuf>) -> Result<Input> {
+    let mut rest: Box<dyn std::io::Read + Send> = match path {
+        Some(p) => Box::new(
+            std::fs::File::open(p).wrap_err_with(|| format!("Failed to open {}", p.display()))?,
+        ),
+        None => Box::new(std::io::stdin()),
+    };
+    let mut head = Vec::new();
+    while (&mut rest).take(INPUT_HEAD_SIZE).read_to_end(&mut head)? > 0 && !head.contains(&b'\n') {}
+    Ok(Input { head, rest })
+}
+
+#[tokio::main]
+async fn main() -> std::process::ExitCode {
+    let args = match OptionsParser::try_parse() {
+        Ok(args) => args,
+        // Help and version.
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            let _ = e.print();
+            return RunOutcome::Usage.into();
+        }
+    };
+    match run(args).await {
+        Ok(outcome) => outcome.into(),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            RunOutcome::of(&e).into()
+        }
+    }
+}
+
+async fn run(args: OptionsParser) -> Result<RunOutcome> {
+    color_eyre::install()?;
+    logging::init(
+        args.verbose,
+        args.quiet,
+        args.log_format,
+        args.log_file.as_deref(),
+    )?;
+    if args.request_timeout == 0 {
+        return Err(eyre!("The request timeout must be at least 1 second"));
+    }
+    let concurrency = match &args.command {
+        Some(Command::Convert(convert)) => convert.max_concurrency,
+        Some(Command::Range(range)) => range.max_concurrency,
+        _ => args.convert.max_concurrency,
+    };
+    let client = BnmClient::builder()
+        .timeout(std::time::Duration::from_secs(args.request_timeout))
+        .retries(args.retries)
+        .concurrency(concurrency);
+    // The bench command generates its own rates, unless they're loaded from a file.
+    let client = match (args.rates_file.as_ref(), &args.command) {
+        (Some(path), _) => client.source(StaticRates::load(path)?),
+        (None, Some(Command::Bench(bench))) => client.source(bench_rates(bench)),
+        (None, _) => client,
+    };
+    let client = &client.build()?;
+    let success = |_| RunOutcome::Success;
+    match args.command {
+        Some(Command::Convert(args)) => convert(&args, client).await,
+        Some(Command::Rate(args)) => print_rates(&args, client).await.map(success),
+        Some(Command::Range(args)) => write_range(&args, client).await.map(success),
+        Some(Command::Calc(args)) => print_conversion(&args, client).await.map(success),
+        Some(Command::Inspect(args)) => inspect(&args).await.map(success),
+        Some(Command::Check(args)) => check(&args, client).await,
+        Some(Command::Bench(args)) => bench(&args, client).await.map(success),
+        Some(Command::Completions { shell }) => {
+            let mut command = OptionsParser::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+            Ok(RunOutcome::Success)
+        }
+        None => convert(&args.convert, client).await,
+    }
+}
+
+/// Adds the exchange rates to the input file rows, writing them to the output file.
+async fn convert(args: &ConvertArgs, client: &BnmClient) -> Result<RunOutcome> {
+    validate_args(args)?;
+    let phase_start = Instant::now();
+    let input = open_input(args)?;
+    let head = input.head.clone();
+    let mut reader = create_reader(args, input);
+    let headers = read_headers(&mut reader)?;
+    let date_parser =
+        create_date_parser(args, headers.as_ref()).map_err(|e| {
+            match headers_hint(args, headers.as_ref(), &head)
+                .or_else(|| delimiter_hint(args, &head))
+            {
+                Some(hint) => eyre!("{} ({})", e, hint),
+                None => e,
+            }
+        })?;
+    let layout = create_layout(args, headers.as_ref(), &date_parser.source)?;
+    let rate_dates = create_rate_dates(args)?;
+    let stats = &RunStats::default();
+    let filter_options = create_filter_options(args, &date_parser);
+    let filter = create_filter(args, headers.as_ref(), &filter_options)?;
+    let out_headers = headers.as_ref().map(|h| get_out_headers(h, &layout));
+    if let (Some(headers), Some(out_headers)) = (headers.as_ref(), out_headers.as_ref()) {
+        if !args.allow_duplicate_headers {
+            check_out_headers(headers, out_headers, &layout)?;
+        }
+    }
+    // Output dates may have a different format, so they can't be compared as dates.
+    let post_filter_options = FilterOptions {
+        dates: None,
+        ..filter_options
+    };
+    let post_filter = FilterSet::all_of(
+        &args.post_filter,
+        "--post-filter",
+        out_headers.as_ref(),
+        &post_filter_options,
+    )?;
+    let dedupe_columns = match args.dedupe_by.as_ref() {
+        Some(columns) => Some(get_column_indexes(headers.as_ref(), columns)?),
+        None => None,
+    };
+    let mut records: Box<dyn Iterator<Item = (StringRecord, bool)>> = match args.in_file.len() {
+        0 | 1 => Box::new(read_records(
+            &mut reader,
+            args.in_file.first(),
+            pad_width(args, headers.as_ref()),
+            &filter,
+            args.filter_mode,
+            stats,
+        )),
+        _ => Box::new(read_files(args, reader, headers.as_ref(), &filter, stats)?.into_iter()),
+    };
+    // The rows are read as they're processed, so the time is summed over them.
+    let mut in_records = std::iter::from_fn(move || {
+        let start = Instant::now();
+        let record = records.next();
+        RunStats::add_time(&stats.read_time, start);
+        record
     });
+    let first_records: Vec<_> = in_records.by_ref().take(DIAGNOSED_SAMPLE).collect();
+    diagnose_records(args, &date_parser, &first_records, &head)?;
+    diagnose_headers(args, &date_parser, headers.as_ref(), &first_records);
+    let mut in_records: Box<dyn Iterator<Item = (StringRecord, bool)>> =
+        Box::new(first_records.into_iter().chain(in_records));
+    if let Some(columns) = dedupe_columns.as_ref() {
+        // Duplicates can be anywhere in the input, so all the rows are read first.
+        let records = dedupe_records(in_records.collect(), columns, args.dedupe_keep, stats);
+        in_records = Box::new(records.into_iter());
+    }
+    if args.strict && !filter.is_empty() {
+        // Whether any row matches is only known once all of them are read, which is done
+        // before any request and before the output is created.
+        let records: Vec<_> = in_records.collect();
+        if let Some(message) = describe_no_match(args, &filter, stats) {
+            log::error!("{}, failing the run as --strict is set", message);
+            return Ok(RunOutcome::Strict);
+        }
+        in_records = Box::new(records.into_iter());
+    }
+    let phase_start = stats.end_phase("read", phase_start);
+    let amounts = create_amounts(args, headers.as_ref())?;
+    // Every row depends on the reference rate, so there's no point in going on without it.
+    let reference_rate = match args.reference_date.as_ref() {
+        Some(_) if args.dry_run || args.unique_dates => None,
+        Some(date) => Some(
+            fetch_exchange_rate(client, date, CURRENCY)
+                .await
+                .wrap_err_with(|| format!("Failed to fetch the reference rate of {}", date))?,
+        ),
+        None => None,
+    };
+    let config = create_config(args, date_parser.clone(), layout, amounts, rate_dates)
+        .reference_rate(reference_rate)
+        .build();
+    let ctx = &RowContext {
+        config: &config,
+        stats,
+        client,
+    };
+    if args.dry_run {
+        print_request_plan(args, ctx, &in_records.collect::<Vec<_>>()).await?;
+        return Ok(RunOutcome::Success);
+    }
+    if args.unique_dates {
+        let (mut dates, _) = request_dates(ctx, &in_records.collect::<Vec<_>>());
+        dates.extend(args.reference_date);
+        for date in dates.iter() {
+            println!("{}", date);
+        }
+        return Ok(RunOutcome::Success);
+    }
+    let on_rate_error = match (args.fail_fast, args.keep_failed) {
+        (true, _) => OnRateError::Fail,
+        (_, true) => OnRateError::Blank,
+        _ => args.on_rate_error,
+    };
+    let options = RunOptions {
+        on_rate_error,
+        post_filter,
+        totals: args.out_totals,
+        group_totals: args.out_group_totals,
+        max_errors: args.max_errors,
+        keep_partial: args.keep_partial,
+        report_failures: args.error_report.is_some(),
+        in_files: args.in_file.clone(),
+        interrupt_grace: INTERRUPT_GRACE,
+        fetch_start: phase_start,
+    };
     let out_delimiter = args
         .out_column_delimiter
         .unwrap_or(args.in_column_delimiter);
     let mut writer_builder = WriterBuilder::new();
+    // Short rows are kept as they are read, unless padded.
     writer_builder
         .delimiter(out_delimiter as u8)
-        .has_headers(out_headers.is_some());
-    match args.out_file {
-        None => {
-            let mut writer = writer_builder.from_writer(std::io::stdout());
-            write_records(&out_records, out_headers, &mut writer)?;
+        .buffer_capacity(args.out_buffer_size)
+        .flexible(true)
+        .has_headers(headers.is_some());
+    let open = || open_output(args.out_file.as_ref()).map(|out| writer_builder.from_writer(out));
+    let interrupt = async {
+        let _ = tokio::signal::ctrl_c().await;
+        log::warn!("Interrupted, writing the rows processed so far, press Ctrl-C again to quit");
+        tokio::spawn(async {
+            let _ = tokio::signal::ctrl_c().await;
+            std::process::exit(RunOutcome::Interrupted as i32);
+        });
+    };
+    let summary =
+        bnm_exporter::csvproc::run(ctx, in_records, headers.as_ref(), &options, open, interrupt)
+            .await?;
+    if let Some(message) = describe_no_match(args, &filter, stats) {
+        log::warn!("{}, check the column names and the expressions", message);
+    }
+    if let Some(path) = args.error_report.as_ref() {
+        write_error_report(
+            path,
+            args.error_report_format,
+            headers.as_ref(),
+            &summary.failed_rows,
+        )?;
+    }
+    let mut exceeded = summary.exceeded;
+    if !args.keep_partial {
+        if let Some(exceeded) = exceeded.take() {
+            return Err(exceeded.into());
         }
-        Some(v) => {
-            let mut writer = writer_builder.from_path(v)?;
-            write_records(&out_records, out_headers, &mut writer)?;
+    }
+    stats.log_summary();
+    if let Some(format) = args
+        .stats_format
+        .or((args.stats || args.timing).then_some(StatsFormat::Text))
+    {
+        eprintln!(
+            "{}",
+            stats.format(
+                client.stats(),
+                format,
+                client.cached_dates().await,
+                args.timing
+            )
+        );
+    }
+    let kept = stats.rows_failed_blank.load(Ordering::Relaxed);
+    if kept > 0 {
+        log::warn!(
+            count = kept;
+            "{} rows were written without an exchange rate as it couldn't be added",
+            kept
+        );
+    }
+    let dropped = stats.rows_failed.load(Ordering::Relaxed);
+    if dropped > 0 {
+        log::warn!(
+            count = dropped;
+            "{} rows were dropped as the exchange rate couldn't be added, use \"--keep-failed\" to keep them",
+            dropped
+        );
+    }
+    if let Some(exceeded) = exceeded {
+        return Err(exceeded.into());
+    }
+    if summary.interrupted {
+        log::warn!(
+            row = summary.last_line;
+            "The rows after line {} weren't processed as the run was interrupted",
+            summary.last_line
+        );
+        return Ok(RunOutcome::Interrupted);
+    }
+    match (dropped, kept) {
+        (0, 0) => Ok(RunOutcome::Success),
+        _ if args.strict => Ok(RunOutcome::Strict),
+        (0, _) => Ok(RunOutcome::Success),
+        _ => Ok(RunOutcome::Partial),
+    }
+}
+
+/// Prints the rates of the requested currencies, failing if any of them isn't published.
+async fn print_rates(args: &RateArgs, client: &BnmClient) -> Result<()> {
+    let currencies = args
+        .currency
+        .iter()
+        .map(|c| parse_currency(c))
+        .collect::<Result<Vec<_>>>()?;
+    let table = client.table(args.date).await?;
+    let mut lines = Vec::with_capacity(currencies.len());
+    for currency in currencies {
+        let rate = table
+            .get(&currency)
+            .map(OfficialRate::per_unit)
+            .ok_or_else(|| FetchError::NotPublished {
+                date: args.date,
+                currency: currency.to_string(),
+            })?
+            .normalize();
+        lines.push(match args.json {
+            true => format!(
+                "{{\"date\":\"{}\",\"currency\":\"{}\",\"rate\":{}}}",
+                args.date, currency, rate
+            ),
+            false => rate.to_string(),
+        });
+    }
+    println!("{}", lines.join("\n"));
+    Ok(())
+}
+
+/// Uppercases the currency code, failing if it isn't one.
+fn parse_currency(value: &str) -> Result<String> {
+    let currency = value.trim().to_uppercase();
+    match is_currency_code(&currency) {
+        true => Ok(currency),
+        false => Err(eyre!("Invalid currency code \"{}\"", value)),
+    }
+}
+
+/// Writes a row with the rates of each date of the range.
+async fn write_range(args: &RangeArgs, client: &BnmClient) -> Result<()> {
+    if args.from > args.to {
+        return Err(eyre!(
+            "The range start {} is after its end {}",
+            args.from,
+            args.to
+        ));
+    }
+    if args.max_concurrency == 0 {
+        return Err(eyre!("The maximum concurrency must be at least 1"));
+    }
+    if let Some(path) = args.out_file.as_ref() {
+        check_writable(path, "output file")?;
+    }
+    let currencies = args
+        .currency
+        .iter()
+        .map(|c| parse_currency(c))
+        .collect::<Result<Vec<_>>>()?;
+    let out_dates = DateFormatter::new(args.out_date_format.clone(), DateLocale::En);
+    let dates: Vec<NaiveDate> = args
+        .from
+        .iter_days()
+        .take_while(|d| *d <= args.to)
+        .collect();
+    let tables: Vec<Result<RateTable, FetchError>> = futures::stream::iter(dates.iter())
+        .map(|date| client.table(*date))
+        .buffered(client.concurrency())
+        .collect()
+        .await;
+    let mut records = Vec::with_capacity(dates.len());
+    for (date, table) in dates.iter().zip(tables) {
+        let table = table?;
+        let rates: Vec<Option<Decimal>> = currencies
+            .iter()
+            .map(|c| table.get(c).map(|r| r.per_unit().normalize()))
+            .collect();
+        if rates.iter().any(Option::is_none) && !args.include_missing {
+            log::warn!(
+                date:% = date,
+                category = RowStatus::RateMissing.as_str();
+                "Skipping {}, BNM has no rates of all the currencies", date
+            );
+            continue;
         }
+        let mut record = vec![out_dates.format_or_iso(date)?];
+        record.extend(
+            rates
+                .into_iter()
+                .map(|r| r.map(|r| r.to_string()).unwrap_or_default()),
+        );
+        records.push(StringRecord::from(record));
+    }
+    let mut headers = vec!["Date".to_string()];
+    headers.extend(currencies);
+    let mut writer_builder = WriterBuilder::new();
+    writer_builder.delimiter(args.out_column_delimiter as u8);
+    let mut writer = create_writer(&writer_builder, args.out_file.as_ref())?;
+    write_records(
+        &records,
+        Some(StringRecord::from(headers)),
+        None,
+        &mut writer,
+    )
+}
+
+/// Prints the amount converted to the target currency.
+async fn print_conversion(args: &CalcArgs, client: &BnmClient) -> Result<()> {
+    let from = parse_currency(&args.currency)?;
+    let to = parse_currency(&args.to)?;
+    let amount = amount::parse_amount(&args.amount, &AmountFormat::default())
+        .ok_or_else(|| eyre!("Invalid amount \"{}\"", args.amount))?;
+    let table = client.table(args.date).await?;
+    let rate = |currency: &str| match currency {
+        LOCAL_CURRENCY => Ok(Decimal::ONE),
+        _ => table
+            .get(currency)
+            .map(OfficialRate::per_unit)
+            .ok_or_else(|| FetchError::NotPublished {
+                date: args.date,
+                currency: currency.to_string(),
+            }),
     };
+    let converted = amount::convert(amount, rate(&from)?, rate(&to)?)?;
+    let converted = format_number(converted, args.amount_precision, args.rounding);
+    match args.json {
+        true => println!(
+            "{{\"date\":\"{}\",\"amount\":{},\"currency\":\"{}\",\"converted\":{},\"to\":\"{}\"}}",
+            args.date,
+            amount.normalize(),
+            from,
+            converted,
+            to
+        ),
+        false => println!("{}", converted),
+    }
+    Ok(())
+}
+
+/// Converts a generated file twice, printing the rows per second of each run: the first one
+/// with an empty cache and the second one with all the rates cached.
+/// The rates are generated unless "--rates-file" is set, so nothing is requested from BNM.
+/// Dates of the rows generated by the bench command.
+fn bench_dates(args: &BenchArgs) -> Vec<NaiveDate> {
+    let first = NaiveDate::from_ymd(2024, 1, 1);
+    first.iter_days().take(args.dates.max(1)).collect()
+}
+
+/// Rates of the dates of the bench command.
+fn bench_rates(args: &BenchArgs) -> StaticRates {
+    let tables = bench_dates(args)
+        .iter()
+        .enumerate()
+        .map(|(i, date)| {
+            let rate = OfficialRate {
+                nominal: Decimal::ONE,
+                rate: Decimal::new(170_000 + i as i64, 4),
+            };
+            (*date, RateTable::from([(CURRENCY.to_string(), rate)]))
+        })
+        .collect();
+    StaticRates(tables)
+}
+
+async fn bench(args: &BenchArgs, client: &BnmClient) -> Result<()> {
+    let dates = bench_dates(args);
+    let dir = std::env::temp_dir();
+    let in_path = dir.join(format!("bnm-exporter-bench-{}.csv", std::process::id()));
+    let out_path = dir.join(format!("bnm-exporter-bench-{}.out.csv", std::process::id()));
+    let mut writer = Writer::from_path(&in_path)?;
+    writer.write_record(["Date", "Description", "Amount"])?;
+    for i in 0..args.rows {
+        let date = dates[i % dates.len()].to_string();
+        let amount = format!("{}.{:02}", i % 1000, i % 100);
+        writer.write_record([date.as_str(), "Payment, \"bench\"", amount.as_str()])?;
+    }
+    writer.flush()?;
+    let convert_args = OptionsParser::try_parse_from([
+        OsStr::new("bnm-exporter"),
+        OsStr::new("-i"),
+        in_path.as_os_str(),
+        OsStr::new("-o"),
+        out_path.as_os_str(),
+        OsStr::new("-d"),
+        OsStr::new("Date"),
+        OsStr::new("--in-date-format"),
+        OsStr::new("%Y-%m-%d"),
+        OsStr::new("--amount-column"),
+        OsStr::new("Amount"),
+    ])?
+    .convert;
+    let mut result = Ok(());
+    for run in ["cold", "warm"] {
+        let start = Instant::now();
+        result = convert(&convert_args, client).await.map(|_| ());
+        if result.is_err() {
+            break;
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        println!(
+            "{}: {} rows in {:.3}s, {:.0} rows/s",
+            run,
+            args.rows,
+            elapsed,
+            args.rows as f64 / elapsed
+        );
+    }
+    let _ = std::fs::remove_file(&in_path);
+    let _ = std::fs::remove_file(&out_path);
+    result
+}
+
+/// Prints the delimiter, the headers, the date columns and the first rows of the input file,
+/// along with how many rows match the filters.
+async fn inspect(args: &ConvertArgs) -> Result<()> {
+    let input = open_input(args)?;
+    let delimiter = detect_delimiter(&input.head);
+    let mut reader = create_reader(args, input);
+    let headers = read_headers(&mut reader)?;
+    println!("Delimiter: {:?}", args.in_column_delimiter);
+    if let Some(d) = delimiter.filter(|d| *d != args.in_column_delimiter) {
+        println!(
+            "  the first line has more fields with {:?}, see --in-column-delimiter",
+            d
+        );
+    }
+    match headers.as_ref() {
+        Some(h) => {
+            println!("Headers:");
+            for (i, name) in h.iter().enumerate() {
+                println!("  {}: {}", i, name);
+            }
+        }
+        None => println!("Headers: none, columns are referenced by index"),
+    }
+    let date_parser = create_date_parser(args, headers.as_ref())?;
+    let describe = |i: &usize| describe_column(headers.as_ref(), *i);
+    match &date_parser.source {
+        DateSource::Columns(columns) => println!(
+            "Date column: {}",
+            columns
+                .iter()
+                .map(describe)
+                .collect::<Vec<_>>()
+                .join(", then ")
+        ),
+        DateSource::Parts { year, month, day } => println!(
+            "Date parts: year {}, month {}, day {}",
+            describe(year),
+            describe(month),
+            describe(day)
+        ),
+    }
+    let filter_options = create_filter_options(args, &date_parser);
+    let filter = create_filter(args, headers.as_ref(), &filter_options)?;
+    let records = read_records(
+        &mut reader,
+        args.in_file.first(),
+        pad_width(args, headers.as_ref()),
+        &filter,
+        FilterMode::Annotate,
+        &RunStats::default(),
+    )
+    .collect::<Vec<_>>();
+    println!(
+        "Rows: {}, matching the filters: {}",
+        records.len(),
+        records.iter().filter(|(_, matches)| *matches).count()
+    );
+    println!("First rows:");
+    let delimiter = args.in_column_delimiter.to_string();
+    for (record, matches) in records.iter().take(INSPECT_ROWS) {
+        let date = match date_parser.is_empty(record) {
+            true => "empty date".to_string(),
+            false => match date_parser.parse_record(record) {
+                Ok((date, _)) => date.to_string(),
+                Err(e) => format!("invalid date - {}", e),
+            },
+        };
+        println!(
+            "  line {}: {} -> {}{}",
+            record
+                .position()
+                .map(|p| p.line().to_string())
+                .unwrap_or_default(),
+            record.iter().collect::<Vec<_>>().join(&delimiter),
+            date,
+            if *matches {
+                ""
+            } else {
+                " (not matching the filters)"
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Header name and index of the column, or just the index for files without headers.
+fn describe_column(headers: Option<&StringRecord>, index: usize) -> String {
+    match headers.and_then(|h| h.get(index)) {
+        Some(name) => format!("\"{}\" ({})", name, index),
+        None => index.to_string(),
+    }
+}
+
+/// Hint about a wrong column delimiter, if the first line isn't split by it but is by
+/// another common one.
+fn delimiter_hint(args: &ConvertArgs, data: &[u8]) -> Option<String> {
+    let line = data.split(|b| *b == b'\n').next()?;
+    if line.contains(&(args.in_column_delimiter as u8)) {
+        return None;
+    }
+    detect_delimiter(data).map(|d| {
+        format!(
+            "the first line isn't split by \"{}\" but it is by \"{}\", try --in-column-delimiter \"{}\"",
+            args.in_column_delimiter.escape_default(),
+            d.escape_default(),
+            d.escape_default()
+        )
+    })
+}
+
+/// Number of rows which must fail the same way before the input is diagnosed as read with
+/// the wrong options.
+const DIAGNOSED_ROWS: usize = 10;
+
+/// Number of rows read before processing the input, to diagnose it.
+const DIAGNOSED_SAMPLE: usize = 1000;
+
+/// Detects inputs read with the wrong options, before any request: fails if the rows
+/// consistently have a single field (a wrong delimiter), and warns if most dates don't
+/// parse (a wrong date format).
+fn diagnose_records(
+    args: &ConvertArgs,
+    date_parser: &DateParser,
+    records: &[(StringRecord, bool)],
+    data: &[u8],
+) -> Result<()> {
+    let dated: Vec<&StringRecord> = records
+        .iter()
+        .filter(|(r, matches)| *matches && !date_parser.is_empty(r))
+        .map(|(r, _)| r)
+        .collect();
+    let statuses: Vec<Option<RowStatus>> = dated
+        .iter()
+        .map(|r| {
+            date_parser
+                .parse_record(r)
+                .err()
+                .map(|e| PipelineError::from(e).status())
+        })
+        .collect();
+    let ragged = statuses
+        .iter()
+        .zip(&dated)
+        .take_while(|(s, r)| **s == Some(RowStatus::RaggedRow) && r.len() <= 1)
+        .count();
+    if ragged >= DIAGNOSED_ROWS.min(dated.len()) && ragged > 0 {
+        let hint = delimiter_hint(args, data)
+            .map(|h| format!(" ({})", h))
+            .unwrap_or_default();
+        return Err(eyre!(
+            "The first {} rows have a single field, the column delimiter \"{}\" is probably wrong{}",
+            ragged,
+            args.in_column_delimiter.escape_default(),
+            hint
+        ));
+    }
+    let failed: Vec<&StringRecord> = statuses
+        .iter()
+        .zip(&dated)
+        .filter(|(s, _)| **s == Some(RowStatus::DateParseError))
+        .map(|(_, r)| *r)
+        .collect();
+    let is_format = matches!(date_parser.source, DateSource::Columns(_));
+    if is_format && dated.len() >= DIAGNOSED_ROWS && failed.len() * 10 > dated.len() * 9 {
+        let samples = failed
+            .iter()
+            .take(3)
+            .map(|r| format!("\"{}\"", date_parser.describe(r)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        log::warn!(
+            "{} of {} dates don't match --in-date-format \"{}\", check it against the values, e.g. {}",
+            failed.len(),
+            dated.len(),
+            args.in_date_format,
+            samples
+        );
+    }
     Ok(())
 }
+
+/// Warns if the headers setting looks wrong: the header of the date column is a date,
+/// or, without headers, the first row is the only one with a date which doesn't parse.
+fn diagnose_headers(
+    args: &ConvertArgs,
+    date_parser: &DateParser,
+    headers: Option<&StringRecord>,
+    records: &[(StringRecord, bool)],
+) {
+    if args.headers == Some(Headers::Auto) {
+        return;
+    }
+    match headers {
+        Some(headers) => {
+            if date_parser.parse_record(headers).is_ok() {
+                log::warn!(
+                    "The header of the date column \"{}\" is a date, the file may have no headers, \
+                     use --in-no-headers or --headers auto",
+                    date_parser.describe(headers)
+                );
+            }
+        }
+        None => {
+            let mut rows = records.iter().map(|(r, _)| r).take(DIAGNOSED_ROWS + 1);
+            let first = match rows.next() {
+                Some(r) if row_line(r) == 1 && date_parser.parse_record(r).is_err() => r,
+                _ => return,
+            };
+            let mut next = rows.peekable();
+            if next.peek().is_some() && next.all(|r| date_parser.parse_record(r).is_ok()) {
+                log::warn!(
+                    "The first row looks like headers, its date \"{}\" doesn't parse unlike \
+                     the next rows, remove --in-no-headers or use --headers auto",
+                    date_parser.describe(first)
+                );
+            }
+        }
+    }
+}
+
+/// Runs everything but the rate requests and the output, printing a line per row issue and
+/// a summary of the issues by status.
+async fn check(args: &ConvertArgs, client: &BnmClient) -> Result<RunOutcome> {
+    validate_args(args)?;
+    let input = open_input(args)?;
+    let mut reader = create_reader(args, input);
+    let headers = read_headers(&mut reader)?;
+    let date_parser = create_date_parser(args, headers.as_ref())?;
+    let layout = create_layout(args, headers.as_ref(), &date_parser.source)?;
+    let filter_options = create_filter_options(args, &date_parser);
+    let filter = create_filter(args, headers.as_ref(), &filter_options)?;
+    let out_headers = headers.as_ref().map(|h| get_out_headers(h, &layout));
+    if let (Some(headers), Some(out_headers)) = (headers.as_ref(), out_headers.as_ref()) {
+        if !args.allow_duplicate_headers {
+            check_out_headers(headers, out_headers, &layout)?;
+        }
+    }
+    let post_filter_options = FilterOptions {
+        dates: None,
+        ..filter_options
+    };
+    FilterSet::all_of(
+        &args.post_filter,
+        "--post-filter",
+        out_headers.as_ref(),
+        &post_filter_options,
+    )?;
+    if let Some(columns) = args.dedupe_by.as_ref() {
+        get_column_indexes(headers.as_ref(), columns)?;
+    }
+    let amounts = create_amounts(args, headers.as_ref())?;
+    let rate_dates = create_rate_dates(args)?;
+    let stats = RunStats::default();
+    let config = create_config(args, date_parser, layout, amounts, rate_dates).build();
+    let ctx = RowContext {
+        config: &config,
+        stats: &stats,
+        client,
+    };
+    let amounts = &config.amounts;
+    let records = read_records(
+        &mut reader,
+        args.in_file.first(),
+        pad_width(args, headers.as_ref()),
+        &filter,
+        FilterMode::Exclude,
+        &stats,
+    )
+    .collect::<Vec<_>>();
+    let mut issues: Vec<(RowStatus, usize)> = Vec::new();
+    for (record, _) in records.iter() {
+        let line = record
+            .position()
+            .map(|p| p.line().to_string())
+            .unwrap_or_default();
+        let (status, message) = match plan_row(&ctx, record) {
+            Ok(RowPlan::Fetch { rate_date, .. }) => {
+                if let Err(e) = config.out_dates.format_or_iso(&rate_date) {
+                    (RowStatus::Error, e.to_string())
+                } else {
+                    match amounts.as_ref().map(|a| a.invalid(record)) {
+                        Some(invalid) if !invalid.is_empty() => (
+                            RowStatus::AmountParseError,
+                            format!("Invalid amount \"{}\"", invalid.join("\", \"")),
+                        ),
+                        _ => continue,
+                    }
+                }
+            }
+            Ok(_) => continue,
+            Err(e) => (e.status(), e.to_string()),
+        };
+        println!("line {}: {} - {}", line, status.as_str(), message);
+        match issues.iter_mut().find(|(s, _)| *s == status) {
+            Some((_, count)) => *count += 1,
+            None => issues.push((status, 1)),
+        }
+    }
+    let failed: usize = issues.iter().map(|(_, count)| count).sum();
+    println!(
+        "Checked {} rows, {} with issues{}",
+        records.len(),
+        failed,
+        issues
+            .iter()
+            .map(|(status, count)| format!(", {}: {}", status.as_str(), count))
+            .collect::<String>()
+    );
+    match failed {
+        0 => Ok(RunOutcome::Success),
+        _ => Ok(RunOutcome::Input),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The "convert" options of the command line.
+    fn convert_args(args: &[&str]) -> ConvertArgs {
+        let args = std::iter::once("bnm-exporter").chain(args.iter().copied());
+        OptionsParser::try_parse_from(args).unwrap().convert
+    }
+
+    #[test]
+    fn convert_defaults_are_the_library_ones() {
+        let args = convert_args(&["-d", "Date"]);
+        let headers = StringRecord::from(vec!["Date", "Amount"]);
+        let date_parser = create_date_parser(&args, Some(&headers)).unwrap();
+        let layout = create_layout(&args, Some(&headers), &date_parser.source).unwrap();
+        let rate_dates = create_rate_dates(&args).unwrap();
+        let cli = create_config(&args, date_parser.clone(), layout, None, rate_dates).build();
+        let library = PipelineConfig::builder(date_parser).build();
+        // Destructured, so that a new setting is added here as well.
+        let PipelineConfig {
+            date_parser: _,
+            date_range,
+            rate_dates,
+            out_dates,
+            layout,
+            amounts,
+            currency,
+            rate_precision,
+            inverse_precision,
+            rate_change_precision,
+            reference_rate,
+            rate_mode,
+            rounding,
+            concurrency,
+            prefetch,
+        } = cli;
+        assert_eq!(date_range.from, library.date_range.from);
+        assert_eq!(date_range.to, library.date_range.to);
+        assert_eq!(rate_dates.offset, library.rate_dates.offset);
+        assert!(rate_dates.calendar.is_none() && library.rate_dates.calendar.is_none());
+        assert_eq!(rate_dates.on_future_date, library.rate_dates.on_future_date);
+        assert_eq!(rate_dates.today, library.rate_dates.today);
+        assert_eq!(rate_dates.min_date, library.rate_dates.min_date);
+        assert_eq!(out_dates.format, library.out_dates.format);
+        assert_eq!(out_dates.locale, library.out_dates.locale);
+        let columns = |layout: &OutLayout| {
+            layout
+                .columns
+                .iter()
+                .map(|c| (c.after, c.name.clone(), c.value.option()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(columns(&layout), columns(&library.layout));
+        assert!(amounts.is_none() && library.amounts.is_none());
+        assert_eq!(currency, library.currency);
+        assert_eq!(rate_precision, library.rate_precision);
+        assert_eq!(inverse_precision, library.inverse_precision);
+        assert_eq!(rate_change_precision, library.rate_change_precision);
+        assert_eq!(reference_rate, library.reference_rate);
+        assert_eq!(rate_mode, library.rate_mode);
+        assert_eq!(rounding, library.rounding);
+        assert_eq!(concurrency, library.concurrency);
+        assert_eq!(prefetch, library.prefetch);
+    }
+
+    #[test]
+    fn amount_defaults_are_the_library_ones() {
+        let args = convert_args(&["-d", "Date", "--amount-column", "Amount"]);
+        let headers = StringRecord::from(vec!["Date", "Amount"]);
+        let AmountColumns {
+            columns,
+            format,
+            direction,
+            precision,
+            on_invalid,
+        } = create_amounts(&args, Some(&headers)).unwrap().unwrap();
+        let library = AmountFormat::default();
+        assert_eq!(columns, [1]);
+        assert_eq!(format.decimal_separator, library.decimal_separator);
+        assert_eq!(format.negative_marker, library.negative_marker);
+        assert_eq!(direction, ConvertDirection::ToMdl);
+        assert_eq!(precision, None);
+        assert_eq!(on_invalid, OnInvalidAmount::Blank);
+    }
+
+    #[test]
+    fn options_with_settings_of_their_own() {
+        let args = convert_args(&[
+            "-d",
+            "Date",
+            "--prefetch",
+            "--max-concurrency",
+            "4",
+            "--out-inverse-column",
+            "Inverse",
+        ]);
+        let headers = StringRecord::from(vec!["Date"]);
+        let date_parser = create_date_parser(&args, Some(&headers)).unwrap();
+        let layout = create_layout(&args, Some(&headers), &date_parser.source).unwrap();
+        let rate_dates = create_rate_dates(&args).unwrap();
+        let config = create_config(&args, date_parser, layout, None, rate_dates).build();
+        assert_eq!(config.prefetch, Some(usize::MAX));
+        assert_eq!(config.concurrency, 4);
+        assert_eq!(config.inverse_precision, Some(args.inverse_precision));
+        assert_eq!(config.layout.columns.len(), 2);
+
+        let args = convert_args(&["-d", "Date", "--chunk-size", "100"]);
+        let layout = create_layout(&args, Some(&headers), &DateSource::Columns(vec![0])).unwrap();
+        let date_parser = create_date_parser(&args, Some(&headers)).unwrap();
+        let rate_dates = create_rate_dates(&args).unwrap();
+        let config = create_config(&args, date_parser, layout, None, rate_dates).build();
+        assert_eq!(config.prefetch, Some(100));
+    }
+}
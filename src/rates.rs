@@ -0,0 +1,1102 @@
+//! Official exchange rates of BNM, requested per date and cached by the client,
+//! or loaded upfront from a file.
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDate;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use reqwest::StatusCode;
+use rust_decimal::{Decimal, RoundingStrategy};
+use tokio::sync::OnceCell;
+
+use crate::csvproc::{detect_delimiter, AVERAGE_RATE_PRECISION};
+use crate::error::{FetchError, ParseError};
+use crate::LOG_TARGET;
+
+/// Address of BNM's website, the exports are under a path per language.
+pub const BNM_BASE_URL: &str = "https://www.bnm.md";
+
+/// Rate tables by date. Concurrent lookups of a date share the same cell, so each date is
+/// requested once.
+type TableCache = tokio::sync::Mutex<HashMap<NaiveDate, Arc<OnceCell<Arc<RateTable>>>>>;
+
+/// Average rates by currency and month (first day of the month).
+type AverageCache = tokio::sync::Mutex<HashMap<(String, NaiveDate), Arc<OnceCell<Decimal>>>>;
+
+/// Official rate of a currency, BNM publishes the rate of a nominal amount of some
+/// currencies (e.g. 100 RUB).
+#[derive(Clone, Copy, Debug)]
+pub struct OfficialRate {
+    pub nominal: Decimal,
+    pub rate: Decimal,
+}
+
+impl OfficialRate {
+    /// Rate of a single unit of the currency.
+    pub fn per_unit(&self) -> Decimal {
+        self.rate / self.nominal
+    }
+}
+
+/// Official rates of a date by currency code.
+pub type RateTable = HashMap<String, OfficialRate>;
+
+/// Counters of the rate lookups of a client.
+#[derive(Default)]
+pub struct FetchStats {
+    pub lookups: AtomicUsize,
+    /// Lookups which weren't answered by the cache.
+    pub requests: AtomicUsize,
+    /// Lookups of currencies BNM didn't publish a rate of for the date.
+    pub missing: AtomicUsize,
+    pub network_errors: AtomicUsize,
+    /// Responses with an unexpected HTTP status.
+    pub status_errors: AtomicUsize,
+    /// Responses which aren't a valid rate table.
+    pub parse_errors: AtomicUsize,
+    /// Time of each request, up to the end of the response body.
+    pub latencies: std::sync::Mutex<Vec<std::time::Duration>>,
+}
+
+impl FetchStats {
+    /// Request latency at the given percentile (nearest rank), zero without requests.
+    pub fn latency_percentile(&self, percentile: usize) -> std::time::Duration {
+        let mut latencies = match self.latencies.lock() {
+            Ok(latencies) => latencies.clone(),
+            Err(_) => return std::time::Duration::ZERO,
+        };
+        latencies.sort();
+        let rank = (latencies.len() * percentile).div_ceil(100);
+        latencies
+            .get(rank.saturating_sub(1))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Where the rate tables come from.
+pub trait RateSource: Send + Sync {
+    /// Rate table of the date, a client with a cache calls it once per date.
+    fn table<'a>(&'a self, date: &'a NaiveDate) -> BoxFuture<'a, Result<RateTable, FetchError>>;
+}
+
+/// Client of the official rates, requested from BNM's export (a request per date) unless
+/// another source is set, and cached.
+///
+/// ```no_run
+/// # async fn example() -> eyre::Result<()> {
+/// use std::time::Duration;
+///
+/// use bnm_exporter::rates::BnmClient;
+/// use chrono::NaiveDate;
+///
+/// let client = BnmClient::builder()
+///     .timeout(Duration::from_secs(10))
+///     .retries(3)
+///     .build()?;
+/// let date = NaiveDate::from_ymd_opt(2024, 3, 15).unwrap();
+/// let rate = client.rate(date, "USD").await?;
+/// let rates = client.rates(&[date, date.succ_opt().unwrap()], "EUR").await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct BnmClient {
+    source: Box<dyn RateSource>,
+    /// Maximum number of requests in flight of a batch lookup.
+    concurrency: usize,
+    /// Tables fetched by the client, unless caching is disabled.
+    cache: Option<TableCache>,
+    /// Monthly averages computed by the client, unless caching is disabled.
+    averages: Option<AverageCache>,
+    stats: Arc<FetchStats>,
+}
+
+/// Configuration of a [`BnmClient`], see [`BnmClient::builder`] for the defaults.
+pub struct BnmClientBuilder {
+    timeout: Duration,
+    base_url: String,
+    language: String,
+    retries: usize,
+    retry_delay: Duration,
+    concurrency: usize,
+    cache: bool,
+    source: Option<Box<dyn RateSource>>,
+}
+
+impl BnmClientBuilder {
+    /// Time limit of each request, up to the end of the response body.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Address the exports are requested from, e.g. a proxy or a mirror of BNM's website.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Language of the export ("ro", "ru" or "en"), which only changes the currency names.
+    pub fn language(mut self, language: impl Into<String>) -> Self {
+        self.language = language.into();
+        self
+    }
+
+    /// Number of times a request is repeated after a network error or a server error status,
+    /// other statuses and invalid responses aren't retried.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Delay before the first retry, doubled for each next one.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Maximum number of dates requested at once by [`BnmClient::rates`], at least 1.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Whether the client keeps the tables it fetched, so each date is requested once.
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Reads the tables from the source rather than requesting them from BNM, e.g.
+    /// [`StaticRates`]. The request options don't apply to it.
+    pub fn source(mut self, source: impl RateSource + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// Creates the client, failing if the HTTP client can't be initialized.
+    pub fn build(self) -> Result<BnmClient, FetchError> {
+        let stats = Arc::new(FetchStats::default());
+        let source = match self.source {
+            Some(source) => source,
+            None => {
+                let http = reqwest::Client::builder()
+                    .timeout(self.timeout)
+                    .build()
+                    .map_err(FetchError::Client)?;
+                Box::new(BnmExport {
+                    http,
+                    base_url: self.base_url,
+                    language: self.language,
+                    retries: self.retries,
+                    retry_delay: self.retry_delay,
+                    stats: stats.clone(),
+                })
+            }
+        };
+        Ok(BnmClient {
+            source,
+            concurrency: self.concurrency,
+            cache: self.cache.then(|| tokio::sync::Mutex::new(HashMap::new())),
+            averages: self.cache.then(|| tokio::sync::Mutex::new(HashMap::new())),
+            stats,
+        })
+    }
+}
+
+impl BnmClient {
+    /// A builder with a 30 seconds timeout, 2 retries starting after 1 second, the
+    /// Romanian export of [`BNM_BASE_URL`], 32 concurrent requests and caching enabled.
+    pub fn builder() -> BnmClientBuilder {
+        BnmClientBuilder {
+            timeout: Duration::from_secs(30),
+            base_url: BNM_BASE_URL.to_string(),
+            language: "ro".to_string(),
+            retries: 2,
+            retry_delay: Duration::from_secs(1),
+            concurrency: 32,
+            cache: true,
+            source: None,
+        }
+    }
+
+    /// Official rates of the date, empty if BNM published none (e.g. a date before 1994).
+    pub async fn table(&self, date: NaiveDate) -> Result<RateTable, FetchError> {
+        Ok(self.lookup(date).await?.as_ref().clone())
+    }
+
+    /// Rate of one unit of the currency, failing with [`FetchError::NotPublished`] if BNM
+    /// didn't publish it for the date.
+    pub async fn rate(&self, date: NaiveDate, currency: &str) -> Result<Decimal, FetchError> {
+        unit_rate(&*self.lookup(date).await?, date, currency)
+    }
+
+    /// Rate of one unit of the currency, `None` if BNM didn't publish it for the date,
+    /// which is counted as a missing rate.
+    pub async fn published_rate(
+        &self,
+        date: NaiveDate,
+        currency: &str,
+    ) -> Result<Option<Decimal>, FetchError> {
+        let rate = self
+            .lookup(date)
+            .await?
+            .get(currency)
+            .map(OfficialRate::per_unit);
+        if rate.is_none() {
+            increment(&self.stats.missing);
+        }
+        Ok(rate)
+    }
+
+    /// Average rate of one unit of the currency over the days of the month (given by its
+    /// first day), rounded to [`AVERAGE_RATE_PRECISION`] decimals. Days without a published
+    /// rate are skipped, failing with [`FetchError::NoneInMonth`] if all of them are.
+    /// Up to the concurrency limit of days are requested at once, and the average is cached
+    /// per month and currency, unless caching is disabled.
+    pub async fn monthly_average(
+        &self,
+        month: NaiveDate,
+        days: &[NaiveDate],
+        currency: &str,
+    ) -> Result<Decimal, FetchError> {
+        let averages = match &self.averages {
+            Some(averages) => averages,
+            None => return self.average(month, days, currency).await,
+        };
+        let cell = averages
+            .lock()
+            .await
+            .entry((currency.to_string(), month))
+            .or_default()
+            .clone();
+        cell.get_or_try_init(|| self.average(month, days, currency))
+            .await
+            .copied()
+    }
+
+    /// Rates of one unit of the currency for each of the dates, failing with the error of
+    /// the earliest date (in the given order) which failed, see [`BnmClient::rate_results`].
+    pub async fn rates(
+        &self,
+        dates: &[NaiveDate],
+        currency: &str,
+    ) -> Result<HashMap<NaiveDate, Decimal>, FetchError> {
+        self.rate_results(dates, currency)
+            .await
+            .into_iter()
+            .map(|(date, rate)| rate.map(|rate| (date, rate)))
+            .collect()
+    }
+
+    /// Rate of one unit of the currency for each distinct date, in the order of their first
+    /// occurrence, along with the error of each date which failed.
+    ///
+    /// Up to the concurrency limit of dates are requested at once, the cached ones aren't
+    /// requested again, and neither are the ones requested concurrently by other lookups,
+    /// which share the request. Dropping the future stops the lookups: requests in flight
+    /// are aborted and the rest aren't sent. The tables already fetched stay cached, while
+    /// a request shared with another lookup is sent again by that lookup.
+    pub async fn rate_results(
+        &self,
+        dates: &[NaiveDate],
+        currency: &str,
+    ) -> Vec<(NaiveDate, Result<Decimal, FetchError>)> {
+        let mut seen = HashSet::new();
+        let dates: Vec<NaiveDate> = dates.iter().copied().filter(|d| seen.insert(*d)).collect();
+        futures::stream::iter(dates)
+            .map(|date| async move {
+                let rate = match self.lookup(date).await {
+                    Ok(table) => unit_rate(&table, date, currency),
+                    Err(e) => Err(e),
+                };
+                (date, rate)
+            })
+            .buffered(self.concurrency)
+            .collect()
+            .await
+    }
+
+    /// Maximum number of requests in flight of a batch lookup.
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    /// Counters of the lookups made so far by the client.
+    pub fn stats(&self) -> &FetchStats {
+        &self.stats
+    }
+
+    /// Number of dates looked up so far, zero without a cache.
+    pub async fn cached_dates(&self) -> usize {
+        match &self.cache {
+            Some(cache) => cache.lock().await.len(),
+            None => 0,
+        }
+    }
+
+    /// Whether the rate table of the date was fetched already.
+    pub async fn is_cached(&self, date: NaiveDate) -> bool {
+        match &self.cache {
+            Some(cache) => {
+                let cache = cache.lock().await;
+                cache.get(&date).map(|c| c.initialized()).unwrap_or(false)
+            }
+            None => false,
+        }
+    }
+
+    /// Rate table of the date, from the cache or the source.
+    async fn lookup(&self, date: NaiveDate) -> Result<Arc<RateTable>, FetchError> {
+        increment(&self.stats.lookups);
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.request(date).await,
+        };
+        let cell = cache.lock().await.entry(date).or_default().clone();
+        // Failed requests leave the cell empty, so they're retried by the next lookup.
+        cell.get_or_try_init(|| self.request(date)).await.cloned()
+    }
+
+    async fn request(&self, date: NaiveDate) -> Result<Arc<RateTable>, FetchError> {
+        increment(&self.stats.requests);
+        let start = Instant::now();
+        let table = self.source.table(&date).await;
+        if let Ok(mut latencies) = self.stats.latencies.lock() {
+            latencies.push(start.elapsed());
+        }
+        table.map(Arc::new)
+    }
+
+    async fn average(
+        &self,
+        month: NaiveDate,
+        days: &[NaiveDate],
+        currency: &str,
+    ) -> Result<Decimal, FetchError> {
+        let rates: Vec<_> = futures::stream::iter(days)
+            .map(|day| self.published_rate(*day, currency))
+            .buffered(self.concurrency)
+            .collect()
+            .await;
+        let mut sum = Decimal::ZERO;
+        let mut count = 0u32;
+        for (day, rate) in days.iter().zip(rates) {
+            match rate? {
+                Some(rate) => {
+                    sum += rate;
+                    count += 1;
+                }
+                None => {
+                    log::debug!(target: LOG_TARGET, "No rate published for {}, skipping it in the average", day)
+                }
+            }
+        }
+        if count == 0 {
+            return Err(FetchError::NoneInMonth {
+                month,
+                currency: currency.to_string(),
+            });
+        }
+        log::debug!(target: LOG_TARGET, "Averaging {} rates of {}", count, month.format("%Y-%m"));
+        Ok((sum / Decimal::from(count)).round_dp_with_strategy(
+            AVERAGE_RATE_PRECISION,
+            RoundingStrategy::MidpointAwayFromZero,
+        ))
+    }
+}
+
+/// Rate of one unit of the currency in the table of the date.
+fn unit_rate(table: &RateTable, date: NaiveDate, currency: &str) -> Result<Decimal, FetchError> {
+    match table.get(currency) {
+        Some(rate) => Ok(rate.per_unit()),
+        None => Err(FetchError::NotPublished {
+            date,
+            currency: currency.to_string(),
+        }),
+    }
+}
+
+/// BNM's official rates export.
+struct BnmExport {
+    http: reqwest::Client,
+    base_url: String,
+    language: String,
+    retries: usize,
+    retry_delay: Duration,
+    /// Counters of the client.
+    stats: Arc<FetchStats>,
+}
+
+impl BnmExport {
+    async fn request_table(&self, date: NaiveDate) -> Result<RateTable, FetchError> {
+        let url = format!(
+            "{}/{}/export-official-exchange-rates?date={}",
+            self.base_url,
+            self.language,
+            date.format("%d.%m.%Y")
+        );
+        let mut delay = self.retry_delay;
+        for retry in 1.. {
+            match self.request(&url, date).await {
+                Err(Retryable(e)) if retry <= self.retries => {
+                    log::debug!(
+                        target: LOG_TARGET,
+                        "Retrying {} in {:?} ({}/{}) - {}",
+                        url,
+                        delay,
+                        retry,
+                        self.retries,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(Retryable(e)) => return Err(e),
+                Ok(table) => return table,
+            }
+        }
+        unreachable!()
+    }
+
+    /// Requests the export, the outer error being one worth retrying.
+    async fn request(
+        &self,
+        url: &str,
+        date: NaiveDate,
+    ) -> Result<Result<RateTable, FetchError>, Retryable> {
+        log::debug!(target: LOG_TARGET, "Fetching exchange from {}", url);
+        let network_error = |error: reqwest::Error| {
+            increment(&self.stats.network_errors);
+            Retryable(FetchError::Network { date, error })
+        };
+        let response = self.http.get(url).send().await.map_err(network_error)?;
+        let status = response.status();
+        if status != StatusCode::OK {
+            increment(&self.stats.status_errors);
+            let error = FetchError::Status { date, status };
+            return match status.is_server_error() {
+                true => Err(Retryable(error)),
+                false => Ok(Err(error)),
+            };
+        }
+        let body = response.text().await.map_err(network_error)?;
+        Ok(parse_rate_table(&body).map_err(|error| {
+            increment(&self.stats.parse_errors);
+            FetchError::InvalidResponse { date, error }
+        }))
+    }
+}
+
+/// An error of a request which may succeed if repeated.
+struct Retryable(FetchError);
+
+impl RateSource for BnmExport {
+    fn table<'a>(&'a self, date: &'a NaiveDate) -> BoxFuture<'a, Result<RateTable, FetchError>> {
+        Box::pin(self.request_table(*date))
+    }
+}
+
+/// Rates known upfront, e.g. loaded from "--rates-file".
+pub struct StaticRates(pub HashMap<NaiveDate, RateTable>);
+
+impl StaticRates {
+    /// Loads a CSV file with a "Date" column and a column per currency code, the rates being
+    /// per one unit. The delimiter is detected from the header.
+    pub fn load(path: &std::path::Path) -> Result<Self, ParseError> {
+        let file_error = |error| ParseError::RatesFile {
+            path: path.to_path_buf(),
+            error,
+        };
+        let data = std::fs::read(path).map_err(|e| file_error(e.into()))?;
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(detect_delimiter(&data).unwrap_or(',') as u8)
+            .from_reader(data.as_slice());
+        let headers = reader.headers().map_err(file_error)?.clone();
+        let mut tables = HashMap::new();
+        for (i, record) in reader.records().enumerate() {
+            // The header is the first line.
+            let line = i + 2;
+            let record = record.map_err(|error| ParseError::RatesRow {
+                path: path.to_path_buf(),
+                line,
+                error,
+            })?;
+            let value = record.get(0).unwrap_or_default().trim();
+            let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|error| {
+                ParseError::FileDate {
+                    path: path.to_path_buf(),
+                    line,
+                    value: value.to_string(),
+                    error,
+                }
+            })?;
+            let mut table = RateTable::new();
+            for (code, value) in headers.iter().zip(record.iter()).skip(1) {
+                if value.trim().is_empty() || !is_currency_code(code.trim()) {
+                    continue;
+                }
+                let rate =
+                    value
+                        .trim()
+                        .parse::<Decimal>()
+                        .map_err(|error| ParseError::RatesValue {
+                            path: path.to_path_buf(),
+                            line,
+                            currency: code.to_string(),
+                            value: value.to_string(),
+                            error,
+                        })?;
+                let rate = OfficialRate {
+                    nominal: Decimal::ONE,
+                    rate,
+                };
+                table.insert(code.trim().to_string(), rate);
+            }
+            tables.insert(date, table);
+        }
+        log::debug!(
+            target: LOG_TARGET,
+            "Loaded the rates of {} dates from {}",
+            tables.len(),
+            path.display()
+        );
+        Ok(StaticRates(tables))
+    }
+}
+
+impl RateSource for StaticRates {
+    fn table<'a>(&'a self, date: &'a NaiveDate) -> BoxFuture<'a, Result<RateTable, FetchError>> {
+        Box::pin(async move { Ok(self.0.get(date).cloned().unwrap_or_default()) })
+    }
+}
+
+/// Parses BNM's export, title lines and a header followed by
+/// "name;numeric code;code;nominal;rate" lines.
+/// Everything before the first rate line is skipped, and the table ends at the first line
+/// after the rates which isn't one (e.g. a note, or a line cut short by a truncated response).
+/// A response without rates is an empty table if it's empty or has the header of an export
+/// (e.g. a date before 1994), an error quoting its first line otherwise.
+pub fn parse_rate_table(body: &str) -> Result<RateTable, ParseError> {
+    let mut table = HashMap::new();
+    let mut has_header = false;
+    for (i, line) in body.lines().enumerate() {
+        let fields: Vec<&str> = line.split(';').map(|f| f.trim()).collect();
+        let (code, nominal, rate) = match fields.as_slice() {
+            [.., code, nominal, rate] if is_currency_code(code) => (code, nominal, rate),
+            _ if table.is_empty() => {
+                has_header |= fields.len() >= 5;
+                continue;
+            }
+            _ if line.trim().is_empty() => continue,
+            _ => {
+                log::debug!(target: LOG_TARGET, "Ignoring line {} after the rates \"{}\"", i + 1, line);
+                break;
+            }
+        };
+        let parse = |value: &str| {
+            value
+                .replace(',', ".")
+                .parse::<Decimal>()
+                .map_err(|error| ParseError::TableRate {
+                    currency: code.to_string(),
+                    line: i + 1,
+                    text: line.to_string(),
+                    error,
+                })
+        };
+        let nominal = parse(nominal)?;
+        if nominal.is_zero() {
+            return Err(ParseError::TableNominal {
+                currency: code.to_string(),
+                line: i + 1,
+                text: line.to_string(),
+            });
+        }
+        table.insert(
+            code.to_string(),
+            OfficialRate {
+                nominal,
+                rate: parse(rate)?,
+            },
+        );
+    }
+    if table.is_empty() && !has_header {
+        if let Some((i, line)) = body.lines().enumerate().find(|(_, l)| !l.trim().is_empty()) {
+            return Err(ParseError::TableLine {
+                line: i + 1,
+                text: line.to_string(),
+            });
+        }
+    }
+    Ok(table)
+}
+
+/// Whether the value is an ISO 4217 code, e.g. "USD".
+pub fn is_currency_code(value: &str) -> bool {
+    value.len() == 3 && value.chars().all(|c| c.is_ascii_uppercase())
+}
+
+fn increment(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(year, month, day)
+    }
+
+    /// An export of BNM, with the title lines and the header.
+    const EXPORT: &str = "Cursul oficial de schimb al leului moldovenesc\n\
+        15.03.2024\n\
+        Denumire;Cod numeric;Cod literal;Rata;Curs\n\
+        Euro;978;EUR;1;19,2900\n\
+        Dolar S.U.A.;840;USD;1;17,7000\n\
+        Rubla rusească;643;RUB;100;19,4000\n\
+        Zlot polonez;985;PLN;1;4,4912\n\
+        Yen japonez;392;JPY;100;11,9200\n";
+
+    #[test]
+    fn parses_decimal_comma_rates_exactly() {
+        let table = parse_rate_table(EXPORT).unwrap();
+        assert_eq!(table.len(), 5);
+        let per_unit = |code: &str| table[code].per_unit().to_string();
+        assert_eq!(per_unit("USD"), "17.7000");
+        assert_eq!(per_unit("EUR"), "19.2900");
+        // Divided by the nominal without binary float error, 19.4 / 100 is 0.19399999999999998
+        // as f64.
+        assert_eq!(per_unit("RUB"), "0.1940");
+        assert_eq!(per_unit("JPY"), "0.1192");
+        assert_eq!(table["RUB"].nominal, Decimal::from(100));
+        let rub_in_eur = table["RUB"].per_unit() / table["EUR"].per_unit();
+        assert_eq!(rub_in_eur.round_dp(10).to_string(), "0.0100570244");
+        assert_eq!(
+            unit_rate(&table, date(2024, 3, 15), "PLN").unwrap(),
+            "4.4912".parse::<Decimal>().unwrap()
+        );
+        assert!(matches!(
+            unit_rate(&table, date(2024, 3, 15), "XAU"),
+            Err(FetchError::NotPublished { .. })
+        ));
+    }
+
+    /// Source counting the tables requested from it, which are all those of the export
+    /// except for the first `failures` ones, which fail.
+    #[derive(Clone, Default)]
+    struct CountingSource {
+        calls: Arc<AtomicUsize>,
+        failures: usize,
+    }
+
+    impl CountingSource {
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    impl RateSource for CountingSource {
+        fn table<'a>(
+            &'a self,
+            date: &'a NaiveDate,
+        ) -> BoxFuture<'a, Result<RateTable, FetchError>> {
+            Box::pin(async move {
+                let call = self.calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                if call < self.failures {
+                    return Err(FetchError::Source {
+                        date: *date,
+                        error: "connection reset".into(),
+                    });
+                }
+                Ok(parse_rate_table(EXPORT).unwrap())
+            })
+        }
+    }
+
+    fn counting_client(source: &CountingSource) -> BnmClient {
+        BnmClient::builder().source(source.clone()).build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn concurrent_lookups_share_the_request() {
+        let source = CountingSource::default();
+        let client = counting_client(&source);
+        let day = date(2024, 3, 15);
+        let rates = futures::future::join_all((0..10).map(|_| client.rate(day, "USD"))).await;
+        assert!(rates
+            .iter()
+            .all(|r| r.as_ref().unwrap() == &Decimal::new(177, 1)));
+        assert_eq!(source.calls(), 1);
+        client.rate(day, "EUR").await.unwrap();
+        assert_eq!(source.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn batch_lookups_request_each_date_once() {
+        let source = CountingSource::default();
+        let client = counting_client(&source);
+        let dates = [
+            date(2024, 3, 14),
+            date(2024, 3, 15),
+            date(2024, 3, 14),
+            date(2024, 3, 18),
+            date(2024, 3, 15),
+        ];
+        let (usd, eur) = futures::join!(client.rates(&dates, "USD"), client.rates(&dates, "EUR"));
+        assert_eq!(usd.unwrap().len(), 3);
+        assert_eq!(eur.unwrap().len(), 3);
+        assert_eq!(source.calls(), 3);
+        assert_eq!(client.cached_dates().await, 3);
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_sent_again() {
+        let source = CountingSource {
+            failures: 1,
+            ..Default::default()
+        };
+        let client = counting_client(&source);
+        let day = date(2024, 3, 15);
+        assert!(matches!(
+            client.rate(day, "USD").await,
+            Err(FetchError::Source { .. })
+        ));
+        assert!(!client.is_cached(day).await);
+        assert_eq!(client.rate(day, "USD").await.unwrap(), Decimal::new(177, 1));
+        assert!(client.is_cached(day).await);
+        client.rate(day, "USD").await.unwrap();
+        assert_eq!(source.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn lookups_sharing_a_failed_request_send_it_again() {
+        let source = CountingSource {
+            failures: 1,
+            ..Default::default()
+        };
+        let client = counting_client(&source);
+        let day = date(2024, 3, 15);
+        let (first, second) = futures::join!(client.rate(day, "USD"), client.rate(day, "EUR"));
+        // The lookup which sent the request fails, the other one sends it again.
+        assert!(first.is_err());
+        assert_eq!(second.unwrap(), Decimal::new(1929, 2));
+        assert_eq!(source.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn uncached_lookups_are_sent_again() {
+        let source = CountingSource::default();
+        let client = BnmClient::builder()
+            .source(source.clone())
+            .cache(false)
+            .build()
+            .unwrap();
+        let day = date(2024, 3, 15);
+        for _ in 0..3 {
+            assert_eq!(client.rate(day, "USD").await.unwrap(), Decimal::new(177, 1));
+        }
+        assert_eq!(source.calls(), 3);
+        assert_eq!(client.cached_dates().await, 0);
+        assert!(!client.is_cached(day).await);
+    }
+
+    #[tokio::test]
+    async fn clients_have_their_own_cache_and_counters() {
+        let (usd, eur) = (CountingSource::default(), CountingSource::default());
+        let (usd_client, eur_client) = (counting_client(&usd), counting_client(&eur));
+        let day = date(2024, 3, 15);
+        usd_client.rate(day, "USD").await.unwrap();
+        usd_client.rate(day, "USD").await.unwrap();
+        assert_eq!(eur_client.published_rate(day, "XAU").await.unwrap(), None);
+        assert_eq!((usd.calls(), eur.calls()), (1, 1));
+        let counters = |client: &BnmClient| {
+            let stats = client.stats();
+            [&stats.lookups, &stats.requests, &stats.missing].map(|c| c.load(Ordering::Relaxed))
+        };
+        assert_eq!(counters(&usd_client), [2, 1, 0]);
+        assert_eq!(counters(&eur_client), [1, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn monthly_averages_are_cached() {
+        let source = CountingSource::default();
+        let client = counting_client(&source);
+        let month = date(2024, 3, 1);
+        let days: Vec<_> = (1..=4).map(|day| date(2024, 3, day)).collect();
+        let average = client.monthly_average(month, &days, "RUB").await.unwrap();
+        assert_eq!(average, Decimal::new(1940, 4));
+        client.monthly_average(month, &days, "RUB").await.unwrap();
+        assert_eq!(source.calls(), 4);
+        assert!(matches!(
+            client.monthly_average(month, &days, "XAU").await,
+            Err(FetchError::NoneInMonth { .. })
+        ));
+        assert_eq!(source.calls(), 4);
+    }
+
+    #[tokio::test]
+    async fn rates_file_is_read_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rates.csv");
+        std::fs::write(&path, "Date;USD;EUR\n2024-03-15;17.70;19.29\n").unwrap();
+        let rates = StaticRates::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let client = BnmClient::builder().source(rates).build().unwrap();
+        let day = date(2024, 3, 15);
+        assert_eq!(
+            client.rate(day, "USD").await.unwrap(),
+            Decimal::new(1770, 2)
+        );
+        assert!(matches!(
+            client.rate(date(2024, 3, 14), "USD").await,
+            Err(FetchError::NotPublished { .. })
+        ));
+    }
+
+    #[test]
+    fn trailing_lines_end_the_table() {
+        let body = format!(
+            "{}\nNota: cursul este stabilit zilnic\nlinie;fara;curs\n",
+            EXPORT
+        );
+        let table = parse_rate_table(&body).unwrap();
+        assert_eq!(table.len(), 5);
+        let body = EXPORT.replace("Zlot polonez;985;PLN;1;4,4912", "Zlot polonez;985;PL");
+        let table = parse_rate_table(&body).unwrap();
+        assert!(table.contains_key("RUB"));
+        assert!(!table.contains_key("PLN"));
+        assert!(!table.contains_key("JPY"));
+    }
+
+    #[test]
+    fn exports_without_rates() {
+        let header_only = "Cursul oficial de schimb\n01.01.1990\n\
+            Denumire;Cod numeric;Cod literal;Rata;Curs\n";
+        assert!(parse_rate_table(header_only).unwrap().is_empty());
+        assert!(parse_rate_table("").unwrap().is_empty());
+        assert!(parse_rate_table("\n \n").unwrap().is_empty());
+        let invalid = [
+            ("<html><body>Service Unavailable</body></html>", 1),
+            ("\n\nBad Gateway", 3),
+            ("Cursul oficial de schimb\n15.03.2024\nDenumire;Cod", 1),
+        ];
+        for (body, line) in invalid {
+            match parse_rate_table(body) {
+                Err(ParseError::TableLine { line: l, .. }) => assert_eq!(l, line, "{:?}", body),
+                other => panic!("{:?}: {:?}", body, other.map(|t| t.len())),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_rate_lines() {
+        let cases = [
+            "Euro;978;EUR;1;",
+            "Euro;978;EUR;1;19,29,00",
+            "Euro;978;EUR;unu;19,2900",
+            "Euro;978;EUR;1;-",
+            "EUR;;",
+        ];
+        for line in cases {
+            let body = format!("Denumire;Cod numeric;Cod literal;Rata;Curs\n{}\n", line);
+            assert!(
+                matches!(parse_rate_table(&body), Err(ParseError::TableRate { .. })),
+                "{}",
+                line
+            );
+        }
+        let body = "Denumire;Cod numeric;Cod literal;Rata;Curs\nEuro;978;EUR;0;19,2900\n";
+        assert!(matches!(
+            parse_rate_table(body),
+            Err(ParseError::TableNominal { .. })
+        ));
+    }
+
+    #[test]
+    fn truncated_and_garbage_bodies_never_panic() {
+        // Every prefix of the export, cut anywhere.
+        for (end, _) in EXPORT.char_indices() {
+            let _ = parse_rate_table(&EXPORT[..end]);
+        }
+        // Pseudo-random bodies, made of the characters of an export.
+        let alphabet: Vec<char> = ";;;,,.\n\n 0123456789EURSDÎăţ-".chars().collect();
+        let mut seed = 0x2545_f491_u64;
+        for _ in 0..2000 {
+            let len = (seed % 80) as usize;
+            let body: String = (0..len)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    alphabet[(seed % alphabet.len() as u64) as usize]
+                })
+                .collect();
+            let _ = parse_rate_table(&body);
+        }
+    }
+
+    #[test]
+    fn builder_defaults() {
+        let builder = BnmClient::builder();
+        assert_eq!(builder.timeout, Duration::from_secs(30));
+        assert_eq!(builder.base_url, BNM_BASE_URL);
+        assert_eq!(builder.language, "ro");
+        assert_eq!(builder.retries, 2);
+        assert_eq!(builder.retry_delay, Duration::from_secs(1));
+        assert_eq!(builder.concurrency, 32);
+        assert!(builder.cache);
+        assert!(builder.source.is_none());
+
+        let builder = BnmClient::builder()
+            .base_url("http://localhost:8080/mirror/")
+            .concurrency(0);
+        assert_eq!(builder.base_url, "http://localhost:8080/mirror");
+        assert_eq!(builder.concurrency, 1);
+        assert_eq!(builder.build().unwrap().concurrency(), 1);
+    }
+
+    #[tokio::test]
+    async fn tables_arent_kept_without_a_cache() {
+        let source = CountingSource::default();
+        let client = BnmClient::builder()
+            .source(source.clone())
+            .cache(false)
+            .build()
+            .unwrap();
+        let day = date(2024, 3, 15);
+        client.rate(day, "USD").await.unwrap();
+        client.table(day).await.unwrap();
+        assert_eq!(source.calls(), 2);
+        assert!(!client.is_cached(day).await);
+        assert_eq!(client.cached_dates().await, 0);
+    }
+
+    /// An HTTP response with the body.
+    fn response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+
+    /// Answers the connections to a local port with the responses in turn, returning its URL
+    /// and the request lines received.
+    fn serve(responses: Vec<String>) -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received = requests.clone();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 4096];
+                let size = stream.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..size]);
+                let line = request.lines().next().unwrap_or_default().to_string();
+                received.lock().unwrap().push(line);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn http_client(url: &str) -> BnmClient {
+        BnmClient::builder()
+            .base_url(url)
+            .retries(1)
+            .retry_delay(Duration::from_millis(1))
+            .timeout(Duration::from_secs(5))
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_the_export_of_the_date() {
+        let (url, requests) = serve(vec![response("200 OK", EXPORT)]);
+        let client = BnmClient::builder()
+            .base_url(&url)
+            .language("en")
+            .build()
+            .unwrap();
+        let table = client.table(date(2024, 3, 15)).await.unwrap();
+        assert_eq!(table.len(), 5);
+        assert_eq!(
+            client.rate(date(2024, 3, 15), "USD").await.unwrap(),
+            Decimal::new(177, 1)
+        );
+        assert!(matches!(
+            client.rate(date(2024, 3, 15), "XAU").await,
+            Err(FetchError::NotPublished { currency, .. }) if currency == "XAU"
+        ));
+        assert_eq!(
+            requests.lock().unwrap().as_slice(),
+            ["GET /en/export-official-exchange-rates?date=15.03.2024 HTTP/1.1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (url, requests) = serve(vec![
+            response("503 Service Unavailable", ""),
+            response("200 OK", EXPORT),
+        ]);
+        let client = http_client(&url);
+        assert!(client.rate(date(2024, 3, 15), "EUR").await.is_ok());
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        let (url, requests) = serve(vec![
+            response("500 Internal Server Error", ""),
+            response("502 Bad Gateway", ""),
+        ]);
+        let client = http_client(&url);
+        assert!(matches!(
+            client.table(date(2024, 3, 15)).await,
+            Err(FetchError::Status { status, .. }) if status == StatusCode::BAD_GATEWAY
+        ));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn request_error_types() {
+        // Client errors and invalid responses aren't retried.
+        let (url, requests) = serve(vec![response("404 Not Found", "")]);
+        let result = http_client(&url).table(date(2024, 3, 15)).await;
+        assert!(matches!(
+            result,
+            Err(FetchError::Status { date: d, status }) if status == StatusCode::NOT_FOUND && d == date(2024, 3, 15)
+        ));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        let (url, _) = serve(vec![response("200 OK", "<html>Maintenance</html>")]);
+        let result = http_client(&url).table(date(2024, 3, 15)).await;
+        assert!(matches!(
+            result,
+            Err(FetchError::InvalidResponse {
+                error: ParseError::TableLine { line: 1, .. },
+                ..
+            })
+        ));
+
+        // Nothing listens on the port anymore.
+        let url = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let result = http_client(&url).table(date(2024, 3, 15)).await;
+        assert!(
+            matches!(result, Err(FetchError::Network { .. })),
+            "{:?}",
+            result
+        );
+    }
+}
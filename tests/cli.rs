@@ -0,0 +1,1096 @@
+//! End-to-end runs of the command, with the rates read from the fixtures rather than BNM.
+use std::path::PathBuf;
+use std::process::Output;
+
+use assert_cmd::cargo::cargo_bin_cmd;
+use assert_cmd::Command;
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+/// The command with the rates of the fixtures, and no logging configuration of the caller.
+fn bnm() -> Command {
+    let mut cmd = cargo_bin_cmd!("bnm-exporter");
+    cmd.env_remove("RUST_LOG")
+        .arg("--rates-file")
+        .arg(fixture("rates.csv"));
+    cmd
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn invalid_filter_aborts_the_run() {
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("statement.csv"))
+        .args(["-d", "Date", "-f", "Ammount>1"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    let stderr = stderr(&output);
+    assert!(stderr.contains("Ammount>1"), "{}", stderr);
+    assert!(stderr.contains("did you mean \"Amount\"?"), "{}", stderr);
+
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("statement.csv"))
+        .args(["-d", "Date", "-f", "Description=(unclosed"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn lenient_filter_skips_invalid_filters() {
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("statement.csv"))
+        .args(["-d", "Date", "-f", "Ammount>1", "--lenient-filter"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output).lines().count(), 4);
+    assert!(stderr(&output).contains("Ignoring invalid --filter #1"));
+}
+
+#[test]
+fn converts_amounts_exactly() {
+    // As f64, 0.7 * 17.7 is 12.389999999999999 and 1.1 * 17.7 is 19.470000000000002.
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("drift.csv"))
+        .args(["-d", "Date", "--amount-column", "Amount"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "Date,Amount,Exchange Rate,Amount MDL\n\
+         03/15/2024,0.7,17.7,12.39\n\
+         03/15/2024,1.1,17.7,19.47\n\
+         03/14/2024,1.15,17.65,20.2975\n\
+         03/14/2024,\"4,35\",17.65,76.7775\n"
+    );
+}
+
+#[test]
+fn status_column_tells_failures_apart() {
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("failures.csv"))
+        .args(["-d", "Date", "--amount-column", "Amount"])
+        .args(["--out-status-column", "Status", "--on-rate-error", "blank"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "Description,Date,Amount,Exchange Rate,Amount MDL,Status\n\
+         ok,03/15/2024,10,17.7,177,ok\n\
+         pending,,5,,,empty-date\n\
+         bad date,31/02/2024,1,,,date-parse-error\n\
+         future,12/31/2099,1,,,future-date\n\
+         no rate,03/20/2024,1,,,rate-missing\n\
+         too early,01/15/1990,1,,,rate-missing\n\
+         bad amount,03/14/2024,12abc34,17.65,,amount-parse-error\n\
+         short,,,ragged-row\n"
+    );
+}
+
+#[test]
+fn completions_of_each_shell() {
+    for shell in ["bash", "zsh", "fish", "powershell", "elvish"] {
+        let output = cargo_bin_cmd!("bnm-exporter")
+            .args(["completions", shell])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0), "{}", shell);
+        let script = stdout(&output);
+        assert!(script.contains("bnm-exporter"), "{}", shell);
+        // The subcommands and the values of the enumerated options.
+        for word in ["inspect", "--on-rate-error", "blank", "monthly-average"] {
+            assert!(script.contains(word), "{} has no {}", shell, word);
+        }
+    }
+    let output = cargo_bin_cmd!("bnm-exporter")
+        .args(["completions", "tcsh"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+}
+
+/// Counters of the "--stats" line.
+fn stats(output: &Output) -> Vec<(String, usize)> {
+    let stderr = stderr(output);
+    let line = stderr
+        .lines()
+        .find(|line| line.starts_with("rows_read="))
+        .unwrap_or_else(|| panic!("no stats in {}", stderr));
+    line.split(' ')
+        .filter_map(|counter| counter.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.parse().unwrap()))
+        .collect()
+}
+
+fn counter(stats: &[(String, usize)], name: &str) -> usize {
+    match stats.iter().find(|(n, _)| n == name) {
+        Some((_, value)) => *value,
+        None => panic!("no {} counter", name),
+    }
+}
+
+#[test]
+fn stats_of_a_run() {
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("drift.csv"))
+        .args(["-d", "Date", "--amount-column", "Amount", "--stats"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let stats = stats(&output);
+    let expected = [
+        ("rows_read", 4),
+        ("rows_written", 4),
+        ("unique_dates", 2),
+        ("amounts_converted", 4),
+        ("rate_lookups", 4),
+        ("rate_requests", 2),
+        ("cache_hits", 2),
+        ("rows_failed_blank", 0),
+    ];
+    for (name, value) in expected {
+        assert_eq!(counter(&stats, name), value, "{}", name);
+    }
+
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("failures.csv"))
+        .args(["-d", "Date", "--amount-column", "Amount", "-f", "Amount>0"])
+        .args(["--on-rate-error", "blank", "--stats-format", "json"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let stderr = stderr(&output);
+    let expected = [
+        "\"rows_read\":8,",
+        "\"rows_matched\":6,",
+        "\"rows_filtered\":2,",
+        "\"rows_written\":6,",
+        "\"empty_dates\":1,",
+        "\"future_dates_skipped\":1,",
+        "\"amounts_converted\":1,",
+        "\"rate_requests\":2,",
+        "\"rates_missing\":1,",
+        "\"rows_failed_date_parse_error\":1,",
+        "\"rows_failed_rate_missing\":2,",
+    ];
+    for counter in expected {
+        assert!(stderr.contains(counter), "no {} in {}", counter, stderr);
+    }
+}
+
+#[test]
+fn prefetching_keeps_the_stats() {
+    let run = |args: &[&str]| {
+        let output = bnm()
+            .arg("-i")
+            .arg(fixture("failures.csv"))
+            .args(["-d", "Date", "--amount-column", "Amount", "--stats"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(4), "{}", stderr(&output));
+        // The rows look up the tables prefetched for them, so only the lookups differ.
+        stats(&output)
+            .into_iter()
+            .filter(|(name, _)| !matches!(name.as_str(), "rate_lookups" | "cache_hits"))
+            .filter(|(name, _)| !name.ends_with("_ms"))
+            .collect::<Vec<_>>()
+    };
+    let expected = run(&[]);
+    assert_eq!(counter(&expected, "empty_dates"), 1);
+    assert_eq!(counter(&expected, "ragged_rows"), 1);
+    assert_eq!(counter(&expected, "future_dates_skipped"), 1);
+    assert_eq!(run(&["--prefetch"]), expected);
+    assert_eq!(run(&["--chunk-size", "2"]), expected);
+}
+
+#[test]
+fn verbosity_flags() {
+    let run = |args: &[&str], rust_log: Option<&str>| {
+        let mut cmd = bnm();
+        if let Some(filter) = rust_log {
+            cmd.env("RUST_LOG", filter);
+        }
+        let output = cmd
+            .arg("-i")
+            .arg(fixture("failures.csv"))
+            .args(["-d", "Date", "--on-rate-error", "blank"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+        stderr(&output)
+    };
+    let skipped = "failed to add exchange rate";
+    let summary = "Rows read: 8";
+
+    let default = run(&[], None);
+    assert!(default.contains(skipped), "{}", default);
+    assert!(!default.contains(summary), "{}", default);
+    let quiet = run(&["-q"], None);
+    assert!(quiet.contains(skipped), "{}", quiet);
+    assert!(!quiet.contains(summary), "{}", quiet);
+    assert_eq!(run(&["-qq"], None), "");
+
+    let verbose = run(&["-v"], None);
+    assert!(verbose.contains(skipped), "{}", verbose);
+    assert!(verbose.contains(summary), "{}", verbose);
+    assert!(!verbose.contains("DEBUG"), "{}", verbose);
+    assert!(run(&["-vv"], None).contains("DEBUG"));
+
+    // RUST_LOG wins over the flags.
+    assert_eq!(run(&["-v"], Some("error")), "");
+    assert!(run(&["-qq"], Some("info")).contains(summary));
+}
+
+#[test]
+fn exit_codes() {
+    let cases: [(&[&str], i32); 8] = [
+        (&[], 4),
+        (&["--on-rate-error", "blank"], 0),
+        (&["-f", "Description=^ok$"], 0),
+        (&["-f", "Ammount>1"], 1),
+        (&["--on-rate-error", "fail"], 2),
+        (
+            &["--on-rate-error", "fail", "-f", "Description=^no rate$"],
+            3,
+        ),
+        (&["--on-rate-error", "blank", "--strict"], 5),
+        (&["--strict", "-f", "Description=^ok$"], 0),
+    ];
+    for (args, code) in cases {
+        let output = bnm()
+            .arg("-i")
+            .arg(fixture("failures.csv"))
+            .args(["-d", "Date"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert_eq!(
+            output.status.code(),
+            Some(code),
+            "{:?}: {}",
+            args,
+            stderr(&output)
+        );
+    }
+
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("missing.csv"))
+        .args(["-d", "Date"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("missing.csv"));
+
+    let output = bnm().arg("--help").output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    for code in ["0", "1", "2", "3", "4", "5", "130"] {
+        let listed = stdout(&output)
+            .lines()
+            .any(|line| line.trim_start().starts_with(&format!("{} ", code)));
+        assert!(listed, "exit code {} isn't listed", code);
+    }
+}
+
+#[test]
+fn options_from_the_environment() {
+    let first_line = |output: &Output| {
+        stdout(output)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    };
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("statement.csv"))
+        .env("BNM_IN_DATE_COLUMN", "Date")
+        .env("BNM_OUT_EXCHANGE_COLUMN", "Rate")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(first_line(&output), "Date,Description,Amount,Rate");
+    // The command line takes precedence.
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("statement.csv"))
+        .args(["-d", "Date", "--out-exchange-column", "Curs"])
+        .env("BNM_IN_DATE_COLUMN", "Description")
+        .env("BNM_OUT_EXCHANGE_COLUMN", "Rate")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(first_line(&output), "Date,Description,Amount,Curs");
+
+    let calc = |args: &[&str], env: &[(&str, &str)]| {
+        let mut cmd = cargo_bin_cmd!("bnm-exporter");
+        cmd.args(["calc", "10", "USD", "--date", "2024-03-15", "--rates-file"])
+            .arg(fixture("rates.csv"))
+            .args(args)
+            .envs(env.iter().copied());
+        stdout(&cmd.output().unwrap())
+    };
+    assert_eq!(calc(&[], &[]), "177\n");
+    assert_ne!(calc(&[], &[("BNM_CALC_TO", "EUR")]), "177\n");
+    assert_eq!(calc(&["--to", "MDL"], &[("BNM_CALC_TO", "EUR")]), "177\n");
+    assert_eq!(calc(&[], &[("BNM_DATE", "2024-03-14")]), "177\n");
+    assert_eq!(calc(&[], &[("BNM_TO", "EUR")]), "177\n");
+
+    let range = |args: &[&str]| {
+        let mut cmd = cargo_bin_cmd!("bnm-exporter");
+        cmd.args(["range", "--rates-file"])
+            .arg(fixture("rates.csv"))
+            .args(args)
+            .env("BNM_RANGE_FROM", "2024-03-14")
+            .env("BNM_RANGE_TO", "2024-03-15")
+            .env("BNM_CALC_TO", "EUR");
+        stdout(&cmd.output().unwrap())
+    };
+    assert_eq!(range(&[]), "Date,USD\n2024-03-14,17.65\n2024-03-15,17.7\n");
+    assert_eq!(
+        range(&["--to", "2024-03-14"]),
+        "Date,USD\n2024-03-14,17.65\n"
+    );
+    assert_eq!(
+        range(&["--max-concurrency", "1"]),
+        "Date,USD\n2024-03-14,17.65\n2024-03-15,17.7\n"
+    );
+    assert_eq!(range(&["--max-concurrency", "0"]), "");
+}
+
+#[test]
+fn warnings_name_the_row() {
+    let log = tempfile::NamedTempFile::new().unwrap();
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("failures.csv"))
+        .args(["-d", "Date", "--log-file"])
+        .arg(log.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(4), "{}", stderr(&output));
+    let file = fixture("failures.csv").display().to_string();
+    let logged = std::fs::read_to_string(log.path()).unwrap();
+    let expected = [
+        (4, "Failed to parse date \"31/02/2024\""),
+        (5, "Rate date 2099-12-31 is in the future"),
+        (6, "BNM has no USD rate for 2024-03-20"),
+        (7, "Rate date 1990-01-15 predates available data"),
+        (9, "Short row"),
+    ];
+    for (line, error) in expected {
+        let prefix = format!(
+            "line {} ({}): failed to add exchange rate - {}",
+            line, file, error
+        );
+        assert!(logged.contains(&prefix), "no {:?} in {}", prefix, logged);
+    }
+
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("failures.csv"))
+        .args(["-d", "Date", "--log-format", "json"])
+        .output()
+        .unwrap();
+    let stderr = stderr(&output);
+    let warning = stderr
+        .lines()
+        .find(|line| line.contains("31/02/2024"))
+        .unwrap_or_else(|| panic!("no warning in {}", stderr));
+    assert!(warning.contains("\"row\":4,"), "{}", warning);
+    assert!(
+        warning.contains("\"category\":\"date-parse-error\""),
+        "{}",
+        warning
+    );
+}
+
+#[test]
+fn strict_run_with_a_bad_date() {
+    let run = |args: &[&str]| {
+        bnm()
+            .arg("-i")
+            .arg(fixture("bad_date.csv"))
+            .args(["-d", "Date", "--strict"])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let output = run(&[]);
+    assert_eq!(output.status.code(), Some(5), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "Date,Description,Amount,Exchange Rate\n\
+         03/14/2024,coffee,4.50,17.65\n\
+         03/15/2024,rent,500,17.7\n"
+    );
+    assert!(stderr(&output).contains("02/30/2024"));
+
+    let output = run(&["--on-rate-error", "blank"]);
+    assert_eq!(output.status.code(), Some(5), "{}", stderr(&output));
+    assert!(stdout(&output).contains("\n02/30/2024,typo,10,\n"));
+
+    // Within --max-errors the run is still strict, beyond it the run fails with the code
+    // of the failed rows.
+    assert_eq!(run(&["--max-errors", "1"]).status.code(), Some(5));
+    assert_eq!(run(&["--max-errors", "0"]).status.code(), Some(2));
+}
+
+#[test]
+fn short_rows() {
+    let run = |args: &[&str]| {
+        bnm()
+            .arg("-i")
+            .arg(fixture("ragged.csv"))
+            .args(["-d", "Date"])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let output = run(&[]);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "Description,Amount,Date,Exchange Rate\n\
+         coffee,4.50,03/14/2024,17.65\n\
+         rent,500,03/15/2024,17.7\n"
+    );
+    let warning = format!(
+        "line 3 ({}): failed to add exchange rate - Short row, failed to parse date: \
+         column 2 is missing, the row has 2 fields",
+        fixture("ragged.csv").display()
+    );
+    assert!(stderr(&output).contains(&warning), "{}", stderr(&output));
+
+    let output = run(&[
+        "--out-status-column",
+        "Status",
+        "--on-rate-error",
+        "blank",
+        "--stats",
+    ]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stdout(&output).contains("\nrefund,-2,,ragged-row\n"));
+    let stats = stats(&output);
+    assert_eq!(counter(&stats, "ragged_rows"), 1);
+    assert_eq!(counter(&stats, "rows_failed_ragged_row"), 1);
+    assert_eq!(counter(&stats, "rows_failed_date_parse_error"), 0);
+
+    let output = run(&["--pad-short-rows", "--out-status-column", "Status"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "Description,Amount,Date,Exchange Rate,Status\n\
+         coffee,4.50,03/14/2024,17.65,ok\n\
+         refund,-2,,,empty-date\n\
+         rent,500,03/15/2024,17.7,ok\n"
+    );
+}
+
+#[test]
+fn unwritable_outputs_fail_upfront() {
+    let dir = tempfile::tempdir().unwrap();
+    // A file in place of a directory, which even root can't write into.
+    let file = dir.path().join("file");
+    std::fs::write(&file, "").unwrap();
+    let cases = [
+        (
+            "--out-file",
+            dir.path().join("missing/out.csv"),
+            "Cannot write the output file",
+        ),
+        (
+            "--out-file",
+            file.join("out.csv"),
+            "Cannot write the output file",
+        ),
+        (
+            "--error-report",
+            file.join("errors.csv"),
+            "Cannot write the error report",
+        ),
+        (
+            "--log-file",
+            file.join("run.log"),
+            "Failed to open log file",
+        ),
+    ];
+    for (option, path, error) in cases {
+        // The input doesn't exist either, it isn't opened.
+        let output = bnm()
+            .arg("-i")
+            .arg(fixture("missing.csv"))
+            .args(["-d", "Date", option])
+            .arg(&path)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1), "{}", option);
+        assert_eq!(stdout(&output), "");
+        let stderr = stderr(&output);
+        assert!(stderr.contains(error), "{}", stderr);
+        assert!(stderr.contains(&path.display().to_string()), "{}", stderr);
+        assert!(!path.exists());
+    }
+}
+
+#[test]
+fn failure_categories_agree() {
+    let dir = tempfile::tempdir().unwrap();
+    let report = dir.path().join("errors.jsonl");
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("failures.csv"))
+        .args([
+            "-d",
+            "Date",
+            "--amount-column",
+            "Amount",
+            "--out-status-column",
+            "Status",
+        ])
+        .args(["--on-rate-error", "blank", "--on-empty-date", "error"])
+        .args(["--on-invalid-amount", "error", "--stats", "--error-report"])
+        .arg(&report)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+
+    let mut expected: Vec<(String, usize)> = [
+        ("empty-date", 1),
+        ("date-parse-error", 1),
+        ("ragged-row", 1),
+        ("future-date", 1),
+        ("rate-missing", 2),
+        ("amount-parse-error", 1),
+    ]
+    .iter()
+    .map(|(category, count)| (category.to_string(), *count))
+    .collect();
+    expected.sort();
+    let tally = |categories: Vec<String>| {
+        let mut counts: Vec<(String, usize)> = Vec::new();
+        for category in categories {
+            match counts.iter_mut().find(|(c, _)| *c == category) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((category, 1)),
+            }
+        }
+        counts.sort();
+        counts
+    };
+
+    let column = stdout(&output)
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.rsplit(',').next())
+        .filter(|status| *status != "ok")
+        .map(str::to_string)
+        .collect();
+    assert_eq!(tally(column), expected);
+
+    let report = std::fs::read_to_string(&report).unwrap();
+    let reported = report
+        .lines()
+        .map(|line| {
+            let start = line.find("\"category\":\"").unwrap() + "\"category\":\"".len();
+            let end = start + line[start..].find('"').unwrap();
+            line[start..end].to_string()
+        })
+        .collect();
+    assert_eq!(tally(reported), expected);
+
+    let mut counted: Vec<(String, usize)> = stats(&output)
+        .into_iter()
+        .filter_map(|(name, count)| {
+            let category = name.strip_prefix("rows_failed_")?.replace('_', "-");
+            (count > 0).then_some((category, count))
+        })
+        .filter(|(category, _)| category != "blank")
+        .collect();
+    counted.sort();
+    assert_eq!(counted, expected);
+}
+
+#[test]
+fn filters_matching_no_row() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("out.csv");
+    let run = |args: &[&str]| {
+        bnm()
+            .arg("-i")
+            .arg(fixture("statement.csv"))
+            .args(["-d", "Date", "-f", "Description=^Rent$"])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let message = "None of the 3 rows matched the filters: --filter \"Description=^Rent$\"";
+    let output = run(&[]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "Date,Description,Amount,Exchange Rate\n");
+    assert!(stderr(&output).contains(message), "{}", stderr(&output));
+
+    // With --strict the run fails before the output is created, even when quiet.
+    let output = run(&["--strict", "-qq", "--out-file", out.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(5), "{}", stderr(&output));
+    assert!(stderr(&output).contains(message), "{}", stderr(&output));
+    assert!(!out.exists());
+    let output = run(&["--strict"]);
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(stdout(&output), "");
+
+    let output = run(&["--stats"]);
+    let stats = stats(&output);
+    assert_eq!(counter(&stats, "rows_read"), 3);
+    assert_eq!(counter(&stats, "rows_matched"), 0);
+    assert_eq!(counter(&stats, "rows_filtered"), 3);
+}
+
+#[test]
+fn header_misconfiguration() {
+    let run = |file: &str, args: &[&str]| {
+        bnm()
+            .arg("-i")
+            .arg(fixture(file))
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let rows = "03/14/2024,coffee,4.50,17.65\n\
+                03/15/2024,rent,500,17.7\n\
+                03/18/2024,refund,-2,17.68\n";
+
+    // Headers expected, but the file has none.
+    let output = run("headerless.csv", &["-d", "0"]);
+    assert_eq!(output.status.code(), Some(1));
+    let message = stderr(&output);
+    assert!(
+        message.contains("the first row has a date, the file may have no headers"),
+        "{}",
+        message
+    );
+    let output = run("headerless.csv", &["-d", "03/14/2024"]);
+    assert!(stderr(&output).contains(
+        "The header of the date column \"03/14/2024\" is a date, the file may have no headers"
+    ));
+
+    // No headers expected, but the file has some.
+    let output = run("statement.csv", &["-d", "0", "--in-no-headers"]);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(stderr(&output).contains(
+        "The first row looks like headers, its date \"Date\" doesn't parse unlike the next rows"
+    ));
+
+    // Detected either way.
+    let output = run("headerless.csv", &["-d", "0", "--headers", "auto"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), rows);
+    assert!(!stderr(&output).contains("WARN"), "{}", stderr(&output));
+    let output = run("statement.csv", &["-d", "Date", "--headers", "auto"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("Date,Description,Amount,Exchange Rate\n"));
+    let output = run("statement.csv", &["-d", "Date"]);
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn duplicate_out_headers() {
+    let run = |args: &[&str]| {
+        bnm()
+            .arg("-i")
+            .arg(fixture("statement.csv"))
+            .args(["-d", "Date"])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let output = run(&["--out-exchange-column", "Amount"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output)
+        .contains("Duplicate output columns: \"Amount\" (from the input, --out-exchange-column)"));
+
+    let output = run(&["--out-rate-date-column", "Exchange Rate"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output)
+        .contains("\"Exchange Rate\" (from --out-exchange-column, --out-rate-date-column)"));
+
+    let output = run(&[
+        "--out-exchange-column",
+        "Amount",
+        "--allow-duplicate-headers",
+    ]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("Date,Description,Amount,Amount\n"));
+}
+
+#[test]
+fn several_input_files() {
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("march_1.csv"))
+        .arg("-i")
+        .arg(fixture("march_2.csv"))
+        .arg("-i")
+        .arg(fixture("march_3.csv"))
+        .args(["-d", "Date"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "Date,Description,Amount,Exchange Rate\n\
+        03/14/2024,Coffee,3.50,17.65\n\
+        03/15/2024,Books,12.00,17.7\n\
+        03/18/2024,Refund,-10.00,17.68\n\
+        03/14/2024,Tea,1.50,17.65\n\
+        03/15/2024,Rent,500,17.7\n"
+    );
+    // The row which isn't UTF-8 is line 3 of the second file.
+    let warnings = stderr(&output);
+    let warning = format!(
+        "line 3 ({}): skipping row",
+        fixture("march_2.csv").display()
+    );
+    assert!(warnings.contains(&warning), "{}", warnings);
+    assert_eq!(warnings.matches("skipping row").count(), 1, "{}", warnings);
+
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("march_1.csv"))
+        .arg("-i")
+        .arg(fixture("ragged.csv"))
+        .args(["-d", "Date"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    let stderr = stderr(&output);
+    assert!(stderr.contains("aren't the same as those of"), "{}", stderr);
+}
+
+#[test]
+fn quoted_fields_are_kept() {
+    let run = |args: &[&str]| {
+        bnm()
+            .arg("-i")
+            .arg(fixture("quoting.csv"))
+            .args(["-d", "Date"])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let output = run(&["--out-exchange-insert-after", "Date"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "Description,Date,Exchange Rate,Amount\n\
+        \"Lunch, with \"\"friends\"\"\",03/14/2024,17.65,\"1,234.50\"\n\
+        \"two\nlines\",03/15/2024,17.7,\n\
+        ,03/18/2024,17.68,a;b\n"
+    );
+    let output = run(&["--out-column-delimiter", ";"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "Description;Date;Amount;Exchange Rate\n\
+        \"Lunch, with \"\"friends\"\"\";03/14/2024;1,234.50;17.65\n\
+        \"two\nlines\";03/15/2024;;17.7\n\
+        ;03/18/2024;\"a;b\";17.68\n"
+    );
+}
+
+#[test]
+fn headerless_input() {
+    let run = |args: &[&str]| {
+        bnm()
+            .arg("-i")
+            .arg(fixture("headerless.csv"))
+            .args(["-d", "0"])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let expected = "03/14/2024,coffee,4.50,17.65\n\
+        03/15/2024,rent,500,17.7\n\
+        03/18/2024,refund,-2,17.68\n";
+    for args in [
+        &["--in-no-headers"][..],
+        &["--headers", "no"],
+        &["--headers", "auto"],
+    ] {
+        let output = run(args);
+        assert_eq!(
+            output.status.code(),
+            Some(0),
+            "{:?}: {}",
+            args,
+            stderr(&output)
+        );
+        assert_eq!(stdout(&output), expected, "{:?}", args);
+    }
+    // Columns are indexes, for the insert position and the filters as well.
+    let output = run(&[
+        "--in-no-headers",
+        "--out-exchange-insert-after",
+        "0",
+        "-f",
+        "1=^r",
+    ]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "03/15/2024,17.7,rent,500\n03/18/2024,17.68,refund,-2\n"
+    );
+    // Read with headers, the first row is the header and its date column isn't found.
+    let output = run(&[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("no headers"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn insert_positions() {
+    let header = |args: &[&str]| {
+        let output = bnm()
+            .arg("-i")
+            .arg(fixture("statement.csv"))
+            .args(["-d", "Date"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert_eq!(
+            output.status.code(),
+            Some(0),
+            "{:?}: {}",
+            args,
+            stderr(&output)
+        );
+        stdout(&output)
+            .lines()
+            .take(2)
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    assert_eq!(
+        header(&[]),
+        "Date,Description,Amount,Exchange Rate\n03/14/2024,Coffee,3.50,17.65"
+    );
+    assert_eq!(
+        header(&["--out-exchange-insert-after", "Date"]),
+        "Date,Exchange Rate,Description,Amount\n03/14/2024,17.65,Coffee,3.50"
+    );
+    assert_eq!(
+        header(&["--out-exchange-insert-after", "Amount"]),
+        "Date,Description,Amount,Exchange Rate\n03/14/2024,Coffee,3.50,17.65"
+    );
+    assert_eq!(
+        header(&[
+            "--out-exchange-insert-after",
+            "Description",
+            "--out-status-column",
+            "Status"
+        ]),
+        "Date,Description,Exchange Rate,Status,Amount\n03/14/2024,Coffee,17.65,ok,3.50"
+    );
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("statement.csv"))
+        .args(["-d", "Date", "--out-exchange-insert-after", "Total"])
+        .output()
+        .unwrap();
+    // An unknown column is warned about, the rate is appended.
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).starts_with("Date,Description,Amount,Exchange Rate\n"));
+    let stderr = stderr(&output);
+    assert!(
+        stderr.contains("Cannot find column \"Total\""),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn filter_modes() {
+    let run = |args: &[&str]| {
+        let output = bnm()
+            .arg("-i")
+            .arg(fixture("statement.csv"))
+            .args(["-d", "Date", "-f", "Description=^(Coffee|Refund)$"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert_eq!(
+            output.status.code(),
+            Some(0),
+            "{:?}: {}",
+            args,
+            stderr(&output)
+        );
+        stdout(&output)
+    };
+    assert_eq!(
+        run(&[]),
+        "Date,Description,Amount,Exchange Rate\n\
+        03/14/2024,Coffee,3.50,17.65\n\
+        03/18/2024,Refund,(10.00),17.68\n"
+    );
+    assert_eq!(
+        run(&["--invert-match"]),
+        "Date,Description,Amount,Exchange Rate\n\
+        03/15/2024,Books,\"1,234.56\",17.7\n"
+    );
+    assert_eq!(
+        run(&["--filter-mode", "annotate", "--out-status-column", "Status"]),
+        "Date,Description,Amount,Exchange Rate,Status\n\
+        03/14/2024,Coffee,3.50,17.65,ok\n\
+        03/15/2024,Books,\"1,234.56\",,not-matched\n\
+        03/18/2024,Refund,(10.00),17.68,ok\n"
+    );
+    assert_eq!(
+        run(&["-f", "Amount>5"]),
+        "Date,Description,Amount,Exchange Rate\n"
+    );
+}
+
+#[test]
+fn failure_policies() {
+    let dir = tempfile::tempdir().unwrap();
+    let run = |args: &[&str]| {
+        let out = dir.path().join("out.csv");
+        let _ = std::fs::remove_file(&out);
+        let output = bnm()
+            .arg("-i")
+            .arg(fixture("failures.csv"))
+            .args(["-d", "Date", "--out-status-column", "Status", "-o"])
+            .arg(&out)
+            .args(args)
+            .output()
+            .unwrap();
+        (output, std::fs::read_to_string(&out).ok())
+    };
+    // Dropped by default, the run is a partial success.
+    let (output, out) = run(&[]);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        out.unwrap(),
+        "Description,Date,Amount,Exchange Rate,Status\n\
+        ok,03/15/2024,10,17.7,ok\n\
+        pending,,5,,empty-date\n\
+        bad amount,03/14/2024,12abc34,17.65,ok\n"
+    );
+    // Kept with empty added columns.
+    let (output, out) = run(&["--on-rate-error", "blank"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let out = out.unwrap();
+    let statuses: Vec<&str> = out
+        .lines()
+        .skip(1)
+        .map(|line| line.rsplit(',').next().unwrap())
+        .collect();
+    assert_eq!(
+        statuses,
+        [
+            "ok",
+            "empty-date",
+            "date-parse-error",
+            "future-date",
+            "rate-missing",
+            "rate-missing",
+            "ok",
+            "ragged-row"
+        ]
+    );
+    assert!(
+        out.contains("\nno rate,03/20/2024,1,,rate-missing\n"),
+        "{}",
+        out
+    );
+    // The whole run fails on the first failed row, without writing the output.
+    let (output, out) = run(&["--on-rate-error", "fail"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(out, None);
+    assert!(stderr(&output).contains("line 4"), "{}", stderr(&output));
+    // Empty dates fail the row too once they're errors.
+    let (output, out) = run(&[
+        "--on-empty-date",
+        "error",
+        "-f",
+        "Description=^(ok|pending)$",
+    ]);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr(&output));
+    assert_eq!(
+        out.unwrap(),
+        "Description,Date,Amount,Exchange Rate,Status\nok,03/15/2024,10,17.7,ok\n"
+    );
+}
+
+#[test]
+fn error_report_lists_the_failed_rows() {
+    let dir = tempfile::tempdir().unwrap();
+    let report = dir.path().join("errors.csv");
+    let output = bnm()
+        .arg("-i")
+        .arg(fixture("failures.csv"))
+        .args([
+            "-d",
+            "Date",
+            "--error-report-format",
+            "csv",
+            "--error-report",
+        ])
+        .arg(&report)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(4), "{}", stderr(&output));
+    let report = std::fs::read_to_string(&report).unwrap();
+    let lines: Vec<(&str, &str)> = report
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let mut fields = line.split(',');
+            (fields.next().unwrap(), fields.next().unwrap())
+        })
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            ("4", "date-parse-error"),
+            ("5", "future-date"),
+            ("6", "rate-missing"),
+            ("7", "rate-missing"),
+            ("9", "ragged-row")
+        ]
+    );
+}